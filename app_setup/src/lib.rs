@@ -15,6 +15,17 @@ pub fn appsetup(
     app_info: &AppInfo,
     config: HashMap<Option<&str>, Vec<&str>>,
     config_file_name: &str,
+) -> Ini {
+    appsetup_with_optional(app_info, config, &[], config_file_name)
+}
+
+/// Like `appsetup`, but the keys in `optional` may be left empty, in which
+/// case they're left out of the config file.
+pub fn appsetup_with_optional(
+    app_info: &AppInfo,
+    config: HashMap<Option<&str>, Vec<&str>>,
+    optional: &[&str],
+    config_file_name: &str,
 ) -> Ini {
     let mut app_conf = app_root(AppDataType::UserConfig, app_info).expect(
         "Couldn't create platform-specific config dir",
@@ -28,14 +39,23 @@ pub fn appsetup(
     let mut rl = Editor::<()>::new();
     for (section, keys) in config {
         for k in keys {
+            let is_optional = optional.contains(&k);
+            let question = if is_optional {
+                format!("{0} Enter value for {1} (optional): ", prompt, k)
+            } else {
+                format!("{0} Enter value for {1}: ", prompt, k)
+            };
             'outer: loop {
-                let readline = rl.readline(&format!("{0} Enter value for {1}: ", prompt, k));
+                let readline = rl.readline(&question);
                 match readline {
                     Ok(line) => {
                         if line != "" {
                             conf.with_section(section).set(k, line);
                             break 'outer;
                         }
+                        if is_optional {
+                            break 'outer;
+                        }
                         continue;
                     }
                     Err(ReadlineError::Interrupted) => continue,
@@ -1,12 +1,97 @@
 use youtube::YoutubeBackend;
+use ytdl::YtdlBackend;
+use local::LocalBackend;
+use error::{Result, SurgeError};
 
 use hyper::Client;
 use hyper::net::HttpsConnector;
 use hyper_native_tls::NativeTlsClient;
+use strsim::levenshtein;
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DURATIONS: &'static [&'static str] = &["any", "short", "medium", "long"];
+const ORDERS: &'static [&'static str] = &[
+    "date",
+    "rating",
+    "relevance",
+    "title",
+    "videoCount",
+    "viewCount",
+];
+const SAFE_SEARCHES: &'static [&'static str] = &["none", "moderate", "strict"];
+/// YouTube's category for music videos
+const MUSIC_CATEGORY: &'static str = "10";
+
+/// Titles at least this similar are taken to be the same track; high
+/// enough that "Track 10" and "Track 11" stay apart
+const DUPLICATE_THRESHOLD: f64 = 0.9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum BackendType {
     Youtube,
+    Ytdl,
+    Local,
+    /// Not a backend, but where URLs played directly come from; mpv opens
+    /// them as they are
+    Stream,
+}
+
+impl fmt::Display for BackendType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            BackendType::Youtube => "youtube",
+            BackendType::Ytdl => "ytdl",
+            BackendType::Local => "local",
+            BackendType::Stream => "stream",
+        };
+        write!(f, "{0}", name)
+    }
+}
+
+impl FromStr for BackendType {
+    type Err = SurgeError;
+
+    /// Parse one entry of the `backend` key of surge.ini, or the source of
+    /// a saved track. `stream` parses, but `MasterBackend` won't take it.
+    fn from_str(s: &str) -> Result<BackendType> {
+        match s.trim() {
+            "youtube" => Ok(BackendType::Youtube),
+            "ytdl" => Ok(BackendType::Ytdl),
+            "local" => Ok(BackendType::Local),
+            "stream" => Ok(BackendType::Stream),
+            x => Err(SurgeError::Backend(format!(
+                "Unknown backend '{0}', expected 'youtube', 'ytdl', 'local' or 'stream'",
+                x
+            ))),
+        }
+    }
+}
+
+/// What a result stands for; playlists and channels are queued by queueing
+/// their tracks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultKind {
+    Track,
+    Playlist,
+    Channel,
+}
+
+impl fmt::Display for ResultKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ResultKind::Track => "track",
+            ResultKind::Playlist => "playlist",
+            ResultKind::Channel => "channel",
+        };
+        write!(f, "{0}", name)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -14,41 +99,1133 @@ pub struct BackendSearchResult {
     pub thumbnail: Option<String>,
     pub id: String,
     pub title: String,
+    /// The backend that found it, which decides how it gets played
+    pub source: BackendType,
+    pub kind: ResultKind,
+    /// The channel of a video, or the artist of a local file
+    pub artist: Option<String>,
+    /// In seconds
+    pub duration: Option<u64>,
+    /// As YYYY-MM-DD
+    pub published: Option<String>,
+    pub views: Option<u64>,
+    pub description: Option<String>,
+}
+
+impl BackendSearchResult {
+    /// A track that's known by no more than its id and title.
+    pub fn new(id: String, title: String, source: BackendType) -> BackendSearchResult {
+        BackendSearchResult {
+            thumbnail: None,
+            id: id,
+            title: title,
+            source: source,
+            kind: ResultKind::Track,
+            artist: None,
+            duration: None,
+            published: None,
+            views: None,
+            description: None,
+        }
+    }
+}
+
+/// Search terms, along with any filters typed in among them, e.g.
+/// `lofi duration:long order:viewCount region:CA after:2020`. Backends use
+/// the filters they can and ignore the rest.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchQuery {
+    pub terms: String,
+    /// `short` (under 4 minutes), `medium` or `long` (over 20 minutes)
+    pub duration: Option<String>,
+    pub order: Option<String>,
+    /// An ISO 3166-1 country code, e.g. `CA`
+    pub region: Option<String>,
+    /// RFC 3339, e.g. `2020-01-01T00:00:00Z`
+    pub published_after: Option<String>,
+    pub safe_search: Option<String>,
+    pub category: Option<String>,
+}
+
+impl FromStr for SearchQuery {
+    type Err = SurgeError;
+
+    /// Words of the form `key:value` with a known key are filters; any
+    /// other word, colons and all, is a search term.
+    fn from_str(s: &str) -> Result<SearchQuery> {
+        let mut query = SearchQuery::default();
+        let mut terms = vec![];
+        for word in s.split_whitespace() {
+            let mut kv = word.splitn(2, ':');
+            let (key, value) = match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if !v.is_empty() => (k, v),
+                _ => {
+                    terms.push(word);
+                    continue;
+                }
+            };
+            match key {
+                "duration" => query.duration = Some(one_of(key, value, DURATIONS)?),
+                "order" => query.order = Some(one_of(key, value, ORDERS)?),
+                "safe" => query.safe_search = Some(one_of(key, value, SAFE_SEARCHES)?),
+                "region" => {
+                    let letters = value.chars().all(|c| match c {
+                        'a'...'z' | 'A'...'Z' => true,
+                        _ => false,
+                    });
+                    if value.len() != 2 || !letters {
+                        return Err(SurgeError::Usage(format!(
+                            "Invalid region '{0}', expected a country code like CA",
+                            value
+                        )));
+                    }
+                    query.region = Some(value.to_uppercase());
+                }
+                "after" => query.published_after = Some(rfc3339_date(value)?),
+                "category" => {
+                    query.category = match value {
+                        "music" => Some(String::from(MUSIC_CATEGORY)),
+                        x if x.chars().all(|c| c.is_digit(10)) => Some(String::from(x)),
+                        x => {
+                            return Err(SurgeError::Usage(format!(
+                                "Invalid category '{0}', expected 'music' or a category id",
+                                x
+                            )))
+                        }
+                    }
+                }
+                _ => terms.push(word),
+            }
+        }
+        if terms.is_empty() {
+            return Err(SurgeError::Usage(String::from("Missing search terms")));
+        }
+        query.terms = terms.join(" ");
+        Ok(query)
+    }
 }
 
+/// The spelling of `value` found in `allowed`, ignoring case.
+fn one_of(key: &str, value: &str, allowed: &[&str]) -> Result<String> {
+    match allowed.iter().find(|x| x.to_lowercase() == value.to_lowercase()) {
+        Some(x) => Ok(String::from(*x)),
+        None => Err(SurgeError::Usage(format!(
+            "Invalid {0} '{1}', expected one of: {2}",
+            key,
+            value,
+            allowed.join(", ")
+        ))),
+    }
+}
+
+/// `2020`, `2020-06` or `2020-06-15` as the start of that year, month or day.
+fn rfc3339_date(x: &str) -> Result<String> {
+    let parts = x.split('-').collect::<Vec<_>>();
+    let valid = parts.len() <= 3 && parts.iter().enumerate().all(|(i, x)| date_part(i, x));
+    if !valid {
+        return Err(SurgeError::Usage(format!(
+            "Invalid date '{0}', expected YYYY, YYYY-MM or YYYY-MM-DD",
+            x
+        )));
+    }
+    Ok(format!(
+        "{0}-{1}-{2}T00:00:00Z",
+        parts[0],
+        parts.get(1).unwrap_or(&"01"),
+        parts.get(2).unwrap_or(&"01")
+    ))
+}
+
+/// Whether `x` works as part `i` of a date: a four digit year, then a two
+/// digit month and day.
+fn date_part(i: usize, x: &str) -> bool {
+    let (len, min, max) = match i {
+        0 => (4, 0, 9999),
+        1 => (2, 1, 12),
+        _ => (2, 1, 31),
+    };
+    x.len() == len && x.chars().all(|c| c.is_digit(10)) &&
+        x.parse::<u32>().map(|n| n >= min && n <= max).unwrap_or(false)
+}
+
+/// One page of results, with the tokens that fetch the pages around it.
+/// Tokens only mean something to the backend that handed them out.
+#[derive(Debug, Clone, Default)]
+pub struct ResultPage {
+    pub results: Vec<BackendSearchResult>,
+    pub next: Option<String>,
+    pub prev: Option<String>,
+}
+
+/// The `Option<&str>` is a page token from an earlier `ResultPage`, or None
+/// for the first page.
 pub trait Backend {
-    fn find_related_tracks(&self, &str) -> Vec<BackendSearchResult>;
-    fn search(&self, &str) -> Vec<BackendSearchResult>;
+    fn find_related_tracks(&self, &str, Option<&str>) -> Result<ResultPage>;
+    fn search(&self, &SearchQuery, Option<&str>) -> Result<ResultPage>;
+
+    /// Playlists or channels, as results of that kind
+    fn search_collections(
+        &self,
+        _query: &SearchQuery,
+        _kind: ResultKind,
+        _page: Option<&str>,
+    ) -> Result<ResultPage> {
+        Err(SurgeError::Backend(
+            String::from("This backend has no playlists or channels"),
+        ))
+    }
+
+    /// A single track by id, for when all there is to go on is a link
+    fn lookup(&self, _id: &str) -> Result<BackendSearchResult> {
+        Err(SurgeError::Backend(
+            String::from("This backend can't look tracks up by id"),
+        ))
+    }
+
+    /// The tracks of a playlist, or the uploads of a channel
+    fn list_collection(&self, _id: &str, _kind: ResultKind, _page: Option<&str>) -> Result<ResultPage> {
+        Err(SurgeError::Backend(
+            String::from("This backend has no playlists or channels"),
+        ))
+    }
+
+    /// Up to `max` tracks of a playlist or channel at once, to queue or
+    /// save it. Backends that can fetch more than a page per request should
+    /// override this.
+    fn collection_tracks(
+        &self,
+        id: &str,
+        kind: ResultKind,
+        max: usize,
+    ) -> Result<Vec<BackendSearchResult>> {
+        let mut tracks = vec![];
+        let mut page = None;
+        loop {
+            let x = self.list_collection(id, kind, page.as_ref().map(String::as_str))?;
+            tracks.extend(x.results);
+            page = x.next;
+            if page.is_none() || tracks.len() >= max {
+                break;
+            }
+        }
+        tracks.truncate(max);
+        Ok(tracks)
+    }
+}
+
+/// Backends without paging of their own use the offset of a page as its
+/// token.
+pub fn parse_offset(page: Option<&str>) -> Result<usize> {
+    match page {
+        Some(x) => {
+            x.parse().map_err(|_| {
+                SurgeError::Backend(format!("Invalid page token '{0}'", x))
+            })
+        }
+        None => Ok(0),
+    }
 }
 
+/// The page of `all` starting at `offset`.
+pub fn offset_page(all: Vec<BackendSearchResult>, offset: usize, max_results: usize) -> ResultPage {
+    let more = all.len() > offset + max_results;
+    ResultPage {
+        results: all.into_iter().skip(offset).take(max_results).collect(),
+        next: if more {
+            Some((offset + max_results).to_string())
+        } else {
+            None
+        },
+        prev: if offset > 0 {
+            Some(offset.saturating_sub(max_results).to_string())
+        } else {
+            None
+        },
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendMode {
+    /// Ask every backend at once and merge what they find
+    Merge,
+    /// Ask one backend at a time, in order, until one of them answers
+    Fallback,
+}
+
+impl FromStr for BackendMode {
+    type Err = SurgeError;
+
+    fn from_str(s: &str) -> Result<BackendMode> {
+        match s.trim() {
+            "merge" => Ok(BackendMode::Merge),
+            "fallback" => Ok(BackendMode::Fallback),
+            x => Err(SurgeError::Backend(format!(
+                "Unknown backend mode '{0}', expected 'merge' or 'fallback'",
+                x
+            ))),
+        }
+    }
+}
+
+/// Search results, along with the backends that failed to contribute.
+pub struct SearchOutcome {
+    pub results: Vec<BackendSearchResult>,
+    /// Tokens for `MasterBackend`'s neighbouring pages
+    pub next: Option<String>,
+    pub prev: Option<String>,
+    pub failures: Vec<(BackendType, SurgeError)>,
+}
+
+/// Everything surge.ini says about backends
+pub struct BackendConfig {
+    /// Enabled backends, most preferred first
+    pub btypes: Vec<BackendType>,
+    pub mode: BackendMode,
+    pub yt_api_key: Option<String>,
+    /// youtube-dl or yt-dlp, see `ytdl::program`
+    pub ytdl_program: String,
+    /// Searched by the local backend, along with surge's download dir
+    pub local_dirs: Vec<PathBuf>,
+    /// How long to wait on any one backend before leaving it out
+    pub timeout: Duration,
+    /// Results per page, from each backend
+    pub max_results: usize,
+}
+
+type SharedBackend = Arc<Backend + Send + Sync>;
+
+/// A backend to ask, and the page to ask it for
+type Target<'a> = (&'a (BackendType, SharedBackend), Option<String>);
+
+/// Fans searches out to the enabled backends, either all at once or one
+/// after the other until one answers.
 pub struct MasterBackend {
-    btype: BackendType,
-    ytb: YoutubeBackend,
+    backends: Vec<(BackendType, SharedBackend)>,
+    mode: BackendMode,
+    timeout: Duration,
 }
 
 impl MasterBackend {
-    pub fn new(yt_api_key: &str) -> MasterBackend {
-        let ssl = NativeTlsClient::new().expect("Couldn't make TLS client");
-        let connector = HttpsConnector::new(ssl);
-        let client = Client::with_connector(connector);
+    pub fn new(config: BackendConfig) -> Result<MasterBackend> {
+        if config.btypes.is_empty() {
+            return Err(SurgeError::Backend(String::from("No backends configured")));
+        }
 
-        MasterBackend {
-            btype: BackendType::Youtube,
-            ytb: YoutubeBackend::new(String::from(yt_api_key), client),
+        let mut backends: Vec<(BackendType, SharedBackend)> = vec![];
+        for btype in config.btypes {
+            if backends.iter().any(|&(x, _)| x == btype) {
+                continue;
+            }
+            let backend: SharedBackend = match btype {
+                BackendType::Youtube => {
+                    let key = match config.yt_api_key {
+                        Some(ref x) => x.clone(),
+                        None => {
+                            return Err(SurgeError::Backend(String::from(
+                                "The youtube backend needs a yt_api_key in surge.ini",
+                            )))
+                        }
+                    };
+                    let ssl = NativeTlsClient::new().map_err(|e| {
+                        SurgeError::Backend(format!("Couldn't make TLS client: {0}", e))
+                    })?;
+                    let connector = HttpsConnector::new(ssl);
+                    let mut client = Client::with_connector(connector);
+                    client.set_read_timeout(Some(config.timeout));
+                    Arc::new(YoutubeBackend::new(key, client, config.max_results))
+                }
+                BackendType::Ytdl => {
+                    Arc::new(YtdlBackend::new(&config.ytdl_program, config.max_results))
+                }
+                BackendType::Local => {
+                    Arc::new(LocalBackend::new(
+                        config.local_dirs.clone(),
+                        config.max_results,
+                    ))
+                }
+                BackendType::Stream => {
+                    return Err(SurgeError::Backend(
+                        String::from("stream isn't a backend that can be configured"),
+                    ))
+                }
+            };
+            backends.push((btype, backend));
         }
+
+        Ok(MasterBackend {
+            backends: backends,
+            mode: config.mode,
+            timeout: config.timeout,
+        })
+    }
+
+    /// Whether results can come from more than one backend, in which case
+    /// they're worth labelling with their origin.
+    pub fn aggregated(&self) -> bool {
+        self.backends.len() > 1
+    }
+
+    /// The local backend, or the YouTube ones, in order of preference.
+    fn candidates(&self, local: bool) -> Vec<&(BackendType, SharedBackend)> {
+        self.backends
+            .iter()
+            .filter(|&&(btype, _)| (btype == BackendType::Local) == local)
+            .collect()
+    }
+
+    /// Related tracks come from a backend that knows the track: the local
+    /// library for files, and the YouTube backends otherwise, in order.
+    pub fn related_outcome(&self, x: &str, page: Option<&str>) -> Result<SearchOutcome> {
+        let candidates = self.candidates(Path::new(x).is_file());
+        if candidates.is_empty() {
+            return Err(SurgeError::Backend(String::from(
+                "No enabled backend can find related tracks for this",
+            )));
+        }
+        let id = String::from(x);
+        self.fall_through(
+            &targets(candidates, page)?,
+            &Arc::new(move |b: &Backend, page: Option<&str>| {
+                b.find_related_tracks(&id, page)
+            }),
+        )
+    }
+
+    /// Details of one track, from the first backend that knows it.
+    pub fn lookup(&self, x: &str) -> Result<BackendSearchResult> {
+        let candidates = self.candidates(Path::new(x).is_file());
+        if candidates.is_empty() {
+            return Err(SurgeError::Backend(
+                String::from("No enabled backend can look this up"),
+            ));
+        }
+        let id = String::from(x);
+        let mut outcome = self.fall_through(
+            &targets(candidates, None)?,
+            &Arc::new(move |b: &Backend, _: Option<&str>| {
+                b.lookup(&id).map(|x| {
+                    ResultPage {
+                        results: vec![x],
+                        next: None,
+                        prev: None,
+                    }
+                })
+            }),
+        )?;
+        Ok(outcome.results.remove(0))
+    }
+
+    /// Playlists and channels only exist on YouTube, so the first YouTube
+    /// backend to answer serves them.
+    pub fn collections_outcome(
+        &self,
+        x: &SearchQuery,
+        kind: ResultKind,
+        page: Option<&str>,
+    ) -> Result<SearchOutcome> {
+        let query = x.clone();
+        self.fall_through(
+            &targets(self.youtube_candidates()?, page)?,
+            &Arc::new(move |b: &Backend, page: Option<&str>| {
+                b.search_collections(&query, kind, page)
+            }),
+        )
+    }
+
+    pub fn collection_outcome(
+        &self,
+        x: &str,
+        kind: ResultKind,
+        page: Option<&str>,
+    ) -> Result<SearchOutcome> {
+        let id = String::from(x);
+        self.fall_through(
+            &targets(self.youtube_candidates()?, page)?,
+            &Arc::new(move |b: &Backend, page: Option<&str>| {
+                b.list_collection(&id, kind, page)
+            }),
+        )
+    }
+
+    pub fn collection_tracks(
+        &self,
+        x: &str,
+        kind: ResultKind,
+        max: usize,
+    ) -> Result<Vec<BackendSearchResult>> {
+        let id = String::from(x);
+        let outcome = self.fall_through(
+            &targets(self.youtube_candidates()?, None)?,
+            &Arc::new(move |b: &Backend, _: Option<&str>| {
+                b.collection_tracks(&id, kind, max).map(|x| {
+                    ResultPage {
+                        results: x,
+                        next: None,
+                        prev: None,
+                    }
+                })
+            }),
+        )?;
+        Ok(outcome.results)
+    }
+
+    fn youtube_candidates(&self) -> Result<Vec<&(BackendType, SharedBackend)>> {
+        let candidates = self.candidates(false);
+        if candidates.is_empty() {
+            return Err(SurgeError::Backend(String::from(
+                "Playlists and channels need the youtube or ytdl backend",
+            )));
+        }
+        Ok(candidates)
+    }
+
+    pub fn search_outcome(&self, x: &SearchQuery, page: Option<&str>) -> Result<SearchOutcome> {
+        let targets = targets(self.backends.iter().collect(), page)?;
+        let query = x.clone();
+        let f = Arc::new(move |b: &Backend, page: Option<&str>| b.search(&query, page));
+
+        if self.mode == BackendMode::Fallback {
+            return self.fall_through(&targets, &f);
+        }
+
+        // all backends share one deadline, so the slowest one bounds the
+        // whole search
+        let deadline = Instant::now() + self.timeout;
+        let pending = targets
+            .into_iter()
+            .map(|(&(btype, ref backend), page)| (btype, spawn(backend, page, &f)))
+            .collect::<Vec<_>>();
+        let outcomes = pending
+            .into_iter()
+            .map(|(btype, rx)| (btype, wait(rx, deadline, btype)))
+            .collect::<Vec<_>>();
+        merge(outcomes)
+    }
+
+    /// Try `targets` in order, each with its own timeout, until one
+    /// answers; errors, quota exhaustion and timeouts all fall through.
+    fn fall_through<F>(&self, targets: &[Target], f: &Arc<F>) -> Result<SearchOutcome>
+    where
+        F: Fn(&Backend, Option<&str>) -> Result<ResultPage> + Send + Sync + 'static,
+    {
+        let mut failures = vec![];
+        for &(&(btype, ref backend), ref page) in targets {
+            let rx = spawn(backend, page.clone(), f);
+            match wait(rx, Instant::now() + self.timeout, btype) {
+                Ok(page) => {
+                    return Ok(SearchOutcome {
+                        results: page.results,
+                        next: join_tokens(page.next.into_iter().map(|x| (btype, x)).collect()),
+                        prev: join_tokens(page.prev.into_iter().map(|x| (btype, x)).collect()),
+                        failures: failures,
+                    })
+                }
+                Err(e) => failures.push((btype, e)),
+            }
+        }
+        Err(all_failed(failures))
     }
 }
 
 impl Backend for MasterBackend {
-    fn find_related_tracks(&self, x: &str) -> Vec<BackendSearchResult> {
-        match self.btype {
-            BackendType::Youtube => self.ytb.find_related_tracks(x),
+    fn find_related_tracks(&self, x: &str, page: Option<&str>) -> Result<ResultPage> {
+        self.related_outcome(x, page).map(|x| {
+            ResultPage {
+                results: x.results,
+                next: x.next,
+                prev: x.prev,
+            }
+        })
+    }
+
+    fn search(&self, x: &SearchQuery, page: Option<&str>) -> Result<ResultPage> {
+        self.search_outcome(x, page).map(|x| {
+            ResultPage {
+                results: x.results,
+                next: x.next,
+                prev: x.prev,
+            }
+        })
+    }
+
+    fn search_collections(
+        &self,
+        x: &SearchQuery,
+        kind: ResultKind,
+        page: Option<&str>,
+    ) -> Result<ResultPage> {
+        self.collections_outcome(x, kind, page).map(|x| {
+            ResultPage {
+                results: x.results,
+                next: x.next,
+                prev: x.prev,
+            }
+        })
+    }
+
+    fn lookup(&self, x: &str) -> Result<BackendSearchResult> {
+        MasterBackend::lookup(self, x)
+    }
+
+    fn list_collection(&self, x: &str, kind: ResultKind, page: Option<&str>) -> Result<ResultPage> {
+        self.collection_outcome(x, kind, page).map(|x| {
+            ResultPage {
+                results: x.results,
+                next: x.next,
+                prev: x.prev,
+            }
+        })
+    }
+
+    fn collection_tracks(
+        &self,
+        x: &str,
+        kind: ResultKind,
+        max: usize,
+    ) -> Result<Vec<BackendSearchResult>> {
+        MasterBackend::collection_tracks(self, x, kind, max)
+    }
+}
+
+/// The backends to ask for `page`: all of `candidates` for a first page,
+/// otherwise only those with a token in it.
+fn targets<'a>(
+    candidates: Vec<&'a (BackendType, SharedBackend)>,
+    page: Option<&str>,
+) -> Result<Vec<Target<'a>>> {
+    let tokens = match page {
+        Some(x) => split_tokens(x)?,
+        None => return Ok(candidates.into_iter().map(|x| (x, None)).collect()),
+    };
+    let ret = candidates
+        .into_iter()
+        .filter_map(|x| {
+            tokens.iter().find(|&&(btype, _)| btype == x.0).map(
+                |&(_, ref token)| (x, Some(token.clone())),
+            )
+        })
+        .collect::<Vec<_>>();
+    if ret.is_empty() {
+        return Err(SurgeError::Backend(
+            String::from("None of the backends of that page are enabled"),
+        ));
+    }
+    Ok(ret)
+}
+
+/// A merged page is a page of each backend that had one, so its token is
+/// theirs strung together, e.g. `youtube=CAUQAA,ytdl=10`.
+fn join_tokens(tokens: Vec<(BackendType, String)>) -> Option<String> {
+    if tokens.is_empty() {
+        return None;
+    }
+    Some(
+        tokens
+            .iter()
+            .map(|&(btype, ref token)| format!("{0}={1}", btype, token))
+            .collect::<Vec<_>>()
+            .join(","),
+    )
+}
+
+fn split_tokens(page: &str) -> Result<Vec<(BackendType, String)>> {
+    let mut ret = vec![];
+    for x in page.split(',') {
+        let mut kv = x.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some(btype), Some(token)) => ret.push((btype.parse()?, String::from(token))),
+            _ => {
+                return Err(SurgeError::Backend(
+                    format!("Invalid page token '{0}'", page),
+                ))
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Run `f` against `backend` on its own thread.
+fn spawn<F>(backend: &SharedBackend, page: Option<String>, f: &Arc<F>) -> Receiver<Result<ResultPage>>
+where
+    F: Fn(&Backend, Option<&str>) -> Result<ResultPage> + Send + Sync + 'static,
+{
+    let (tx, rx) = channel();
+    let (backend, f) = (backend.clone(), f.clone());
+    thread::spawn(move || {
+        let _ = tx.send((*f)(&*backend, page.as_ref().map(String::as_str)));
+    });
+    rx
+}
+
+/// A backend that misses the deadline is given up on; its thread finishes
+/// in the background and the late answer is dropped.
+fn wait(rx: Receiver<Result<ResultPage>>, deadline: Instant, btype: BackendType) -> Result<ResultPage> {
+    let now = Instant::now();
+    let res = if now < deadline {
+        rx.recv_timeout(deadline - now).ok()
+    } else {
+        rx.try_recv().ok()
+    };
+    match res {
+        Some(x) => x,
+        None => Err(SurgeError::Backend(
+            format!("{0} didn't answer in time", btype),
+        )),
+    }
+}
+
+/// Interleave the results of each backend, best first, skipping any that
+/// look like one already taken from a more preferred backend. Failing
+/// backends are left out unless they all failed.
+fn merge(outcomes: Vec<(BackendType, Result<ResultPage>)>) -> Result<SearchOutcome> {
+    let mut lists = vec![];
+    let (mut next, mut prev) = (vec![], vec![]);
+    let mut failures = vec![];
+    for (btype, res) in outcomes {
+        match res {
+            Ok(x) => {
+                next.extend(x.next.map(|x| (btype, x)));
+                prev.extend(x.prev.map(|x| (btype, x)));
+                lists.push(x.results);
+            }
+            Err(e) => failures.push((btype, e)),
+        }
+    }
+    if lists.is_empty() {
+        return Err(all_failed(failures));
+    }
+
+    let mut merged: Vec<BackendSearchResult> = vec![];
+    let mut seen: Vec<String> = vec![];
+    let longest = lists.iter().map(Vec::len).max().unwrap_or(0);
+    for rank in 0..longest {
+        for list in &lists {
+            let x = match list.get(rank) {
+                Some(x) => x,
+                None => continue,
+            };
+            // a title that's all brackets says nothing about the track
+            let key = dedup_key(&x.title);
+            let duplicate = merged.iter().any(|m| m.id == x.id) ||
+                (!key.is_empty() &&
+                     seen.iter().any(|s| similarity(s, &key) >= DUPLICATE_THRESHOLD));
+            if !duplicate {
+                seen.push(key);
+                merged.push(x.clone());
+            }
+        }
+    }
+    Ok(SearchOutcome {
+        results: merged,
+        next: join_tokens(next),
+        prev: join_tokens(prev),
+        failures: failures,
+    })
+}
+
+/// A single failure is passed on as is, so with one backend configured
+/// its errors read the same as ever.
+fn all_failed(mut failures: Vec<(BackendType, SurgeError)>) -> SurgeError {
+    if failures.len() == 1 {
+        return failures.remove(0).1;
+    }
+    SurgeError::Backend(format!(
+        "Every backend failed: {0}",
+        failures
+            .iter()
+            .map(|&(btype, ref e)| format!("{0}: {1}", btype, e))
+            .collect::<Vec<_>>()
+            .join("; ")
+    ))
+}
+
+/// Lowercased words of a title, minus anything in brackets, so that
+/// "Song (Official Video)" and "song [HD]" compare equal.
+fn dedup_key(title: &str) -> String {
+    let mut depth = 0;
+    let mut stripped = String::new();
+    for c in title.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' if depth > 0 => depth -= 1,
+            _ if depth == 0 => stripped.push(c),
+            _ => (),
+        }
+    }
+    stripped
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Edit distance scaled to 0..1, 1 being identical.
+fn similarity(a: &str, b: &str) -> f64 {
+    let longest = a.chars().count().max(b.chars().count());
+    if longest == 0 {
+        return 1.0;
+    }
+    1.0 - levenshtein(a, b) as f64 / longest as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(id: &str, title: &str, source: BackendType) -> BackendSearchResult {
+        BackendSearchResult::new(String::from(id), String::from(title), source)
+    }
+
+    fn page(results: Vec<BackendSearchResult>) -> Result<ResultPage> {
+        Ok(ResultPage {
+            results: results,
+            next: None,
+            prev: None,
+        })
+    }
+
+    fn ids(outcome: &SearchOutcome) -> Vec<&str> {
+        outcome.results.iter().map(|x| x.id.as_str()).collect()
+    }
+
+    #[test]
+    fn merge_interleaves_by_rank_in_backend_order() {
+        let outcome = merge(vec![
+            (
+                BackendType::Youtube,
+                page(vec![
+                    result("y1", "Daft Punk - One More Time", BackendType::Youtube),
+                    result("y2", "Daft Punk - Aerodynamic", BackendType::Youtube),
+                    result("y3", "Daft Punk - Digital Love", BackendType::Youtube),
+                ]),
+            ),
+            (
+                BackendType::Local,
+                page(vec![
+                    result("l1", "Justice - Genesis", BackendType::Local),
+                    result("l2", "Justice - Phantom", BackendType::Local),
+                ]),
+            ),
+        ]).unwrap();
+        assert_eq!(ids(&outcome), vec!["y1", "l1", "y2", "l2", "y3"]);
+        assert!(outcome.failures.is_empty());
+    }
+
+    #[test]
+    fn merge_keeps_the_first_of_duplicates() {
+        let outcome = merge(vec![
+            (
+                BackendType::Youtube,
+                page(vec![
+                    result(
+                        "y1",
+                        "Daft Punk - One More Time (Official Video)",
+                        BackendType::Youtube,
+                    ),
+                    result("same", "Something Else", BackendType::Youtube),
+                ]),
+            ),
+            (
+                BackendType::Ytdl,
+                page(vec![
+                    result("d1", "daft punk one more time [HD]", BackendType::Ytdl),
+                    result("same", "Something Else Again", BackendType::Ytdl),
+                ]),
+            ),
+        ]).unwrap();
+        assert_eq!(ids(&outcome), vec!["y1", "same"]);
+        assert_eq!(outcome.results[1].source, BackendType::Youtube);
+    }
+
+    #[test]
+    fn merge_leaves_failed_backends_out() {
+        let outcome = merge(vec![
+            (BackendType::Youtube, Err(SurgeError::Backend(String::from("quota")))),
+            (BackendType::Local, page(vec![result("l1", "Genesis", BackendType::Local)])),
+        ]).unwrap();
+        assert_eq!(ids(&outcome), vec!["l1"]);
+        assert_eq!(outcome.failures.len(), 1);
+        assert_eq!(outcome.failures[0].0, BackendType::Youtube);
+
+        let all_failed = merge(vec![
+            (BackendType::Youtube, Err(SurgeError::Backend(String::from("quota")))),
+            (BackendType::Local, Err(SurgeError::Backend(String::from("no dirs")))),
+        ]);
+        assert!(all_failed.is_err());
+    }
+
+    #[test]
+    fn titles_past_the_threshold_are_duplicates() {
+        // one edit in ten
+        assert_eq!(similarity("abcdefghij", "abcdefghik"), 0.9);
+        assert!(similarity("abcdefghij", "abcdefghij") >= DUPLICATE_THRESHOLD);
+        assert!(similarity("abcdefghij", "abcdefghik") >= DUPLICATE_THRESHOLD);
+        assert!(similarity("abcdefghij", "abcdefghkl") < DUPLICATE_THRESHOLD);
+        assert!(similarity("track 10", "track 11") < DUPLICATE_THRESHOLD);
+        assert_eq!(similarity("", ""), 1.0);
+    }
+
+    #[test]
+    fn numbered_tracks_stay_apart() {
+        let outcome = merge(vec![
+            (BackendType::Local, page(vec![result("a", "Track 10", BackendType::Local)])),
+            (BackendType::Ytdl, page(vec![result("b", "Track 11", BackendType::Ytdl)])),
+        ]).unwrap();
+        assert_eq!(ids(&outcome), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn dedup_keys_drop_brackets_case_and_punctuation() {
+        assert_eq!(dedup_key("Song (Official Video)"), "song");
+        assert_eq!(dedup_key("song [HD]"), "song");
+        assert_eq!(dedup_key("A - B (feat. C) [Remix (Extended)]"), "a b");
+        assert_eq!(dedup_key("Unclosed (bracket"), "unclosed");
+        assert_eq!(dedup_key("Stray) bracket"), "stray bracket");
+        assert_eq!(dedup_key("[Official Video]"), "");
+    }
+
+    #[test]
+    fn titles_of_only_brackets_are_never_duplicates() {
+        let outcome = merge(vec![
+            (
+                BackendType::Youtube,
+                page(vec![
+                    result("y1", "[Official Video]", BackendType::Youtube),
+                    result("y2", "(Live)", BackendType::Youtube),
+                ]),
+            ),
+            (BackendType::Ytdl, page(vec![result("d1", "[HD]", BackendType::Ytdl)])),
+        ]).unwrap();
+        assert_eq!(ids(&outcome), vec!["y1", "d1", "y2"]);
+    }
+
+    struct Nothing;
+
+    impl Backend for Nothing {
+        fn find_related_tracks(&self, _: &str, _: Option<&str>) -> Result<ResultPage> {
+            page(vec![])
+        }
+
+        fn search(&self, _: &SearchQuery, _: Option<&str>) -> Result<ResultPage> {
+            page(vec![])
         }
     }
 
-    fn search(&self, x: &str) -> Vec<BackendSearchResult> {
-        match self.btype {
-            BackendType::Youtube => self.ytb.search(x),
+    fn backends(btypes: &[BackendType]) -> Vec<(BackendType, SharedBackend)> {
+        btypes
+            .iter()
+            .map(|&x| (x, Arc::new(Nothing) as SharedBackend))
+            .collect()
+    }
+
+    /// Which of `btypes` `targets` asks for `page`, and with what token.
+    fn target_pages(
+        btypes: &[BackendType],
+        page: Option<&str>,
+    ) -> Result<Vec<(BackendType, Option<String>)>> {
+        let all = backends(btypes);
+        let ret = targets(all.iter().collect(), page)?
+            .into_iter()
+            .map(|(&(btype, _), page)| (btype, page))
+            .collect();
+        Ok(ret)
+    }
+
+    #[test]
+    fn page_tokens_round_trip() {
+        let tokens = vec![
+            (BackendType::Youtube, String::from("CAUQAA")),
+            (BackendType::Ytdl, String::from("10")),
+            (BackendType::Local, String::from("a=b")),
+        ];
+        let joined = join_tokens(tokens.clone()).unwrap();
+        assert_eq!(joined, "youtube=CAUQAA,ytdl=10,local=a=b");
+        assert_eq!(split_tokens(&joined).unwrap(), tokens);
+        assert_eq!(join_tokens(vec![]), None);
+    }
+
+    #[test]
+    fn merged_pages_only_carry_on_with_backends_that_have_more() {
+        let outcome = merge(vec![
+            (
+                BackendType::Youtube,
+                Ok(ResultPage {
+                    results: vec![result("y1", "One", BackendType::Youtube)],
+                    next: Some(String::from("CAUQAA")),
+                    prev: None,
+                }),
+            ),
+            (
+                BackendType::Ytdl,
+                Ok(ResultPage {
+                    results: vec![result("d1", "Two", BackendType::Ytdl)],
+                    next: None,
+                    prev: Some(String::from("0")),
+                }),
+            ),
+        ]).unwrap();
+        assert_eq!(outcome.next, Some(String::from("youtube=CAUQAA")));
+        assert_eq!(outcome.prev, Some(String::from("ytdl=0")));
+
+        let enabled = [BackendType::Youtube, BackendType::Ytdl];
+        assert_eq!(
+            target_pages(&enabled, outcome.next.as_ref().map(String::as_str)).unwrap(),
+            vec![(BackendType::Youtube, Some(String::from("CAUQAA")))]
+        );
+        assert_eq!(
+            target_pages(&enabled, None).unwrap(),
+            vec![(BackendType::Youtube, None), (BackendType::Ytdl, None)]
+        );
+    }
+
+    #[test]
+    fn targets_follow_backend_order_not_token_order() {
+        let enabled = [BackendType::Local, BackendType::Ytdl];
+        assert_eq!(
+            target_pages(&enabled, Some("ytdl=20,local=10")).unwrap(),
+            vec![
+                (BackendType::Local, Some(String::from("10"))),
+                (BackendType::Ytdl, Some(String::from("20"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn page_tokens_of_disabled_backends_are_refused() {
+        assert!(target_pages(&[BackendType::Local], Some("youtube=CAUQAA")).is_err());
+    }
+
+    #[test]
+    fn malformed_page_tokens_are_refused() {
+        for x in &["", "CAUQAA", "youtube=CAUQAA,", "spotify=1", "youtube=CAUQAA,10"] {
+            assert!(split_tokens(x).is_err(), "{0}", x);
+            assert!(target_pages(&[BackendType::Youtube], Some(x)).is_err(), "{0}", x);
+        }
+    }
+
+    #[test]
+    fn offset_pages() {
+        let all = (0..5)
+            .map(|i| result(&i.to_string(), "x", BackendType::Local))
+            .collect::<Vec<_>>();
+        let first = offset_page(all.clone(), parse_offset(None).unwrap(), 2);
+        assert_eq!((first.next, first.prev), (Some(String::from("2")), None));
+        let last = offset_page(all.clone(), parse_offset(Some("4")).unwrap(), 2);
+        assert_eq!(last.results.len(), 1);
+        assert_eq!((last.next, last.prev), (None, Some(String::from("2"))));
+        let past = offset_page(all, 10, 2);
+        assert!(past.results.is_empty());
+        assert_eq!(past.next, None);
+        assert!(parse_offset(Some("youtube=2")).is_err());
+        assert!(parse_offset(Some("-2")).is_err());
+    }
+
+    fn query(x: &str) -> SearchQuery {
+        match x.parse() {
+            Ok(x) => x,
+            Err(e) => panic!("'{0}' gave {1}", x, e),
+        }
+    }
+
+    fn refused(x: &str) -> String {
+        match x.parse::<SearchQuery>() {
+            Ok(q) => panic!("'{0}' gave {1:?}", x, q),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn queries_without_filters_are_all_terms() {
+        assert_eq!(
+            query("  daft   punk "),
+            SearchQuery {
+                terms: String::from("daft punk"),
+                ..SearchQuery::default()
+            }
+        );
+    }
+
+    #[test]
+    fn each_filter_key() {
+        let q = query(
+            "lofi duration:LONG order:viewcount region:ca after:2020-06 safe:strict \
+             category:music",
+        );
+        assert_eq!(
+            q,
+            SearchQuery {
+                terms: String::from("lofi"),
+                duration: Some(String::from("long")),
+                order: Some(String::from("viewCount")),
+                region: Some(String::from("CA")),
+                published_after: Some(String::from("2020-06-01T00:00:00Z")),
+                safe_search: Some(String::from("strict")),
+                category: Some(String::from(MUSIC_CATEGORY)),
+            }
+        );
+        assert_eq!(query("x category:20").category, Some(String::from("20")));
+    }
+
+    #[test]
+    fn unknown_keys_and_empty_values_are_terms() {
+        let q = query("artist:daft punk duration: 12:34");
+        assert_eq!(q.terms, "artist:daft punk duration: 12:34");
+        assert_eq!(q.duration, None);
+    }
+
+    #[test]
+    fn the_last_of_a_repeated_filter_wins() {
+        assert_eq!(
+            query("x order:date order:title").order,
+            Some(String::from("title"))
+        );
+    }
+
+    #[test]
+    fn invalid_filter_values_are_refused() {
+        assert!(refused("x duration:forever").contains("Invalid duration 'forever'"));
+        assert!(refused("x order:random").contains("Invalid order 'random'"));
+        assert!(refused("x safe:off").contains("Invalid safe 'off'"));
+        assert!(refused("x region:CAN").contains("Invalid region 'CAN'"));
+        assert!(refused("x region:C1").contains("Invalid region 'C1'"));
+        assert!(refused("x category:jazz").contains("Invalid category 'jazz'"));
+        assert!(refused("x after:yesterday").contains("Invalid date 'yesterday'"));
+    }
+
+    #[test]
+    fn filters_alone_are_not_a_search() {
+        assert!(refused("order:date").contains("Missing search terms"));
+        assert!(refused("   ").contains("Missing search terms"));
+    }
+
+    #[test]
+    fn dates_start_their_year_month_or_day() {
+        assert_eq!(rfc3339_date("2020").unwrap(), "2020-01-01T00:00:00Z");
+        assert_eq!(rfc3339_date("2020-06").unwrap(), "2020-06-01T00:00:00Z");
+        assert_eq!(rfc3339_date("2020-06-15").unwrap(), "2020-06-15T00:00:00Z");
+    }
+
+    #[test]
+    fn invalid_dates_are_refused() {
+        let cases = [
+            "20",
+            "20201",
+            "2020-6",
+            "2020-06-1",
+            "2020-06-15-01",
+            "2020--15",
+            "2020-",
+            "2020/06",
+            "2020-00",
+            "2020-13",
+            "2020-06-00",
+            "2020-06-32",
+            "２０２０",
+            "",
+        ];
+        for x in &cases {
+            assert!(rfc3339_date(x).is_err(), "{0}", x);
         }
     }
 }
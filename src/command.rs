@@ -5,209 +5,1390 @@ use term_size::dimensions;
 use player::*;
 use download::Downloader;
 use backend::*;
+use link::{self, Link};
+use radio::{Radio, TopUp};
+use playlist_file;
+use history::{self, Play};
+use library::{self, Favorite, Library};
+use smart::SmartQuery;
+use parser;
+use parser::Command;
+use error::{Result, SurgeError};
 
-use std::io::{StdoutLock, Write};
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
 
 const SCALE_FACTOR: f32 = 0.5;
+/// Thumbnails in `list` are kept small enough to skim past
+const LIST_SCALE_FACTOR: f32 = 0.1;
+/// Used for `list` when the terminal doesn't say how wide it is
+const DEFAULT_WIDTH: usize = 80;
+const LIST_CHANNEL_WIDTH: usize = 24;
+/// Descriptions get one line of at most this many characters
+const DESCRIPTION_WIDTH: usize = 76;
+/// Queueing a playlist or channel stops after this many tracks
+const COLLECTION_MAX_TRACKS: usize = 200;
+/// How many plays `history` and `history search` list
+const HISTORY_SHOWN: usize = 20;
 
-pub struct CommandCenter<'a> {
+/// What `currents` was listed from, so more pages of it can be fetched
+#[derive(Clone)]
+enum Listing {
+    Search(SearchQuery),
+    Related(String),
+    Collections(SearchQuery, ResultKind),
+    Collection(String, ResultKind),
+}
+
+pub struct CommandCenter {
     currents: Vec<BackendSearchResult>,
     current: Option<BackendSearchResult>,
-    out: StdoutLock<'a>,
     cycle_ctr: usize,
+    listing: Option<Listing>,
+    next_page: Option<String>,
+    prev_page: Option<String>,
+    /// Backends the last search had to skip
+    backend_failures: Vec<String>,
     nodl: bool,
-    player: &'a mut AudioPlayer,
-    dloader: &'a mut Downloader,
-    backend: &'a mut MasterBackend,
+    player: AudioPlayer,
+    dloader: Arc<Downloader>,
+    backend: Arc<MasterBackend>,
+    radio: Radio,
+    library: Library,
+    events: Receiver<PlayerEvent>,
 }
 
-impl<'a> CommandCenter<'a> {
+impl CommandCenter {
     pub fn new(
-        out: StdoutLock<'a>,
-        player: &'a mut AudioPlayer,
-        dloader: &'a mut Downloader,
-        backend: &'a mut MasterBackend,
-    ) -> CommandCenter<'a> {
+        player: AudioPlayer,
+        dloader: Downloader,
+        backend: MasterBackend,
+        radio: Radio,
+        library: Library,
+    ) -> CommandCenter {
+        let events = player.subscribe();
         CommandCenter {
             currents: vec![],
             current: None,
-            out: out,
             cycle_ctr: 0,
+            listing: None,
+            next_page: None,
+            prev_page: None,
+            backend_failures: vec![],
             nodl: true,
             player: player,
-            dloader: dloader,
-            backend: backend,
-        }
-    }
-
-    pub fn handle_command(&mut self, command: &str) {
-        let cmd_split = command.splitn(2, ' ').collect::<Vec<&str>>();
-        match cmd_split[0] {
-            "" => (),
-            "play" => {
-                if cmd_split.len() == 2 {
-                    if let Ok(sel) = cmd_split[1].parse() {
-                        self.select(sel);
-                        let dl = self.download().expect("Couldn't dl song");
-                        self.player.queue_and_play(dl);
-                    } else {
-                        println!("Please pick a valid selection");
-                        return;
-                    }
+            dloader: Arc::new(dloader),
+            backend: Arc::new(backend),
+            radio: radio,
+            library: library,
+            events: events,
+        }
+    }
+
+    /// Run one line of input, writing everything meant for the user to
+    /// `out`. Errors are reported to `out` too, so a failing command never
+    /// takes the session down with it.
+    pub fn handle_command(&mut self, command: &str, out: &mut Write) {
+        let res = self.handle_player_events(out).and_then(
+            |_| match Command::parse(command) {
+                Ok(cmd) => self.run(cmd, out),
+                Err(e) => writeln!(out, "{0}", e).map_err(SurgeError::from),
+            },
+        );
+        if let Err(e) = res {
+            let _ = writeln!(out, "{0}", e);
+        }
+    }
+
+    fn run(&mut self, cmd: Command, out: &mut Write) -> Result<()> {
+        match cmd {
+            Command::Empty => (),
+            Command::Play(sel) => {
+                if let Some(x) = self.play(sel)? {
+                    self.show_selected(&x, out)?;
                 }
-                self.player.resume();
             }
-            "download" => {
+            Command::Download => {
                 if self.nodl {
-                    println!("Toggling download mode: ON")
+                    writeln!(out, "Toggling download mode: ON")?
                 } else {
-                    println!("Toggling download mode: OFF")
+                    writeln!(out, "Toggling download mode: OFF")?
                 }
                 self.nodl = !self.nodl;
             }
-            "queue" => {
-                if let Ok(sel) = cmd_split[1].parse() {
-                    self.select(sel);
+            Command::Queue(sel) => {
+                let x = self.queue(sel)?;
+                self.show_selected(&x, out)?;
+            }
+            Command::PlayUrl(target) => {
+                let x = self.play_url(&target)?;
+                self.show_selected(&x, out)?;
+            }
+            Command::QueueUrl(target) => {
+                let x = self.queue_url(&target)?;
+                self.show_selected(&x, out)?;
+            }
+            Command::QueueList => self.list_queue(out)?,
+            Command::QueueClear => {
+                self.player.clear_queue()?;
+                writeln!(out, "Cleared the queue")?;
+            }
+            Command::QueueImport(file) => {
+                let (n, skipped) = self.queue_import(&file)?;
+                writeln!(out, "Queued {0} tracks from {1}", n, file)?;
+                show_skipped(out, skipped)?;
+            }
+            Command::QueueExport(file) => {
+                let n = self.queue_export(&file)?;
+                writeln!(out, "Wrote {0} tracks to {1}", n, file)?;
+            }
+            Command::QueueMove(from, to) => {
+                if self.player.move_entry(from, to)? {
+                    self.list_queue(out)?;
+                } else {
+                    writeln!(out, "Please pick valid queue positions")?;
+                }
+            }
+            Command::QueueRemove(idx) => {
+                if self.player.remove_entry(idx)? {
+                    self.list_queue(out)?;
+                } else {
+                    writeln!(out, "Please pick a valid queue position")?;
+                }
+            }
+            Command::Skip => {
+                if !self.player.skip()? {
+                    writeln!(out, "Nothing left in the queue to skip to")?;
+                }
+            }
+            Command::Prev => {
+                if !self.player.prev()? {
+                    writeln!(out, "Already at the start of the queue")?;
+                }
+            }
+            Command::Jump(idx) => {
+                if !self.player.jump(idx)? {
+                    writeln!(out, "Please pick a valid queue position")?;
+                }
+            }
+            Command::Loop => {
+                if self.toggle_loop()? {
+                    writeln!(out, "Toggling loop on")?;
                 } else {
-                    println!("Please pick a valid selection");
-                    return;
-                }
-                let dl = self.download().expect("Couldn't dl song");
-                self.player.queue(dl);
-            }
-            "loop" => self.player.loop_(),
-            "pause" => self.player.pause(),
-            "related" => {
-                self.related("");
-                self.cycle();
-            }
-            "cycle" => self.cycle(),
-            "now" => {
-                self.now();
-                self.player.print_time_remain();
-            }
-            "stop" => self.stop(),
-            "search" => {
-                if cmd_split.len() == 2 {
-                    self.search(cmd_split[1]);
-                    self.cycle();
+                    writeln!(out, "Toggling loop off")?;
+                }
+            }
+            Command::Radio(sel) => {
+                let (on, queued) = self.radio(sel)?;
+                if on {
+                    writeln!(out, "Radio on")?;
                 } else {
-                    println!("Please enter non-empty search terms");
+                    writeln!(out, "Radio off")?;
+                }
+                for x in queued {
+                    writeln!(out, "Queued: {0}", x.title)?;
+                }
+            }
+            Command::Pause => self.pause()?,
+            Command::Related => {
+                self.related()?;
+                self.report_failures(out)?;
+                self.cycle(out)?;
+            }
+            Command::More => {
+                self.more()?;
+                self.report_failures(out)?;
+                self.cycle(out)?;
+            }
+            Command::NextPage => {
+                self.next_page()?;
+                self.report_failures(out)?;
+                self.cycle(out)?;
+            }
+            Command::PrevPage => {
+                self.prev_page()?;
+                self.report_failures(out)?;
+                self.cycle(out)?;
+            }
+            Command::SearchCollections(terms, kind) => {
+                self.search_collections(&terms, kind)?;
+                self.report_failures(out)?;
+                self.cycle(out)?;
+            }
+            Command::Open(sel) => {
+                self.open(sel)?;
+                self.report_failures(out)?;
+                self.cycle(out)?;
+            }
+            Command::Cycle => self.cycle(out)?,
+            Command::List(thumbnails) => self.list(out, thumbnails)?,
+            Command::Now => {
+                self.now(out)?;
+                if let Some(x) = self.time_remain() {
+                    writeln!(out, "Time remaining: {0}s", x)?;
+                }
+            }
+            Command::Stop => self.stop()?,
+            Command::Search(terms) => {
+                self.search(&terms)?;
+                self.report_failures(out)?;
+                self.cycle(out)?;
+            }
+            Command::PlaylistSave(name) => {
+                let n = self.playlist_save(&name)?;
+                writeln!(out, "Saved {0} tracks as '{1}'", n, name)?;
+            }
+            Command::PlaylistLoad(name) => {
+                let n = self.playlist_load(&name)?;
+                writeln!(out, "Queued {0} tracks from '{1}'", n, name)?;
+            }
+            Command::PlaylistList => {
+                let playlists = self.playlist_list()?;
+                if playlists.is_empty() {
+                    writeln!(out, "No saved playlists. Try 'playlist save'")?;
+                }
+                for (name, n) in playlists {
+                    writeln!(out, "{0} ({1} tracks)", name, n)?;
+                }
+            }
+            Command::PlaylistDelete(name) => {
+                self.playlist_delete(&name)?;
+                writeln!(out, "Deleted '{0}'", name)?;
+            }
+            Command::PlaylistAdd(name, sel) => {
+                for x in self.playlist_add(&name, sel)? {
+                    writeln!(out, "Added to '{0}': {1}", name, x.title)?;
+                }
+            }
+            Command::PlaylistRemove(name, pos) => {
+                let x = self.playlist_remove(&name, pos)?;
+                writeln!(out, "Removed from '{0}': {1}", name, x.title)?;
+            }
+            Command::PlaylistShow(name) => {
+                let tracks = self.playlist_tracks(&name)?;
+                if tracks.is_empty() {
+                    writeln!(out, "'{0}' is empty", name)?;
+                }
+                for (i, x) in tracks.iter().enumerate() {
+                    match x.duration {
+                        Some(secs) => {
+                            writeln!(out, "{0}: {1} ({2})", i, x.title, format_duration(secs))?
+                        }
+                        None => writeln!(out, "{0}: {1}", i, x.title)?,
+                    }
+                }
+            }
+            Command::PlaylistImport(name, file) => {
+                let (n, skipped) = self.playlist_import(&name, &file)?;
+                writeln!(out, "Saved {0} tracks from {1} as '{2}'", n, file, name)?;
+                show_skipped(out, skipped)?;
+            }
+            Command::PlaylistExport(name, file) => {
+                let n = self.playlist_export(&name, &file)?;
+                writeln!(out, "Wrote {0} tracks of '{1}' to {2}", n, name, file)?;
+            }
+            Command::History => {
+                let plays = self.history(HISTORY_SHOWN)?;
+                if plays.is_empty() {
+                    writeln!(out, "Nothing played yet")?;
+                }
+                for (i, play) in plays.iter().enumerate() {
+                    show_play(out, i, play)?;
+                }
+            }
+            Command::HistorySearch(query) => {
+                let found = self.history_search(&query, HISTORY_SHOWN)?;
+                if found.is_empty() {
+                    writeln!(out, "Nothing played matches '{0}'", query)?;
+                }
+                for (i, play) in found {
+                    show_play(out, i, &play)?;
+                }
+            }
+            Command::HistoryReplay(pos) => {
+                let x = self.history_replay(pos)?;
+                self.show_selected(&x, out)?;
+            }
+            Command::Fav(sel) => {
+                let (x, added) = self.fav(sel)?;
+                if added {
+                    writeln!(out, "Added to favorites: {0}", x.title)?;
+                } else {
+                    writeln!(out, "Already a favorite: {0}", x.title)?;
+                }
+            }
+            Command::Unfav(sel) => {
+                let x = self.unfav(sel)?;
+                writeln!(out, "Removed from favorites: {0}", x.title)?;
+            }
+            Command::Rate(stars, sel) => {
+                let x = self.rate(stars, sel)?;
+                writeln!(out, "Rated {0}: {1}", stars_of(stars as u8), x.title)?;
+            }
+            Command::Favs => {
+                let favs = self.favs()?;
+                if favs.is_empty() {
+                    writeln!(out, "No favorites yet. Try 'fav'")?;
+                }
+                for (i, x) in favs.iter().enumerate() {
+                    match x.rating {
+                        Some(n) => writeln!(out, "{0}: {1} {2}", i, x.track.title, stars_of(n))?,
+                        None => writeln!(out, "{0}: {1}", i, x.track.title)?,
+                    }
+                }
+            }
+            Command::Smart(rules) => {
+                let tracks = self.smart(&rules)?;
+                writeln!(out, "Queued {0} tracks", tracks.len())?;
+            }
+            Command::SmartSave(name, rules) => {
+                self.smart_save(&name, &rules)?;
+                writeln!(out, "Saved '{0}': {1}", name, rules)?;
+            }
+            Command::SmartList => {
+                let playlists = self.smart_list()?;
+                if playlists.is_empty() {
+                    writeln!(out, "No smart playlists. Try 'smart save'")?;
+                }
+                for (name, rules) in playlists {
+                    writeln!(out, "{0}: {1}", name, rules)?;
+                }
+            }
+            Command::SmartDelete(name) => {
+                self.smart_delete(&name)?;
+                writeln!(out, "Deleted '{0}'", name)?;
+            }
+            Command::Help(topic) => {
+                match parser::help(topic.as_ref().map(String::as_str)) {
+                    Ok(x) => write!(out, "{0}", x)?,
+                    Err(e) => writeln!(out, "{0}", e)?,
                 }
             }
-            "help" => unimplemented!(),
-            _ => println!("Unrecognized command! Try 'help'"),
         }
+        Ok(())
     }
 
-    fn cycle(&mut self) {
+    fn cycle(&mut self, out: &mut Write) -> Result<()> {
+        if self.currents.is_empty() {
+            writeln!(out, "No results to cycle through. Try 'search'")?;
+            return Ok(());
+        }
         if self.cycle_ctr > self.currents.len() - 1 {
             self.cycle_ctr = 0;
         }
-        match self.currents.get(self.cycle_ctr) {
-            Some(x) => {
-                println!("{0}: {1}", self.cycle_ctr, x.title);
-                display_png(
-                    self.dloader.download_thumbnail(
-                        x.thumbnail.as_ref().map(String::as_str),
-                        &x.id,
-                    ),
-                    &mut self.out,
-                );
+        if let Some(x) = self.currents.get(self.cycle_ctr) {
+            if self.backend.aggregated() {
+                writeln!(out, "{0}: {1} [{2}]", self.cycle_ctr, label(x), x.source)?;
+            } else {
+                writeln!(out, "{0}: {1}", self.cycle_ctr, label(x))?;
             }
-            None => panic!("Shouldn't happen"),
+            show_details(out, x)?;
+            show_thumbnail(&self.dloader, out, x, SCALE_FACTOR)?;
         }
         self.cycle_ctr += 1;
+        Ok(())
     }
 
-    fn now(&mut self) {
-        match self.current {
-            Some(ref x) => {
-                println!("NOW PLAYING: {0}", x.title);
-                display_png(
-                    self.dloader.download_thumbnail(
-                        x.thumbnail.as_ref().map(String::as_str),
-                        &x.id,
-                    ),
-                    &mut self.out,
-                );
-            }
-            None => println!("Nothing currently playing."),
-        }
-    }
-
-    fn select(&mut self, sel: usize) {
-        self.current = Some(self.currents.remove(sel));
-        if let Some(ref x) = self.current {
-            println!("SELECTED: {0}", x.title);
-            display_png(
-                self.dloader.download_thumbnail(
-                    x.thumbnail.as_ref().map(String::as_str),
-                    &x.id,
-                ),
-                &mut self.out,
+    /// Every current result as a row of index, title, channel and duration,
+    /// cut to the width of the terminal.
+    fn list(&self, out: &mut Write, thumbnails: bool) -> Result<()> {
+        if self.currents.is_empty() {
+            writeln!(out, "No results to list. Try 'search'")?;
+            return Ok(());
+        }
+        let width = dimensions().map(|(w, _)| w).unwrap_or(DEFAULT_WIDTH);
+        let channels = self.currents
+            .iter()
+            .map(|x| x.artist.clone().unwrap_or_default())
+            .collect::<Vec<_>>();
+        let durations = self.currents
+            .iter()
+            .map(|x| x.duration.map(format_duration).unwrap_or_default())
+            .collect::<Vec<_>>();
+
+        let index_width = (self.currents.len() - 1).to_string().len();
+        let channel_width = channels
+            .iter()
+            .map(|x| x.chars().count())
+            .max()
+            .unwrap_or(0)
+            .min(LIST_CHANNEL_WIDTH);
+        let duration_width = durations.iter().map(String::len).max().unwrap_or(0);
+        // two spaces between each of the four columns
+        let title_width = width.saturating_sub(index_width + channel_width + duration_width + 6);
+
+        for (i, x) in self.currents.iter().enumerate() {
+            if thumbnails {
+                show_thumbnail(&self.dloader, out, x, LIST_SCALE_FACTOR)?;
+            }
+            let row = format!(
+                "{0:>1$}  {2:3$}  {4:5$}  {6:>7$}",
+                i,
+                index_width,
+                truncate(&label(x), title_width),
+                title_width,
+                truncate(&channels[i], channel_width),
+                channel_width,
+                durations[i],
+                duration_width
             );
+            writeln!(out, "{0}", row.trim_right())?;
+        }
+        Ok(())
+    }
+
+    fn handle_player_events(&mut self, out: &mut Write) -> Result<()> {
+        while let Ok(ev) = self.events.try_recv() {
+            match ev {
+                PlayerEvent::TrackChanged(x) => writeln!(out, "NOW PLAYING: {0}", x.title)?,
+                PlayerEvent::TrackFailed(x) => writeln!(out, "Couldn't play: {0}", x.title)?,
+                PlayerEvent::QueueFinished => writeln!(out, "Reached the end of the queue")?,
+                PlayerEvent::TrackFinished(_) |
+                PlayerEvent::Stopped |
+                PlayerEvent::Paused |
+                PlayerEvent::Resumed => (),
+            }
         }
+        Ok(())
     }
 
-    fn search(&mut self, search: &str) {
-        self.cycle_ctr = 0;
-        self.currents.clear();
-        self.currents.append(&mut self.backend.search(search));
+    fn list_queue(&mut self, out: &mut Write) -> Result<()> {
+        let (entries, pos) = self.player.queue_entries();
+        if entries.is_empty() {
+            writeln!(out, "The queue is empty.")?;
+            return Ok(());
+        }
+        for (i, x) in entries.iter().enumerate() {
+            let marker = if Some(i) == pos { "*" } else { " " };
+            writeln!(out, "{0} {1}: {2}", marker, i, x.track.title)?;
+        }
+        Ok(())
     }
 
-    fn related(&mut self, _: &str) {
-        match self.current {
+    fn now(&mut self, out: &mut Write) -> Result<()> {
+        match self.player.now_playing() {
             Some(ref x) => {
-                self.cycle_ctr = 0;
-                self.currents.clear();
-                self.currents.append(&mut self.backend.find_related_tracks(
-                    x.id.as_str(),
-                ));
+                writeln!(out, "NOW PLAYING: {0}", x.title)?;
+                if let Some(n) = self.library.rating(&x.id)? {
+                    writeln!(out, "Rated {0}", stars_of(n))?;
+                }
+                show_details(out, x)?;
+                show_thumbnail(&self.dloader, out, x, SCALE_FACTOR)?;
             }
-            None => panic!("No current selection"),
+            None => writeln!(out, "Nothing currently playing.")?,
         }
+        Ok(())
     }
 
-    fn download(&mut self) -> Option<String> {
-        match self.current {
-            Some(ref mut x) => {
-                match self.dloader.download_audio_from_yt(
-                    x.id.as_str(),
-                    self.nodl,
-                ) {
-                    Ok(x) => Some(x),
-                    Err(_) => None,
-                }
+    fn show_selected(&self, x: &BackendSearchResult, out: &mut Write) -> Result<()> {
+        writeln!(out, "SELECTED: {0}", label(x))?;
+        show_details(out, x)?;
+        show_thumbnail(&self.dloader, out, x, SCALE_FACTOR)
+    }
+
+    /// The result at `sel`. It stays in the results until `picked`, so a
+    /// failed fetch or download loses nothing.
+    fn select(&self, sel: usize) -> Result<BackendSearchResult> {
+        match self.currents.get(sel) {
+            Some(x) => Ok(x.clone()),
+            None => Err(SurgeError::Usage(
+                String::from("Please pick a valid selection"),
+            )),
+        }
+    }
+
+    /// Drop a result that was played or queued from the results.
+    fn picked(&mut self, sel: usize) {
+        if sel < self.currents.len() {
+            self.currents.remove(sel);
+        }
+    }
+
+    /// Play a search result immediately, or just resume playback without one.
+    pub fn play(&mut self, sel: Option<usize>) -> Result<Option<BackendSearchResult>> {
+        let track = match sel {
+            Some(sel) => {
+                let x = self.select(sel)?;
+                let x = self.play_now(x)?;
+                self.picked(sel);
+                Some(x)
             }
             None => None,
+        };
+        self.player.resume()?;
+        Ok(track)
+    }
+
+    pub fn queue(&mut self, sel: usize) -> Result<BackendSearchResult> {
+        let x = self.select(sel)?;
+        let x = self.queue_later(x)?;
+        self.picked(sel);
+        Ok(x)
+    }
+
+    /// Play a YouTube link or video id, a stream or a local file without
+    /// searching for it first.
+    pub fn play_url(&mut self, target: &str) -> Result<BackendSearchResult> {
+        let x = self.resolve(target)?;
+        let x = self.play_now(x)?;
+        self.player.resume()?;
+        Ok(x)
+    }
+
+    pub fn queue_url(&mut self, target: &str) -> Result<BackendSearchResult> {
+        let x = self.resolve(target)?;
+        self.queue_later(x)
+    }
+
+    /// Play `x` right away; a playlist or channel plays its first track and
+    /// queues the rest.
+    fn play_now(&mut self, x: BackendSearchResult) -> Result<BackendSearchResult> {
+        self.choose(&x);
+        let mut tracks = self.tracks_of(&x)?;
+        let first = tracks.remove(0);
+        let dl = self.download(&first)?;
+        self.player.queue_and_play(first, dl)?;
+        for track in tracks {
+            self.queue_track(track)?;
+        }
+        Ok(x)
+    }
+
+    /// Queue `x`, or every track of a playlist or channel.
+    pub fn queue_later(&mut self, x: BackendSearchResult) -> Result<BackendSearchResult> {
+        self.choose(&x);
+        for track in self.tracks_of(&x)? {
+            self.queue_track(track)?;
+        }
+        Ok(x)
+    }
+
+    /// Remember `x` for `related`, which only makes sense for tracks.
+    fn choose(&mut self, x: &BackendSearchResult) {
+        if x.kind == ResultKind::Track {
+            self.current = Some(x.clone());
+        }
+    }
+
+    /// A result for `target`, with whatever details the library or the
+    /// backends know of it, so `now` and `related` work as if it had been
+    /// searched for.
+    fn resolve(&self, target: &str) -> Result<BackendSearchResult> {
+        let (id, source) = match link::parse(target) {
+            Some(Link::Video(id)) => (id, BackendType::Youtube),
+            Some(Link::File(path)) => (path.to_string_lossy().into_owned(), BackendType::Local),
+            Some(Link::Playlist(id)) => {
+                return Ok(bare_result(&id, BackendType::Youtube, ResultKind::Playlist))
+            }
+            Some(Link::Stream(url)) => {
+                return Ok(bare_result(&url, BackendType::Stream, ResultKind::Track))
+            }
+            None => {
+                return Err(SurgeError::Usage(format!(
+                    "'{0}' isn't a URL, video id or file",
+                    target
+                )))
+            }
+        };
+        if let Some(x) = self.library.track(&id)? {
+            return Ok(x);
+        }
+        match self.backend.lookup(&id) {
+            Ok(x) => Ok(x),
+            Err(_) => Ok(bare_result(&id, source, ResultKind::Track)),
+        }
+    }
+
+    /// The tracks to queue for `x`: itself, or those of a playlist or
+    /// channel, up to `COLLECTION_MAX_TRACKS`. Never empty.
+    fn tracks_of(&self, x: &BackendSearchResult) -> Result<Vec<BackendSearchResult>> {
+        if x.kind == ResultKind::Track {
+            return Ok(vec![x.clone()]);
+        }
+        let tracks = self.backend.collection_tracks(&x.id, x.kind, COLLECTION_MAX_TRACKS)?;
+        if tracks.is_empty() {
+            return Err(SurgeError::Usage(
+                format!("The {0} '{1}' has no tracks", x.kind, x.title),
+            ));
+        }
+        Ok(tracks)
+    }
+
+    /// Queue a track that didn't necessarily come from the current results,
+    /// e.g. one a remote client picked by id.
+    pub fn queue_track(&mut self, track: BackendSearchResult) -> Result<()> {
+        let dl = self.download(&track)?;
+        self.player.queue(track, dl)
+    }
+
+    /// Look a track up by id among the current results, then in the
+    /// library, by `id` or by what it links to, falling back to a bare
+    /// entry from `link_result`.
+    pub fn find_track(&self, id: &str) -> BackendSearchResult {
+        let found = self.currents
+            .iter()
+            .chain(self.current.iter())
+            .find(|x| x.id == id)
+            .cloned();
+        if let Some(x) = found {
+            return x;
+        }
+        if let Ok(Some(x)) = self.library.track(id) {
+            return x;
+        }
+        let linked = link_result(id);
+        match self.library.track(&linked.id) {
+            Ok(Some(x)) => x,
+            _ => linked,
+        }
+    }
+
+    pub fn search(&mut self, search: &str) -> Result<&[BackendSearchResult]> {
+        let query = search.parse::<SearchQuery>()?;
+        let outcome = self.backend.search_outcome(&query, None)?;
+        self.listing = Some(Listing::Search(query));
+        Ok(self.set_results(outcome, false))
+    }
+
+    pub fn related(&mut self) -> Result<&[BackendSearchResult]> {
+        let id = match self.current {
+            Some(ref x) => x.id.clone(),
+            None => {
+                return Err(SurgeError::Usage(
+                    String::from("Nothing selected to find related tracks for"),
+                ))
+            }
+        };
+        let outcome = self.backend.related_outcome(&id, None)?;
+        self.listing = Some(Listing::Related(id));
+        Ok(self.set_results(outcome, false))
+    }
+
+    /// Search for playlists or channels instead of tracks.
+    pub fn search_collections(
+        &mut self,
+        search: &str,
+        kind: ResultKind,
+    ) -> Result<&[BackendSearchResult]> {
+        let query = search.parse::<SearchQuery>()?;
+        let outcome = self.backend.collections_outcome(&query, kind, None)?;
+        self.listing = Some(Listing::Collections(query, kind));
+        Ok(self.set_results(outcome, false))
+    }
+
+    /// List the tracks of a playlist, or the uploads of a channel, from the
+    /// current results.
+    pub fn open(&mut self, sel: usize) -> Result<&[BackendSearchResult]> {
+        let x = match self.currents.get(sel) {
+            Some(x) => x.clone(),
+            None => {
+                return Err(SurgeError::Usage(
+                    String::from("Please pick a valid selection"),
+                ))
+            }
+        };
+        if x.kind == ResultKind::Track {
+            return Err(SurgeError::Usage(
+                String::from("Only playlists and channels can be opened"),
+            ));
+        }
+        let outcome = self.backend.collection_outcome(&x.id, x.kind, None)?;
+        self.listing = Some(Listing::Collection(x.id, x.kind));
+        Ok(self.set_results(outcome, false))
+    }
+
+    /// Add the next page of results to the current ones.
+    pub fn more(&mut self) -> Result<&[BackendSearchResult]> {
+        let page = self.next_page.clone();
+        self.turn_page(page, "No more results", true)
+    }
+
+    pub fn next_page(&mut self) -> Result<&[BackendSearchResult]> {
+        let page = self.next_page.clone();
+        self.turn_page(page, "No more results", false)
+    }
+
+    pub fn prev_page(&mut self) -> Result<&[BackendSearchResult]> {
+        let page = self.prev_page.clone();
+        self.turn_page(page, "Already at the first page", false)
+    }
+
+    /// Fetch `page` of whatever the last `search` or `related` listed.
+    fn turn_page(
+        &mut self,
+        page: Option<String>,
+        none_left: &str,
+        append: bool,
+    ) -> Result<&[BackendSearchResult]> {
+        let listing = match self.listing {
+            Some(ref x) => x.clone(),
+            None => {
+                return Err(SurgeError::Usage(
+                    String::from("Nothing to page through. Try 'search'"),
+                ))
+            }
+        };
+        let page = match page {
+            Some(x) => x,
+            None => return Err(SurgeError::Usage(String::from(none_left))),
+        };
+        let outcome = match listing {
+            Listing::Search(ref x) => self.backend.search_outcome(x, Some(&page))?,
+            Listing::Related(ref x) => self.backend.related_outcome(x, Some(&page))?,
+            Listing::Collections(ref x, kind) => {
+                self.backend.collections_outcome(x, kind, Some(&page))?
+            }
+            Listing::Collection(ref x, kind) => {
+                self.backend.collection_outcome(x, kind, Some(&page))?
+            }
+        };
+        Ok(self.set_results(outcome, append))
+    }
+
+    /// Replace the current results with `outcome`, or with `append`, add
+    /// the ones not already there and start cycling from the first of them.
+    fn set_results(&mut self, mut outcome: SearchOutcome, append: bool) -> &[BackendSearchResult] {
+        self.backend_failures = outcome
+            .failures
+            .iter()
+            .map(|&(btype, ref e)| format!("Skipped {0}: {1}", btype, e))
+            .collect();
+        if append {
+            let currents = &self.currents;
+            outcome.results.retain(
+                |x| !currents.iter().any(|current| current.id == x.id),
+            );
+        } else {
+            self.currents.clear();
+            self.prev_page = outcome.prev;
+        }
+        // the cache only saves asking the backends again, so results are
+        // still worth showing if it can't be written
+        let _ = self.library.cache_tracks(&outcome.results);
+        self.cycle_ctr = self.currents.len();
+        self.next_page = outcome.next;
+        self.currents.append(&mut outcome.results);
+        &self.currents
+    }
+
+    /// Say which backends a search had to do without, so it's clear why
+    /// results came from elsewhere.
+    fn report_failures(&self, out: &mut Write) -> Result<()> {
+        for x in &self.backend_failures {
+            writeln!(out, "{0}", x)?;
+        }
+        Ok(())
+    }
+
+    /// Toggle radio mode, or start it by playing a search result. Returns
+    /// whether it's on, and what it queued to begin with.
+    pub fn radio(&mut self, sel: Option<usize>) -> Result<(bool, Vec<BackendSearchResult>)> {
+        match sel {
+            Some(sel) => {
+                let x = self.select(sel)?;
+                let x = self.play_now(x)?;
+                self.picked(sel);
+                self.player.resume()?;
+                self.radio.remember(&x);
+            }
+            None if self.radio.on() => {
+                self.radio.set(false);
+                return Ok((false, vec![]));
+            }
+            None => {
+                if self.player.now_playing().is_none() && self.current.is_none() {
+                    return Err(SurgeError::Usage(String::from(
+                        "Nothing to start the radio from. Play something or pick a selection",
+                    )));
+                }
+            }
+        }
+        self.radio.set(true);
+        Ok((true, self.top_up_radio()?))
+    }
+
+    /// With radio mode on, queue tracks related to the end of the queue
+    /// until there are enough after the one playing. Returns what it queued.
+    pub fn top_up_radio(&mut self) -> Result<Vec<BackendSearchResult>> {
+        let mut top_up = match self.radio_top_up() {
+            Some(x) => x,
+            None => return Ok(vec![]),
+        };
+        let mut tracks = vec![];
+        for x in top_up.pick()? {
+            let location = self.download(&x)?;
+            tracks.push((x, location, false));
+        }
+        self.finish_top_up(tracks)
+    }
+
+    /// What it takes to top the queue up in radio mode, if it needs it.
+    pub fn radio_top_up(&self) -> Option<TopUp> {
+        let needed = self.radio_needed();
+        if needed == 0 {
+            return None;
+        }
+        let entries = self.player.queue_entries().0;
+        let seed = match entries.last() {
+            Some(x) => x.track.clone(),
+            None => {
+                match self.current {
+                    Some(ref x) => x.clone(),
+                    None => return None,
+                }
+            }
+        };
+        Some(TopUp::new(
+            self.radio.clone(),
+            self.backend.clone(),
+            self.dloader.clone(),
+            seed,
+            entries.iter().map(|x| x.track.id.clone()).collect(),
+            needed,
+        ))
+    }
+
+    /// Queue the tracks a top-up found, at their locations, recording
+    /// those it downloaded. The queue may have changed in the meantime, so
+    /// only as many are queued as are still needed. Returns what it queued.
+    pub fn finish_top_up(
+        &mut self,
+        tracks: Vec<(BackendSearchResult, String, bool)>,
+    ) -> Result<Vec<BackendSearchResult>> {
+        let needed = self.radio_needed();
+        let queued_ids = self.player
+            .queue_entries()
+            .0
+            .into_iter()
+            .map(|x| x.track.id)
+            .collect::<Vec<_>>();
+        let mut queued = vec![];
+        for (x, location, downloaded) in tracks {
+            if queued.len() >= needed || queued_ids.contains(&x.id) {
+                continue;
+            }
+            if downloaded {
+                self.library.record_download(&x, &location)?;
+            }
+            self.player.queue(x.clone(), location)?;
+            self.radio.remember(&x);
+            queued.push(x);
+        }
+        Ok(queued)
+    }
+
+    /// How many tracks radio mode wants queued on top of what's there.
+    fn radio_needed(&self) -> usize {
+        if !self.radio.on() {
+            return 0;
+        }
+        let (entries, pos) = self.player.queue_entries();
+        let ahead = match pos {
+            Some(pos) => entries.len() - pos - 1,
+            None => 0,
+        };
+        self.radio.depth().saturating_sub(ahead)
+    }
+
+    /// Save the queue as `name`. Returns how many tracks it had.
+    pub fn playlist_save(&mut self, name: &str) -> Result<usize> {
+        let tracks = self.player
+            .queue_entries()
+            .0
+            .into_iter()
+            .map(|x| x.track)
+            .collect::<Vec<_>>();
+        if tracks.is_empty() {
+            return Err(SurgeError::Usage(
+                String::from("The queue is empty, so there's nothing to save"),
+            ));
+        }
+        self.library.save_playlist(name, &tracks)?;
+        Ok(tracks.len())
+    }
+
+    /// Queue every track of `name`. Returns how many there were.
+    pub fn playlist_load(&mut self, name: &str) -> Result<usize> {
+        let tracks = self.library.playlist(name)?;
+        let n = tracks.len();
+        for track in tracks {
+            self.queue_track(track)?;
+        }
+        Ok(n)
+    }
+
+    /// Every playlist with its number of tracks.
+    pub fn playlist_list(&self) -> Result<Vec<(String, usize)>> {
+        self.library.playlists()
+    }
+
+    pub fn playlist_delete(&mut self, name: &str) -> Result<()> {
+        if !self.library.delete_playlist(name)? {
+            return Err(SurgeError::Usage(format!("No playlist named '{0}'", name)));
+        }
+        Ok(())
+    }
+
+    /// Add a search result to `name`, or every track of a playlist or
+    /// channel result. Returns what was added.
+    pub fn playlist_add(&mut self, name: &str, sel: usize) -> Result<Vec<BackendSearchResult>> {
+        let x = match self.currents.get(sel) {
+            Some(x) => x.clone(),
+            None => {
+                return Err(SurgeError::Usage(
+                    String::from("Please pick a valid selection"),
+                ))
+            }
+        };
+        let added = self.tracks_of(&x)?;
+        let mut tracks = if self.library.playlist_exists(name)? {
+            self.library.playlist(name)?
+        } else {
+            vec![]
+        };
+        tracks.extend(added.iter().cloned());
+        self.library.save_playlist(name, &tracks)?;
+        Ok(added)
+    }
+
+    pub fn playlist_remove(&mut self, name: &str, pos: usize) -> Result<BackendSearchResult> {
+        let mut tracks = self.library.playlist(name)?;
+        if pos >= tracks.len() {
+            return Err(SurgeError::Usage(
+                String::from("Please pick a valid playlist position"),
+            ));
+        }
+        let x = tracks.remove(pos);
+        self.library.save_playlist(name, &tracks)?;
+        Ok(x)
+    }
+
+    pub fn playlist_tracks(&self, name: &str) -> Result<Vec<BackendSearchResult>> {
+        self.library.playlist(name)
+    }
+
+    /// Save the playlist file `file` as `name`. Returns how many tracks it
+    /// had, and how many entries couldn't be played.
+    pub fn playlist_import(&mut self, name: &str, file: &str) -> Result<(usize, usize)> {
+        let (tracks, skipped) = playlist_file::read(Path::new(file))?;
+        self.library.save_playlist(name, &tracks)?;
+        Ok((tracks.len(), skipped))
+    }
+
+    pub fn playlist_export(&mut self, name: &str, file: &str) -> Result<usize> {
+        let tracks = self.library.playlist(name)?;
+        playlist_file::write(Path::new(file), &tracks)?;
+        Ok(tracks.len())
+    }
+
+    /// Queue every track of the playlist file `file`.
+    pub fn queue_import(&mut self, file: &str) -> Result<(usize, usize)> {
+        let (tracks, skipped) = playlist_file::read(Path::new(file))?;
+        let n = tracks.len();
+        for track in tracks {
+            self.queue_track(track)?;
+        }
+        Ok((n, skipped))
+    }
+
+    pub fn queue_export(&mut self, file: &str) -> Result<usize> {
+        let tracks = self.player
+            .queue_entries()
+            .0
+            .into_iter()
+            .map(|x| x.track)
+            .collect::<Vec<_>>();
+        playlist_file::write(Path::new(file), &tracks)?;
+        Ok(tracks.len())
+    }
+
+    /// Log a play that just ended, and keep the radio from picking it again
+    /// for a while.
+    pub fn record_play(&mut self, play: &Play) -> Result<()> {
+        self.radio.remember(&play.track);
+        self.library.record_play(play)
+    }
+
+    /// Every play, newest first.
+    pub fn history(&self, limit: usize) -> Result<Vec<Play>> {
+        self.library.plays(0, limit)
+    }
+
+    /// Up to `limit` plays whose title or artist contains `query`, ignoring
+    /// case, with their positions in `history`.
+    pub fn history_search(&self, query: &str, limit: usize) -> Result<Vec<(usize, Play)>> {
+        self.library.search_plays(query, limit)
+    }
+
+    /// Play the track at `pos` in `history` again.
+    pub fn history_replay(&mut self, pos: usize) -> Result<BackendSearchResult> {
+        let play = match self.library.plays(pos, 1)?.pop() {
+            Some(x) => x,
+            None => {
+                return Err(SurgeError::Usage(
+                    String::from("Please pick a valid history position"),
+                ))
+            }
+        };
+        let x = self.play_now(play.track)?;
+        self.player.resume()?;
+        Ok(x)
+    }
+
+    /// Make a search result, or what's playing, a favorite. Returns false
+    /// as well if it already was one.
+    pub fn fav(&mut self, sel: Option<usize>) -> Result<(BackendSearchResult, bool)> {
+        let x = self.track_for(sel)?;
+        let added = self.library.set_favorite(&x, true)?;
+        Ok((x, added))
+    }
+
+    pub fn unfav(&mut self, sel: Option<usize>) -> Result<BackendSearchResult> {
+        let x = self.track_for(sel)?;
+        if !self.library.set_favorite(&x, false)? {
+            return Err(SurgeError::Usage(format!("'{0}' isn't a favorite", x.title)));
+        }
+        Ok(x)
+    }
+
+    pub fn rate(&mut self, stars: usize, sel: Option<usize>) -> Result<BackendSearchResult> {
+        let x = self.track_for(sel)?;
+        self.library.rate(&x, stars)?;
+        Ok(x)
+    }
+
+    /// Make the favorites the current results, so they can be played and
+    /// queued by selection.
+    pub fn favs(&mut self) -> Result<Vec<Favorite>> {
+        let favs = self.library.favorites()?;
+        self.listing = None;
+        self.set_results(
+            SearchOutcome {
+                results: favs.iter().map(|x| x.track.clone()).collect(),
+                next: None,
+                prev: None,
+                failures: vec![],
+            },
+            false,
+        );
+        Ok(favs)
+    }
+
+    /// Queue the library tracks matching `rules`, or the rules saved as the
+    /// smart playlist of that name. Returns what was queued.
+    pub fn smart(&mut self, rules: &str) -> Result<Vec<BackendSearchResult>> {
+        let rules = match self.library.smart_playlist(rules.trim())? {
+            Some(x) => x,
+            None => String::from(rules),
+        };
+        let tracks = self.library.smart(&rules.parse::<SmartQuery>()?)?;
+        if tracks.is_empty() {
+            return Err(SurgeError::Usage(
+                format!("No tracks in the library match '{0}'", rules),
+            ));
+        }
+        for track in &tracks {
+            self.queue_track(track.clone())?;
         }
+        Ok(tracks)
     }
 
-    pub fn stop(&mut self) {
-        self.player.stop();
+    /// Save `rules` as the smart playlist `name`, once they're known to
+    /// parse.
+    pub fn smart_save(&mut self, name: &str, rules: &str) -> Result<()> {
+        rules.parse::<SmartQuery>()?;
+        self.library.save_smart_playlist(name, rules.trim())
+    }
+
+    /// Every smart playlist with its rules.
+    pub fn smart_list(&self) -> Result<Vec<(String, String)>> {
+        self.library.smart_playlists()
+    }
+
+    pub fn smart_delete(&mut self, name: &str) -> Result<()> {
+        if !self.library.delete_smart_playlist(name)? {
+            return Err(SurgeError::Usage(format!("No smart playlist named '{0}'", name)));
+        }
+        Ok(())
+    }
+
+    /// The search result at `sel`, or without one, the track playing or
+    /// last picked.
+    fn track_for(&self, sel: Option<usize>) -> Result<BackendSearchResult> {
+        let x = match sel {
+            Some(sel) => self.currents.get(sel).cloned(),
+            None => self.player.now_playing().or_else(|| self.current.clone()),
+        };
+        match x {
+            Some(ref x) if x.kind != ResultKind::Track => Err(SurgeError::Usage(format!(
+                "Only tracks can be favorites or rated, and '{0}' is a {1}",
+                x.title,
+                x.kind
+            ))),
+            Some(x) => Ok(x),
+            None if sel.is_some() => Err(SurgeError::Usage(
+                String::from("Please pick a valid selection"),
+            )),
+            None => Err(SurgeError::Usage(
+                String::from("Nothing playing or selected. Pick a selection"),
+            )),
+        }
+    }
+
+    pub fn pause(&mut self) -> Result<()> {
+        self.player.pause()
+    }
+
+    pub fn toggle_loop(&mut self) -> Result<bool> {
+        self.player.loop_()
+    }
+
+    pub fn now_playing(&self) -> Option<BackendSearchResult> {
+        self.player.now_playing()
+    }
+
+    /// Seconds left in the current track, if anything is playing.
+    pub fn time_remain(&self) -> Option<i64> {
+        self.player.time_remain().ok()
+    }
+
+    pub fn player(&mut self) -> &mut AudioPlayer {
+        &mut self.player
+    }
+
+    pub fn subscribe(&self) -> Receiver<PlayerEvent> {
+        self.player.subscribe()
+    }
+
+    /// Path to the thumbnail of `x`, if it's been fetched already.
+    pub fn cached_thumbnail(&self, x: &BackendSearchResult) -> Option<PathBuf> {
+        self.dloader.cached_thumbnail(
+            x.thumbnail.as_ref().map(String::as_str),
+            &x.id,
+        )
+    }
+
+    /// For downloads that shouldn't hold the `CommandCenter` lock.
+    pub fn downloader(&self) -> Arc<Downloader> {
+        self.dloader.clone()
+    }
+
+    /// What to hand mpv for `x`: local files and streams play as they are.
+    /// Where `x` plays from if that's known without downloading it, see
+    /// `Downloader::known_location`.
+    pub fn known_location(&self, x: &BackendSearchResult) -> Result<Option<String>> {
+        match x.source {
+            BackendType::Local |
+            BackendType::Stream => Ok(Some(x.id.clone())),
+            BackendType::Youtube |
+            BackendType::Ytdl => self.dloader.known_location(&self.library, x, self.nodl),
+        }
+    }
+
+    fn download(&mut self, x: &BackendSearchResult) -> Result<String> {
+        match x.source {
+            BackendType::Local |
+            BackendType::Stream => Ok(x.id.clone()),
+            BackendType::Youtube |
+            BackendType::Ytdl => {
+                self.dloader.download_audio_from_yt(&mut self.library, x, self.nodl)
+            }
+        }
+    }
+
+    pub fn stop(&mut self) -> Result<()> {
+        self.player.stop()
+    }
+
+    /// Stop playback without clearing the queue.
+    pub fn halt(&mut self) -> Result<()> {
+        self.player.halt()
+    }
+}
+
+/// A bare result for whatever `id` links to, see `link::parse`. Anything
+/// that isn't a link is taken for a YouTube id.
+pub fn link_result(id: &str) -> BackendSearchResult {
+    match link::parse(id) {
+        Some(Link::Video(x)) => bare_result(&x, BackendType::Youtube, ResultKind::Track),
+        Some(Link::Playlist(x)) => bare_result(&x, BackendType::Youtube, ResultKind::Playlist),
+        Some(Link::Stream(url)) => bare_result(&url, BackendType::Stream, ResultKind::Track),
+        Some(Link::File(path)) => {
+            bare_result(&path.to_string_lossy(), BackendType::Local, ResultKind::Track)
+        }
+        None => bare_result(id, BackendType::Youtube, ResultKind::Track),
+    }
+}
+
+/// A result that only knows its id, which doubles as its title.
+fn bare_result(id: &str, source: BackendType, kind: ResultKind) -> BackendSearchResult {
+    BackendSearchResult {
+        kind: kind,
+        ..BackendSearchResult::new(String::from(id), String::from(id), source)
+    }
+}
+
+fn show_play(out: &mut Write, pos: usize, play: &Play) -> Result<()> {
+    writeln!(
+        out,
+        "{0}: {1} ({2}, {3} listened, {4})",
+        pos,
+        play.track.title,
+        format_ago(history::unix_now().saturating_sub(play.started)),
+        format_duration(play.listened),
+        play.outcome
+    )?;
+    Ok(())
+}
+
+/// `3` as `★★★☆☆`.
+fn stars_of(n: u8) -> String {
+    (0..library::MAX_RATING)
+        .map(|i| if i < n { '★' } else { '☆' })
+        .collect()
+}
+
+fn format_ago(secs: u64) -> String {
+    if secs < 60 {
+        String::from("just now")
+    } else if secs < 3600 {
+        format!("{0} min ago", secs / 60)
+    } else if secs < 86400 {
+        format!("{0} h ago", secs / 3600)
+    } else {
+        format!("{0} days ago", secs / 86400)
+    }
+}
+
+fn show_skipped(out: &mut Write, skipped: usize) -> Result<()> {
+    if skipped > 0 {
+        writeln!(
+            out,
+            "Skipped {0} entries that aren't tracks surge can play",
+            skipped
+        )?;
+    }
+    Ok(())
+}
+
+/// The title of `x`, saying so when it's a playlist or channel.
+fn label(x: &BackendSearchResult) -> String {
+    match x.kind {
+        ResultKind::Track => x.title.clone(),
+        kind => format!("{0} ({1})", x.title, kind),
+    }
+}
+
+/// Whatever the backend knew about `x` besides its title, e.g.
+/// `Some Channel | 3:45 | 2017-06-01 | 1,234,567 views`, and the first line
+/// of its description.
+fn show_details(out: &mut Write, x: &BackendSearchResult) -> Result<()> {
+    let mut details = vec![];
+    if let Some(ref artist) = x.artist {
+        details.push(artist.clone());
+    }
+    if let Some(secs) = x.duration {
+        details.push(format_duration(secs));
+    }
+    if let Some(ref published) = x.published {
+        details.push(published.clone());
+    }
+    if let Some(views) = x.views {
+        details.push(format!("{0} views", group_digits(views)));
+    }
+    if !details.is_empty() {
+        writeln!(out, "    {0}", details.join(" | "))?;
+    }
+
+    let description = x.description.as_ref().and_then(|x| {
+        x.lines().map(str::trim).find(|x| !x.is_empty())
+    });
+    if let Some(line) = description {
+        writeln!(out, "    {0}", truncate(line, DESCRIPTION_WIDTH))?;
+    }
+    Ok(())
+}
+
+/// `x` cut to `width` characters, marking the cut with an ellipsis.
+fn truncate(x: &str, width: usize) -> String {
+    if x.chars().count() <= width {
+        return String::from(x);
+    }
+    if width < 3 {
+        return x.chars().take(width).collect();
+    }
+    let mut ret = x.chars().take(width - 3).collect::<String>();
+    ret.push_str("...");
+    ret
+}
+
+/// `3:07`, or `1:02:03` past the hour.
+fn format_duration(secs: u64) -> String {
+    if secs >= 3600 {
+        format!("{0}:{1:02}:{2:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{0}:{1:02}", secs / 60, secs % 60)
+    }
+}
+
+/// `1234567` as `1,234,567`.
+fn group_digits(n: u64) -> String {
+    let digits = n.to_string();
+    let mut ret = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            ret.push(',');
+        }
+        ret.push(c);
+    }
+    ret
+}
+
+/// Thumbnails are a nicety, so failing to fetch or draw one is reported
+/// without failing the command that wanted it. `scale` is relative to the
+/// size of the terminal.
+fn show_thumbnail(
+    dloader: &Downloader,
+    out: &mut Write,
+    x: &BackendSearchResult,
+    scale: f32,
+) -> Result<()> {
+    let res = dloader
+        .download_thumbnail(x.thumbnail.as_ref().map(String::as_str), &x.id)
+        .and_then(|path| display_png(path, out, scale));
+    if let Err(e) = res {
+        writeln!(out, "{0}", e)?;
     }
+    Ok(())
 }
 
-fn display_png(path: Option<PathBuf>, out: &mut StdoutLock) {
+fn display_png(path: Option<PathBuf>, mut out: &mut Write, scale: f32) -> Result<()> {
     let path_ = match path {
-        Some(x) => x.clone(),
-        None => return,
+        Some(x) => x,
+        None => return Ok(()),
     };
     let tup = &(String::new(), path_);
-    let format = ops::guess_format(tup).expect("Couldn't guess format of downloaded thumbnail");
-    let img = ops::load_image(tup, format).expect("Couldn't load downloaded thumbnail");
+    let format = ops::guess_format(tup).map_err(|_| {
+        SurgeError::Thumbnail(String::from(
+            "Couldn't guess format of downloaded thumbnail",
+        ))
+    })?;
+    let img = ops::load_image(tup, format).map_err(|_| {
+        SurgeError::Thumbnail(String::from("Couldn't load downloaded thumbnail"))
+    })?;
 
     if let Some((w, h)) = dimensions() {
         let (w, h) = (w as u32, h as u32);
         let img_s = ops::image_resized_size(img.dimensions(), (w, h), true);
         let (w, h) = (
-            (SCALE_FACTOR * img_s.0 as f32) as u32,
-            (SCALE_FACTOR * img_s.1 as f32) as u32,
+            (scale * img_s.0 as f32) as u32,
+            (scale * img_s.1 as f32) as u32,
         );
         let resized = ops::resize_image(&img, (w, h));
-        ops::write_ansi_truecolor(out, &resized);
-        writeln!(out, "\x1b[0m").expect("Couldn't write to stdout");
+        ops::write_ansi_truecolor(&mut out, &resized);
+        writeln!(out, "\x1b[0m")?;
     }
+    Ok(())
 }
@@ -0,0 +1,152 @@
+use command::CommandCenter;
+use error::{Result, SurgeError};
+use rpc;
+
+use serde_json::{self, Value};
+
+use std::env;
+use std::fs::remove_file;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const SOCKET_NAME: &'static str = "surge.sock";
+
+/// Written on a line of its own after the output of every command, so the
+/// client knows when to show the prompt again.
+const REPLY_END: &'static str = "\u{4}";
+
+pub fn socket_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(x) => {
+            let mut path = PathBuf::from(x);
+            path.push(SOCKET_NAME);
+            path
+        }
+        None => {
+            let mut path = env::temp_dir();
+            path.push(format!(
+                "surge-{0}.sock",
+                env::var("USER").unwrap_or_default()
+            ));
+            path
+        }
+    }
+}
+
+/// Serve every client that connects to `path`, one thread per connection,
+/// all sharing the same `CommandCenter`.
+pub fn serve(path: &Path, cmd: Arc<Mutex<CommandCenter>>) -> Result<()> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(SurgeError::Daemon(format!(
+                "Another surge daemon is listening on {0}",
+                path.display()
+            )));
+        }
+        // left behind by a daemon that didn't shut down cleanly
+        remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    println!("surge daemon listening on {0}", path.display());
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(x) => x,
+            Err(e) => {
+                println!("Couldn't accept client: {0}", e);
+                continue;
+            }
+        };
+        let cmd = cmd.clone();
+        thread::spawn(move || if let Err(e) = handle_client(stream, &cmd) {
+            println!("Client disconnected: {0}", e);
+        });
+    }
+    Ok(())
+}
+
+fn handle_client(stream: UnixStream, cmd: &Arc<Mutex<CommandCenter>>) -> Result<()> {
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let mut subscribed = false;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if rpc::is_request(&line) {
+            let resp = rpc::handle(&line, |req| if req.method == "subscribe" {
+                if !subscribed {
+                    forward_events(cmd, writer.clone());
+                    subscribed = true;
+                }
+                Ok(Value::Bool(true))
+            } else {
+                rpc::call(&mut cmd.lock().expect("CommandCenter poisoned"), req)
+            });
+            if let Some(x) = resp {
+                write_json(&writer, &x)?;
+            }
+            continue;
+        }
+
+        let mut reply = vec![];
+        cmd.lock()
+            .expect("CommandCenter poisoned")
+            .handle_command(&line, &mut reply);
+        let mut writer = writer.lock().expect("Client writer poisoned");
+        writer.write_all(&reply)?;
+        writeln!(writer, "{0}", REPLY_END)?;
+    }
+    Ok(())
+}
+
+fn write_json(writer: &Mutex<UnixStream>, v: &Value) -> Result<()> {
+    let mut writer = writer.lock().expect("Client writer poisoned");
+    serde_json::to_writer(&mut *writer, v)?;
+    writeln!(writer, "")?;
+    Ok(())
+}
+
+/// Push player events to a JSON-RPC client as notifications until it hangs
+/// up.
+fn forward_events(cmd: &Mutex<CommandCenter>, writer: Arc<Mutex<UnixStream>>) {
+    let events = cmd.lock().expect("CommandCenter poisoned").subscribe();
+    thread::spawn(move || for ev in events.iter() {
+        if write_json(&writer, &rpc::notification(&ev)).is_err() {
+            break;
+        }
+    });
+}
+
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Client {
+    pub fn connect(path: &Path) -> Result<Client> {
+        let stream = UnixStream::connect(path)?;
+        Ok(Client {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream),
+        })
+    }
+
+    /// Send one command to the daemon and copy its reply to `out`.
+    pub fn send(&mut self, command: &str, out: &mut Write) -> Result<()> {
+        writeln!(self.writer, "{0}", command)?;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(SurgeError::Daemon(
+                    String::from("The surge daemon closed the connection"),
+                ));
+            }
+            if line.trim_right_matches('\n') == REPLY_END {
+                return Ok(());
+            }
+            out.write_all(line.as_bytes())?;
+        }
+    }
+}
@@ -1,9 +1,13 @@
 use regex::Regex;
 
+use backend::BackendSearchResult;
+use error::{Result, SurgeError};
+use library::Library;
+
 use std::process::Command;
 use std::path::PathBuf;
-use std::fs::File;
-use std::io::{Error, copy};
+use std::fs::{File, remove_file};
+use std::io::copy;
 
 use hyper::Client;
 use hyper::net::HttpsConnector;
@@ -13,34 +17,75 @@ pub struct Downloader {
     client: Client,
     music_dir: PathBuf,
     thumbnail_dir: PathBuf,
+    /// youtube-dl or yt-dlp, see `ytdl::program`
+    ytdl_program: String,
 }
 
 impl Downloader {
-    pub fn new(music_dir: PathBuf, thumbnail_dir: PathBuf) -> Downloader {
-        let ssl = NativeTlsClient::new().expect("Couldn't make TLS client");
+    pub fn new(
+        music_dir: PathBuf,
+        thumbnail_dir: PathBuf,
+        ytdl_program: String,
+    ) -> Result<Downloader> {
+        let ssl = NativeTlsClient::new().map_err(|e| {
+            SurgeError::Download(format!("Couldn't make TLS client: {0}", e))
+        })?;
         let connector = HttpsConnector::new(ssl);
         let client = Client::with_connector(connector);
 
-        Downloader {
+        Ok(Downloader {
             client,
             music_dir,
             thumbnail_dir,
-        }
+            ytdl_program,
+        })
     }
 
-    pub fn download_audio_from_yt(&self, id: &str, nodl: bool) -> Result<String, Error> {
-        let dl_opt = format!(
-            "{0}/%(title)s.%(ext)s",
-            self.music_dir.to_str().expect(
-                "Coudln't convert music_dir to str",
-            )
-        );
-        let dl_url = format!("https://www.youtube.com/watch?v={0}", id);
+    /// Tracks downloaded before are played from where they were saved, as
+    /// long as the file is still there.
+    pub fn download_audio_from_yt(
+        &self,
+        library: &mut Library,
+        track: &BackendSearchResult,
+        nodl: bool,
+    ) -> Result<String> {
+        if let Some(x) = self.known_location(library, track, nodl)? {
+            return Ok(x);
+        }
+        let path = self.fetch_audio(track)?;
+        library.record_download(track, &path)?;
+        Ok(path)
+    }
 
+    /// Where `track` plays from without downloading anything: its watch URL
+    /// when not downloading, or where it was downloaded to before.
+    pub fn known_location(
+        &self,
+        library: &Library,
+        track: &BackendSearchResult,
+        nodl: bool,
+    ) -> Result<Option<String>> {
         if nodl {
-            return Ok(dl_url);
+            return Ok(Some(watch_url(track)));
         }
-        match Command::new("youtube-dl")
+        Ok(library.download(track)?.map(
+            |x| x.to_string_lossy().into_owned(),
+        ))
+    }
+
+    /// Download the audio of `track`, whether or not it was before. Returns
+    /// where it was saved.
+    pub fn fetch_audio(&self, track: &BackendSearchResult) -> Result<String> {
+        let dl_url = watch_url(track);
+        let dl_opt = match self.music_dir.to_str() {
+            Some(x) => format!("{0}/%(title)s.%(ext)s", x),
+            None => {
+                return Err(SurgeError::Download(
+                    String::from("Couldn't convert music_dir to str"),
+                ))
+            }
+        };
+        let output = Command::new(&self.ytdl_program)
             .args(
                 &[
                     "--extract-audio",
@@ -53,57 +98,82 @@ impl Downloader {
                     &dl_url,
                 ],
             )
-            .output() {
-            Ok(output) => {
-                Ok(get_dl_path_from_ytdl_stdout(
-                    String::from_utf8_lossy(&output.stdout)
-                        .into_owned()
-                        .as_str(),
-                ))
-            }
-            Err(e) => Err(e),
+            .output()
+            .map_err(|e| {
+                SurgeError::Download(format!("Couldn't run {0}: {1}", self.ytdl_program, e))
+            })?;
+        if !output.status.success() {
+            return Err(SurgeError::Download(format!(
+                "{0} exited with {1}: {2}",
+                self.ytdl_program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
         }
+        get_dl_path_from_ytdl_stdout(String::from_utf8_lossy(&output.stdout).as_ref())
     }
 
-    pub fn download_thumbnail(&self, url: Option<&str>, uid: &str) -> Option<PathBuf> {
+    pub fn download_thumbnail(&self, url: Option<&str>, uid: &str) -> Result<Option<PathBuf>> {
         let url = match url {
             Some(x) => x,
+            None => return Ok(None),
+        };
+        let file_path = self.thumbnail_path(url, uid);
+        if file_path.exists() {
+            return Ok(Some(file_path));
+        }
+
+        let mut res = self.client.get(url).send()?;
+        let mut tmp_file = File::create(&file_path)?;
+        if let Err(e) = copy(&mut res, &mut tmp_file) {
+            // don't leave a truncated thumbnail behind to be picked up next time
+            let _ = remove_file(&file_path);
+            return Err(SurgeError::from(e));
+        }
+        Ok(Some(file_path))
+    }
+
+    /// Where the thumbnail at `url` is, if it's been downloaded already.
+    pub fn cached_thumbnail(&self, url: Option<&str>, uid: &str) -> Option<PathBuf> {
+        let file_path = match url {
+            Some(x) => self.thumbnail_path(x, uid),
             None => return None,
         };
+        if file_path.exists() {
+            Some(file_path)
+        } else {
+            None
+        }
+    }
+
+    fn thumbnail_path(&self, url: &str, uid: &str) -> PathBuf {
         let mut file_path = self.thumbnail_dir.clone();
         file_path.push(format!(
             "{0}_{1}",
             uid,
             url.rsplitn(2, '/').collect::<Vec<&str>>()[0]
         ));
-        let file_path_copy = file_path.clone();
-        if file_path.exists() {
-            return Some(file_path_copy);
-        }
-        let mut tmp_file = File::create(file_path).expect("Couldn't make temp file");
-
-        let res = self.client.get(url).send();
-        match res {
-            Ok(mut res) => {
-                match copy(&mut res, &mut tmp_file) {
-                    Ok(_) => (),
-                    Err(_) => return None,
-                }
-            }
-            Err(_) => return None,
-        }
-        Some(file_path_copy)
+        file_path
     }
 }
 
-fn get_dl_path_from_ytdl_stdout(out: &str) -> String {
+fn watch_url(track: &BackendSearchResult) -> String {
+    format!("https://www.youtube.com/watch?v={0}", track.id)
+}
+
+fn get_dl_path_from_ytdl_stdout(out: &str) -> Result<String> {
     lazy_static! {
         static ref RE: Regex = Regex::new("Destination:.*flac\n").expect("Couldn't recreate regex");
     }
-    let cap = RE.captures(out).expect(
-        "Didn't find dl path output from ytdl",
-    );
+    let cap = match RE.captures(out) {
+        Some(x) => x,
+        None => {
+            return Err(SurgeError::Download(
+                String::from("Didn't find dl path output from ytdl"),
+            ))
+        }
+    };
     let mut ret = String::from(&cap[0][13..]);
     ret.pop();
-    ret
+    Ok(ret)
 }
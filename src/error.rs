@@ -0,0 +1,105 @@
+use dbus;
+use hyper;
+use mpv;
+use rusqlite;
+use serde_json;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::result;
+
+#[derive(Debug)]
+pub enum SurgeError {
+    Io(io::Error),
+    Http(hyper::Error),
+    Json(serde_json::Error),
+    Mpv(mpv::Error),
+    Dbus(dbus::Error),
+    Database(rusqlite::Error),
+    /// The backend answered, but not with anything we could use
+    Backend(String),
+    Download(String),
+    Thumbnail(String),
+    Player(String),
+    Daemon(String),
+    /// The command was understood, but its arguments don't make sense
+    /// right now, e.g. a selection past the end of the search results
+    Usage(String),
+}
+
+pub type Result<T> = result::Result<T, SurgeError>;
+
+impl fmt::Display for SurgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SurgeError::Io(ref e) => write!(f, "I/O error: {0}", e),
+            SurgeError::Http(ref e) => write!(f, "HTTP error: {0}", e),
+            SurgeError::Json(ref e) => write!(f, "Couldn't parse JSON: {0}", e),
+            SurgeError::Mpv(ref e) => write!(f, "mpv error: {0}", e),
+            SurgeError::Dbus(ref e) => write!(f, "D-Bus error: {0}", e),
+            SurgeError::Database(ref e) => write!(f, "Library database error: {0}", e),
+            SurgeError::Backend(ref e) => write!(f, "Backend error: {0}", e),
+            SurgeError::Download(ref e) => write!(f, "Download failed: {0}", e),
+            SurgeError::Thumbnail(ref e) => write!(f, "Couldn't display thumbnail: {0}", e),
+            SurgeError::Player(ref e) => write!(f, "Player error: {0}", e),
+            SurgeError::Daemon(ref e) => write!(f, "Daemon error: {0}", e),
+            SurgeError::Usage(ref e) => write!(f, "{0}", e),
+        }
+    }
+}
+
+impl Error for SurgeError {
+    fn description(&self) -> &str {
+        match *self {
+            SurgeError::Io(ref e) => e.description(),
+            SurgeError::Http(ref e) => e.description(),
+            SurgeError::Json(ref e) => e.description(),
+            SurgeError::Mpv(ref e) => e.description(),
+            SurgeError::Dbus(ref e) => e.description(),
+            SurgeError::Database(ref e) => e.description(),
+            SurgeError::Backend(ref e) |
+            SurgeError::Download(ref e) |
+            SurgeError::Thumbnail(ref e) |
+            SurgeError::Player(ref e) |
+            SurgeError::Daemon(ref e) |
+            SurgeError::Usage(ref e) => e,
+        }
+    }
+}
+
+impl From<io::Error> for SurgeError {
+    fn from(e: io::Error) -> SurgeError {
+        SurgeError::Io(e)
+    }
+}
+
+impl From<hyper::Error> for SurgeError {
+    fn from(e: hyper::Error) -> SurgeError {
+        SurgeError::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for SurgeError {
+    fn from(e: serde_json::Error) -> SurgeError {
+        SurgeError::Json(e)
+    }
+}
+
+impl From<mpv::Error> for SurgeError {
+    fn from(e: mpv::Error) -> SurgeError {
+        SurgeError::Mpv(e)
+    }
+}
+
+impl From<dbus::Error> for SurgeError {
+    fn from(e: dbus::Error) -> SurgeError {
+        SurgeError::Dbus(e)
+    }
+}
+
+impl From<rusqlite::Error> for SurgeError {
+    fn from(e: rusqlite::Error) -> SurgeError {
+        SurgeError::Database(e)
+    }
+}
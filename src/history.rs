@@ -0,0 +1,287 @@
+//! What surge has played: each play is timed from player events and
+//! recorded in the library as the track ends.
+
+use backend::BackendSearchResult;
+use command::CommandCenter;
+use error::{Result, SurgeError};
+use player::PlayerEvent;
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Completed,
+    /// Cut short by a skip, a jump or `stop`
+    Skipped,
+    Failed,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Outcome::Completed => "completed",
+            Outcome::Skipped => "skipped",
+            Outcome::Failed => "failed",
+        };
+        write!(f, "{0}", name)
+    }
+}
+
+impl FromStr for Outcome {
+    type Err = SurgeError;
+
+    fn from_str(s: &str) -> Result<Outcome> {
+        match s {
+            "completed" => Ok(Outcome::Completed),
+            "skipped" => Ok(Outcome::Skipped),
+            "failed" => Ok(Outcome::Failed),
+            x => Err(SurgeError::Usage(format!("Unknown play outcome '{0}'", x))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Play {
+    pub track: BackendSearchResult,
+    /// Unix time the track started
+    pub started: u64,
+    /// Seconds listened, pauses left out
+    pub listened: u64,
+    pub outcome: Outcome,
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+/// The track playing right now, timed by wall clock between player events.
+struct Playing {
+    track: BackendSearchResult,
+    started: u64,
+    /// Since when it's been playing, None while paused
+    since: Option<Instant>,
+    listened: Duration,
+}
+
+impl Playing {
+    fn start(track: BackendSearchResult, paused: bool) -> Playing {
+        Playing {
+            track: track,
+            started: unix_now(),
+            since: if paused { None } else { Some(Instant::now()) },
+            listened: Duration::from_secs(0),
+        }
+    }
+
+    fn pause(&mut self) {
+        if let Some(since) = self.since.take() {
+            self.listened += since.elapsed();
+        }
+    }
+
+    fn resume(&mut self) {
+        if self.since.is_none() {
+            self.since = Some(Instant::now());
+        }
+    }
+
+    fn end(mut self, outcome: Outcome) -> Play {
+        self.pause();
+        Play {
+            track: self.track,
+            started: self.started,
+            listened: self.listened.as_secs(),
+            outcome: outcome,
+        }
+    }
+}
+
+/// Turns player events into plays, holding the one in progress.
+struct Tracker {
+    playing: Option<Playing>,
+    paused: bool,
+}
+
+impl Tracker {
+    fn new() -> Tracker {
+        Tracker {
+            playing: None,
+            paused: false,
+        }
+    }
+
+    /// The plays that `ev` brings to an end.
+    fn handle(&mut self, ev: PlayerEvent) -> Vec<Play> {
+        let mut ended = vec![];
+        match ev {
+            // a track that's replaced before it finishes was skipped
+            PlayerEvent::TrackChanged(x) => {
+                ended.extend(self.playing.take().map(|p| p.end(Outcome::Skipped)));
+                self.playing = Some(Playing::start(x, self.paused));
+            }
+            PlayerEvent::TrackFinished(_) => {
+                ended.extend(self.playing.take().map(|p| p.end(Outcome::Completed)));
+            }
+            // tracks that fail to load never start, so this is usually
+            // not the one playing
+            PlayerEvent::TrackFailed(x) => {
+                match self.playing.take() {
+                    Some(p) => {
+                        if p.track.id == x.id {
+                            ended.push(p.end(Outcome::Failed));
+                        } else {
+                            ended.push(p.end(Outcome::Skipped));
+                            ended.push(Playing::start(x, true).end(Outcome::Failed));
+                        }
+                    }
+                    None => ended.push(Playing::start(x, true).end(Outcome::Failed)),
+                }
+            }
+            PlayerEvent::QueueFinished |
+            PlayerEvent::Stopped => {
+                ended.extend(self.playing.take().map(|p| p.end(Outcome::Skipped)));
+            }
+            PlayerEvent::Paused => {
+                self.paused = true;
+                if let Some(ref mut p) = self.playing {
+                    p.pause();
+                }
+            }
+            PlayerEvent::Resumed => {
+                self.paused = false;
+                if let Some(ref mut p) = self.playing {
+                    p.resume();
+                }
+            }
+        }
+        ended
+    }
+}
+
+/// Record every play as it ends, until the player goes away.
+pub fn serve(cmd: Arc<Mutex<CommandCenter>>) {
+    let events = cmd.lock().expect("CommandCenter poisoned").subscribe();
+    let mut tracker = Tracker::new();
+    for ev in events.iter() {
+        for play in tracker.handle(ev) {
+            let res = cmd.lock().expect("CommandCenter poisoned").record_play(&play);
+            if let Err(e) = res {
+                println!("Couldn't record history: {0}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::BackendType;
+
+    fn track(id: &str) -> BackendSearchResult {
+        BackendSearchResult::new(id.to_string(), id.to_string(), BackendType::Local)
+    }
+
+    fn outcomes(plays: &[Play]) -> Vec<(&str, Outcome)> {
+        plays.iter().map(|x| (x.track.id.as_str(), x.outcome)).collect()
+    }
+
+    #[test]
+    fn changing_track_skips_the_one_playing() {
+        let mut t = Tracker::new();
+        assert!(t.handle(PlayerEvent::TrackChanged(track("a"))).is_empty());
+        let ended = t.handle(PlayerEvent::TrackChanged(track("b")));
+        assert_eq!(outcomes(&ended), vec![("a", Outcome::Skipped)]);
+        assert_eq!(t.playing.as_ref().unwrap().track.id, "b");
+    }
+
+    #[test]
+    fn finishing_completes_the_play() {
+        let mut t = Tracker::new();
+        t.handle(PlayerEvent::TrackChanged(track("a")));
+        let ended = t.handle(PlayerEvent::TrackFinished(track("a")));
+        assert_eq!(outcomes(&ended), vec![("a", Outcome::Completed)]);
+        assert!(t.playing.is_none());
+        // mpv's next FileLoaded doesn't end anything twice
+        assert!(t.handle(PlayerEvent::TrackChanged(track("b"))).is_empty());
+    }
+
+    #[test]
+    fn failing_the_playing_track_fails_it() {
+        let mut t = Tracker::new();
+        t.handle(PlayerEvent::TrackChanged(track("a")));
+        let ended = t.handle(PlayerEvent::TrackFailed(track("a")));
+        assert_eq!(outcomes(&ended), vec![("a", Outcome::Failed)]);
+    }
+
+    #[test]
+    fn failing_another_track_skips_the_playing_one() {
+        let mut t = Tracker::new();
+        t.handle(PlayerEvent::TrackChanged(track("a")));
+        let ended = t.handle(PlayerEvent::TrackFailed(track("b")));
+        assert_eq!(
+            outcomes(&ended),
+            vec![("a", Outcome::Skipped), ("b", Outcome::Failed)]
+        );
+        assert_eq!(ended[1].listened, 0);
+    }
+
+    #[test]
+    fn failing_with_nothing_playing_records_the_failure() {
+        let mut t = Tracker::new();
+        let ended = t.handle(PlayerEvent::TrackFailed(track("a")));
+        assert_eq!(outcomes(&ended), vec![("a", Outcome::Failed)]);
+    }
+
+    #[test]
+    fn stopping_ends_the_open_play() {
+        let mut t = Tracker::new();
+        t.handle(PlayerEvent::TrackChanged(track("a")));
+        let ended = t.handle(PlayerEvent::Stopped);
+        assert_eq!(outcomes(&ended), vec![("a", Outcome::Skipped)]);
+        assert!(t.playing.is_none());
+        assert!(t.handle(PlayerEvent::Stopped).is_empty());
+    }
+
+    #[test]
+    fn queue_finishing_ends_the_open_play() {
+        let mut t = Tracker::new();
+        t.handle(PlayerEvent::TrackChanged(track("a")));
+        let ended = t.handle(PlayerEvent::QueueFinished);
+        assert_eq!(outcomes(&ended), vec![("a", Outcome::Skipped)]);
+    }
+
+    #[test]
+    fn pauses_are_left_out_of_the_time_listened() {
+        let mut t = Tracker::new();
+        t.handle(PlayerEvent::TrackChanged(track("a")));
+        t.playing.as_mut().unwrap().since = Some(Instant::now() - Duration::from_secs(5));
+        t.handle(PlayerEvent::Paused);
+        assert!(t.playing.as_ref().unwrap().since.is_none());
+        let ended = t.handle(PlayerEvent::TrackFinished(track("a")));
+        assert_eq!(ended[0].listened, 5);
+    }
+
+    #[test]
+    fn tracks_changed_to_while_paused_start_paused() {
+        let mut t = Tracker::new();
+        t.handle(PlayerEvent::Paused);
+        t.handle(PlayerEvent::TrackChanged(track("a")));
+        assert!(t.playing.as_ref().unwrap().since.is_none());
+        t.handle(PlayerEvent::Resumed);
+        assert!(t.playing.as_ref().unwrap().since.is_some());
+    }
+
+    #[test]
+    fn outcomes_round_trip_through_their_names() {
+        for x in &[Outcome::Completed, Outcome::Skipped, Outcome::Failed] {
+            assert_eq!(x.to_string().parse::<Outcome>().unwrap(), *x);
+        }
+        assert!("finished".parse::<Outcome>().is_err());
+    }
+}
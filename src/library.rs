@@ -0,0 +1,958 @@
+//! The library database: every track surge has come across, with its
+//! metadata, where it was downloaded to, when it was played, whether it's a
+//! favorite, and the playlists it's on. One SQLite file in surge's data dir.
+
+use rusqlite::{self, Connection, Row};
+use rusqlite::types::ToSql;
+use serde_json::{self, Value};
+
+use backend::{BackendSearchResult, ResultKind};
+use error::{Result, SurgeError};
+use history::{self, Outcome, Play};
+use smart::{Order, Rule, SmartQuery};
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+const DB_NAME: &'static str = "library.sqlite3";
+pub const MAX_RATING: u8 = 5;
+
+/// Each brings the schema up by one `user_version`. Only ever append.
+const MIGRATIONS: &'static [&'static str] = &[
+    "CREATE TABLE sources (
+         id INTEGER PRIMARY KEY,
+         name TEXT NOT NULL UNIQUE
+     );
+     INSERT INTO sources (name) VALUES ('youtube'), ('ytdl'), ('local'), ('stream');
+
+     CREATE TABLE tracks (
+         id INTEGER PRIMARY KEY,
+         source_id INTEGER NOT NULL REFERENCES sources (id),
+         remote_id TEXT NOT NULL,
+         title TEXT NOT NULL,
+         artist TEXT,
+         duration INTEGER,
+         thumbnail TEXT,
+         published TEXT,
+         views INTEGER,
+         description TEXT,
+         favorite INTEGER NOT NULL DEFAULT 0,
+         favorited INTEGER,
+         rating INTEGER,
+         UNIQUE (source_id, remote_id)
+     );
+     CREATE INDEX tracks_remote_id ON tracks (remote_id);
+
+     CREATE TABLE downloads (
+         track_id INTEGER PRIMARY KEY REFERENCES tracks (id) ON DELETE CASCADE,
+         path TEXT NOT NULL,
+         downloaded INTEGER NOT NULL
+     );
+
+     CREATE TABLE plays (
+         id INTEGER PRIMARY KEY,
+         track_id INTEGER NOT NULL REFERENCES tracks (id) ON DELETE CASCADE,
+         started INTEGER NOT NULL,
+         listened INTEGER NOT NULL,
+         outcome TEXT NOT NULL
+     );
+     CREATE INDEX plays_started ON plays (started);
+
+     CREATE TABLE playlists (
+         id INTEGER PRIMARY KEY,
+         name TEXT NOT NULL UNIQUE
+     );
+     CREATE TABLE playlist_tracks (
+         playlist_id INTEGER NOT NULL REFERENCES playlists (id) ON DELETE CASCADE,
+         position INTEGER NOT NULL,
+         track_id INTEGER NOT NULL REFERENCES tracks (id),
+         PRIMARY KEY (playlist_id, position)
+     );",
+    "CREATE TABLE smart_playlists (
+         id INTEGER PRIMARY KEY,
+         name TEXT NOT NULL UNIQUE,
+         query TEXT NOT NULL
+     );",
+];
+
+/// What's selected to make a `BackendSearchResult`, see `track_from_row`.
+const TRACK_COLUMNS: &'static str = "sources.name, tracks.remote_id, tracks.title, \
+                                     tracks.artist, tracks.duration, tracks.thumbnail, \
+                                     tracks.published, tracks.views, tracks.description";
+/// Joined onto `tracks` for the name of their source
+const SOURCE_JOIN: &'static str = "JOIN sources ON sources.id = tracks.source_id";
+/// Tracks the user picked in some way, as opposed to search results that
+/// were only cached for their metadata
+const CHOSEN: &'static str = "(tracks.favorite OR tracks.rating IS NOT NULL \
+                              OR EXISTS (SELECT 1 FROM plays WHERE plays.track_id = tracks.id) \
+                              OR EXISTS (SELECT 1 FROM downloads \
+                                         WHERE downloads.track_id = tracks.id) \
+                              OR EXISTS (SELECT 1 FROM playlist_tracks \
+                                         WHERE playlist_tracks.track_id = tracks.id))";
+
+#[derive(Debug, Clone)]
+pub struct Favorite {
+    pub track: BackendSearchResult,
+    /// 1 to `MAX_RATING` stars
+    pub rating: Option<u8>,
+}
+
+pub struct Library {
+    conn: Connection,
+}
+
+impl Library {
+    /// Open the library in `dir`, creating or upgrading it as needed. A new
+    /// library takes in the JSON files surge kept before it had one.
+    pub fn open(dir: &Path) -> Result<Library> {
+        let mut path = dir.to_path_buf();
+        path.push(DB_NAME);
+        let mut library = Library { conn: Connection::open(&path)? };
+        library.conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        library.migrate(Some(dir))?;
+        Ok(library)
+    }
+
+    /// A library that only lives as long as it does, with nothing to import.
+    #[cfg(test)]
+    pub fn in_memory() -> Result<Library> {
+        let mut library = Library { conn: Connection::open_in_memory()? };
+        library.conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        library.migrate(None)?;
+        Ok(library)
+    }
+
+    /// Apply the migrations the database hasn't had yet. The first takes in
+    /// the JSON files found in `json_dir`.
+    fn migrate(&mut self, json_dir: Option<&Path>) -> Result<()> {
+        let version = self.conn.query_row(
+            "PRAGMA user_version",
+            &[],
+            |row| row.get::<_, i64>(0),
+        )? as usize;
+        if version > MIGRATIONS.len() {
+            return Err(SurgeError::Usage(format!(
+                "{0} is from a newer surge, version {1} of its schema",
+                DB_NAME,
+                version
+            )));
+        }
+        for (i, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = self.conn.transaction()?;
+            tx.execute_batch(sql)?;
+            // in the same transaction, so an import that fails is tried
+            // again next time instead of being lost
+            if let (0, Some(dir)) = (i, json_dir) {
+                let skipped = import_json_stores(&tx, dir)?;
+                if skipped > 0 {
+                    println!(
+                        "Couldn't import {0} saved playlists, plays or favorites",
+                        skipped
+                    );
+                }
+            }
+            tx.execute_batch(&format!("PRAGMA user_version = {0};", i + 1))?;
+            tx.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Keep the metadata of search results, so tracks can be looked up
+    /// without asking the backends again.
+    pub fn cache_tracks(&mut self, xs: &[BackendSearchResult]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        for x in xs.iter().filter(|x| x.kind == ResultKind::Track) {
+            track_row(&tx, x)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// What's known of the track with this backend id, from any backend.
+    pub fn track(&self, id: &str) -> Result<Option<BackendSearchResult>> {
+        let res = self.conn.query_row(
+            &format!(
+                "SELECT {0} FROM tracks {1} WHERE tracks.remote_id = ?1 LIMIT 1",
+                TRACK_COLUMNS,
+                SOURCE_JOIN
+            ),
+            &[&id],
+            |row| track_from_row(row, 0),
+        );
+        match optional(res)? {
+            Some(x) => Ok(Some(x?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Every playlist with its number of tracks, by name.
+    pub fn playlists(&self) -> Result<Vec<(String, usize)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT playlists.name, COUNT(playlist_tracks.track_id) FROM playlists \
+             LEFT JOIN playlist_tracks ON playlist_tracks.playlist_id = playlists.id \
+             GROUP BY playlists.id ORDER BY playlists.name",
+        )?;
+        let rows = stmt.query_map(&[], |row| {
+            (row.get::<_, String>(0), row.get::<_, i64>(1) as usize)
+        })?;
+        let mut ret = vec![];
+        for row in rows {
+            ret.push(row?);
+        }
+        Ok(ret)
+    }
+
+    pub fn playlist_exists(&self, name: &str) -> Result<bool> {
+        Ok(self.playlist_id(name)?.is_some())
+    }
+
+    fn playlist_id(&self, name: &str) -> Result<Option<i64>> {
+        optional(self.conn.query_row(
+            "SELECT id FROM playlists WHERE name = ?1",
+            &[&name],
+            |row| row.get(0),
+        ))
+    }
+
+    pub fn playlist(&self, name: &str) -> Result<Vec<BackendSearchResult>> {
+        let id = match self.playlist_id(name)? {
+            Some(x) => x,
+            None => return Err(SurgeError::Usage(format!("No playlist named '{0}'", name))),
+        };
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {0} FROM playlist_tracks \
+             JOIN tracks ON tracks.id = playlist_tracks.track_id {1} \
+             WHERE playlist_tracks.playlist_id = ?1 ORDER BY playlist_tracks.position",
+            TRACK_COLUMNS,
+            SOURCE_JOIN
+        ))?;
+        let rows = stmt.query_map(&[&id], |row| track_from_row(row, 0))?;
+        let mut tracks = vec![];
+        for row in rows {
+            tracks.push(row??);
+        }
+        Ok(tracks)
+    }
+
+    /// Make `tracks` the playlist `name`, replacing any of that name.
+    pub fn save_playlist(&mut self, name: &str, tracks: &[BackendSearchResult]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        insert_playlist(&tx, name, tracks)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Returns false if there was no such playlist.
+    pub fn delete_playlist(&mut self, name: &str) -> Result<bool> {
+        let n = self.conn.execute(
+            "DELETE FROM playlists WHERE name = ?1",
+            &[&name],
+        )?;
+        Ok(n > 0)
+    }
+
+    pub fn record_play(&mut self, play: &Play) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        insert_play(&tx, play)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Up to `limit` plays, newest first, skipping the `offset` newest.
+    pub fn plays(&self, offset: usize, limit: usize) -> Result<Vec<Play>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {0}, plays.started, plays.listened, plays.outcome \
+             FROM plays JOIN tracks ON tracks.id = plays.track_id {1} \
+             ORDER BY plays.started DESC, plays.id DESC LIMIT ?1 OFFSET ?2",
+            TRACK_COLUMNS,
+            SOURCE_JOIN
+        ))?;
+        let rows = stmt.query_map(
+            &[&sql_int(limit as u64), &sql_int(offset as u64)],
+            |row| play_from_row(row, 0),
+        )?;
+        let mut plays = vec![];
+        for row in rows {
+            plays.push(row??);
+        }
+        Ok(plays)
+    }
+
+    /// Up to `limit` plays whose title or artist contains `query`, ignoring
+    /// case, newest first, each with its position among all plays as
+    /// `plays` has them.
+    pub fn search_plays(&self, query: &str, limit: usize) -> Result<Vec<(usize, Play)>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT (SELECT COUNT(*) FROM plays AS newer \
+                     WHERE newer.started > plays.started \
+                     OR (newer.started = plays.started AND newer.id > plays.id)), \
+             {0}, plays.started, plays.listened, plays.outcome \
+             FROM plays JOIN tracks ON tracks.id = plays.track_id {1} \
+             WHERE instr(lower(tracks.title), ?1) > 0 \
+             OR instr(lower(COALESCE(tracks.artist, '')), ?1) > 0 \
+             ORDER BY plays.started DESC, plays.id DESC LIMIT ?2",
+            TRACK_COLUMNS,
+            SOURCE_JOIN
+        ))?;
+        let rows = stmt.query_map(
+            &[&query.trim().to_lowercase(), &sql_int(limit as u64)],
+            |row| -> Result<(usize, Play)> {
+                Ok((row.get_checked::<_, i64>(0)? as usize, play_from_row(row, 1)?))
+            },
+        )?;
+        let mut plays = vec![];
+        for row in rows {
+            plays.push(row??);
+        }
+        Ok(plays)
+    }
+
+    /// Favorites, in the order they were made favorites.
+    pub fn favorites(&self) -> Result<Vec<Favorite>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {0}, tracks.rating FROM tracks {1} WHERE tracks.favorite \
+             ORDER BY tracks.favorited, tracks.id",
+            TRACK_COLUMNS,
+            SOURCE_JOIN
+        ))?;
+        let rows = stmt.query_map(&[], |row| -> Result<Favorite> {
+            Ok(Favorite {
+                track: track_from_row(row, 0)?,
+                rating: row.get_checked::<_, Option<i64>>(9)?.map(|x| x as u8),
+            })
+        })?;
+        let mut favs = vec![];
+        for row in rows {
+            favs.push(row??);
+        }
+        Ok(favs)
+    }
+
+    pub fn rating(&self, id: &str) -> Result<Option<u8>> {
+        let rating = optional(self.conn.query_row(
+            "SELECT rating FROM tracks WHERE remote_id = ?1 AND rating IS NOT NULL LIMIT 1",
+            &[&id],
+            |row| row.get::<_, i64>(0),
+        ))?;
+        Ok(rating.map(|x| x as u8))
+    }
+
+    /// Returns false if `track` already was, or wasn't, a favorite.
+    pub fn set_favorite(&mut self, track: &BackendSearchResult, favorite: bool) -> Result<bool> {
+        let tx = self.conn.transaction()?;
+        let changed = favorite_row(&tx, track, favorite)?;
+        tx.commit()?;
+        Ok(changed)
+    }
+
+    pub fn rate(&mut self, track: &BackendSearchResult, stars: usize) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        rate_row(&tx, track, stars)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Where `track` was downloaded to, if it was and the file's still
+    /// there.
+    pub fn download(&self, track: &BackendSearchResult) -> Result<Option<PathBuf>> {
+        let path = optional(self.conn.query_row(
+            &format!(
+                "SELECT downloads.path FROM downloads \
+                 JOIN tracks ON tracks.id = downloads.track_id {0} \
+                 WHERE sources.name = ?1 AND tracks.remote_id = ?2",
+                SOURCE_JOIN
+            ),
+            &[&track.source.to_string(), &track.id],
+            |row| row.get::<_, String>(0),
+        ))?;
+        Ok(path.map(PathBuf::from).and_then(
+            |x| if x.is_file() { Some(x) } else { None },
+        ))
+    }
+
+    pub fn record_download(&mut self, track: &BackendSearchResult, path: &str) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        let id = track_row(&tx, track)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO downloads (track_id, path, downloaded) VALUES (?1, ?2, ?3)",
+            &[&id, &path, &(history::unix_now() as i64)],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The tracks matching every rule of `query`, out of those that were
+    /// played, rated, made favorites, downloaded or put on a playlist.
+    /// Failed plays don't count as plays.
+    pub fn smart(&self, query: &SmartQuery) -> Result<Vec<BackendSearchResult>> {
+        let now = history::unix_now();
+        let since = query.since.map_or(0, |x| now.saturating_sub(x));
+        // everything that comes from the query is bound, numbers included;
+        // ?1 is `since` and ?2 is now
+        let mut params: Vec<Box<ToSql>> = vec![Box::new(sql_int(since)), Box::new(sql_int(now))];
+        let plays = "(SELECT COUNT(*) FROM plays WHERE plays.track_id = tracks.id \
+                     AND plays.outcome != 'failed' AND plays.started >= ?1)";
+        let last_played = "(SELECT COALESCE(MAX(plays.started), 0) FROM plays \
+                           WHERE plays.track_id = tracks.id AND plays.outcome != 'failed')";
+        let downloaded = "EXISTS (SELECT 1 FROM downloads WHERE downloads.track_id = tracks.id)";
+
+        let mut wheres = vec![String::from(CHOSEN)];
+        for rule in &query.rules {
+            let n = params.len() + 1;
+            wheres.push(match *rule {
+                Rule::Rating(cmp, x) => {
+                    params.push(Box::new(sql_int(x)));
+                    format!("tracks.rating {0} ?{1}", cmp.sql(), n)
+                }
+                Rule::Plays(cmp, x) => {
+                    params.push(Box::new(sql_int(x)));
+                    format!("{0} {1} ?{2}", plays, cmp.sql(), n)
+                }
+                Rule::Played(cmp, secs) => {
+                    params.push(Box::new(sql_int(secs)));
+                    format!("?2 - {0} {1} ?{2}", last_played, cmp.sql(), n)
+                }
+                Rule::Downloaded(true) => String::from(downloaded),
+                Rule::Downloaded(false) => format!("NOT {0}", downloaded),
+                Rule::Favorite(x) => {
+                    params.push(Box::new(x));
+                    format!("tracks.favorite = ?{0}", n)
+                }
+                Rule::Source(x) => {
+                    params.push(Box::new(x.to_string()));
+                    format!("sources.name = ?{0}", n)
+                }
+                Rule::Text(ref x) => {
+                    params.push(Box::new(x.clone()));
+                    format!(
+                        "(instr(lower(tracks.title), ?{0}) > 0 \
+                         OR instr(lower(COALESCE(tracks.artist, '')), ?{0}) > 0)",
+                        n
+                    )
+                }
+            });
+        }
+        let order = match query.order {
+            Some(Order::Plays) => format!("{0} DESC,", plays),
+            Some(Order::Rating) => String::from("tracks.rating IS NULL, tracks.rating DESC,"),
+            Some(Order::Recent) => format!("{0} DESC,", last_played),
+            Some(Order::Title) => String::from("tracks.title COLLATE NOCASE,"),
+            Some(Order::Random) => String::from("RANDOM(),"),
+            None => String::new(),
+        };
+        params.push(Box::new(sql_int(query.limit as u64)));
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {0} FROM tracks {1} WHERE {2} ORDER BY {3} tracks.id LIMIT ?{4}",
+            TRACK_COLUMNS,
+            SOURCE_JOIN,
+            wheres.join(" AND "),
+            order,
+            params.len()
+        ))?;
+        let params = params.iter().map(|x| &**x).collect::<Vec<&ToSql>>();
+        let rows = stmt.query_map(&params, |row| track_from_row(row, 0))?;
+        let mut tracks = vec![];
+        for row in rows {
+            tracks.push(row??);
+        }
+        Ok(tracks)
+    }
+
+    /// Every saved smart playlist and its query, by name.
+    pub fn smart_playlists(&self) -> Result<Vec<(String, String)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT name, query FROM smart_playlists ORDER BY name",
+        )?;
+        let rows = stmt.query_map(&[], |row| {
+            (row.get::<_, String>(0), row.get::<_, String>(1))
+        })?;
+        let mut ret = vec![];
+        for row in rows {
+            ret.push(row?);
+        }
+        Ok(ret)
+    }
+
+    /// The query saved as the smart playlist `name`.
+    pub fn smart_playlist(&self, name: &str) -> Result<Option<String>> {
+        optional(self.conn.query_row(
+            "SELECT query FROM smart_playlists WHERE name = ?1",
+            &[&name],
+            |row| row.get(0),
+        ))
+    }
+
+    /// Save `query` as the smart playlist `name`, replacing any of that name.
+    pub fn save_smart_playlist(&mut self, name: &str, query: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO smart_playlists (name, query) VALUES (?1, ?2)",
+            &[&name, &query],
+        )?;
+        Ok(())
+    }
+
+    /// Returns false if there was no such smart playlist.
+    pub fn delete_smart_playlist(&mut self, name: &str) -> Result<bool> {
+        let n = self.conn.execute(
+            "DELETE FROM smart_playlists WHERE name = ?1",
+            &[&name],
+        )?;
+        Ok(n > 0)
+    }
+}
+
+/// The id of the row for `x` in `tracks`, adding it or filling in what it
+/// was missing.
+fn track_row(conn: &Connection, x: &BackendSearchResult) -> Result<i64> {
+    let source = x.source.to_string();
+    conn.execute(
+        "INSERT OR IGNORE INTO tracks (source_id, remote_id, title) \
+         SELECT id, ?2, ?3 FROM sources WHERE name = ?1",
+        &[&source, &x.id, &x.title],
+    )?;
+    // bare results only know their id, which makes a poor title
+    conn.execute(
+        "UPDATE tracks SET title = CASE WHEN ?3 = remote_id THEN title ELSE ?3 END, \
+         artist = COALESCE(?4, artist), duration = COALESCE(?5, duration), \
+         thumbnail = COALESCE(?6, thumbnail), published = COALESCE(?7, published), \
+         views = COALESCE(?8, views), description = COALESCE(?9, description) \
+         WHERE source_id = (SELECT id FROM sources WHERE name = ?1) AND remote_id = ?2",
+        &[
+            &source,
+            &x.id,
+            &x.title,
+            &x.artist,
+            &x.duration.map(|x| x as i64),
+            &x.thumbnail,
+            &x.published,
+            &x.views.map(|x| x as i64),
+            &x.description,
+        ],
+    )?;
+    Ok(conn.query_row(
+        &format!(
+            "SELECT tracks.id FROM tracks {0} WHERE sources.name = ?1 AND tracks.remote_id = ?2",
+            SOURCE_JOIN
+        ),
+        &[&source, &x.id],
+        |row| row.get(0),
+    )?)
+}
+
+fn insert_playlist(conn: &Connection, name: &str, tracks: &[BackendSearchResult]) -> Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO playlists (name) VALUES (?1)",
+        &[&name],
+    )?;
+    let id = conn.query_row(
+        "SELECT id FROM playlists WHERE name = ?1",
+        &[&name],
+        |row| row.get::<_, i64>(0),
+    )?;
+    conn.execute(
+        "DELETE FROM playlist_tracks WHERE playlist_id = ?1",
+        &[&id],
+    )?;
+    for (i, x) in tracks.iter().enumerate() {
+        let track_id = track_row(conn, x)?;
+        conn.execute(
+            "INSERT INTO playlist_tracks (playlist_id, position, track_id) VALUES (?1, ?2, ?3)",
+            &[&id, &(i as i64), &track_id],
+        )?;
+    }
+    Ok(())
+}
+
+fn insert_play(conn: &Connection, play: &Play) -> Result<()> {
+    let track_id = track_row(conn, &play.track)?;
+    conn.execute(
+        "INSERT INTO plays (track_id, started, listened, outcome) VALUES (?1, ?2, ?3, ?4)",
+        &[
+            &track_id,
+            &(play.started as i64),
+            &(play.listened as i64),
+            &play.outcome.to_string(),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Returns false if `track` already was, or wasn't, a favorite.
+fn favorite_row(conn: &Connection, track: &BackendSearchResult, favorite: bool) -> Result<bool> {
+    let id = track_row(conn, track)?;
+    let was = conn.query_row(
+        "SELECT favorite FROM tracks WHERE id = ?1",
+        &[&id],
+        |row| row.get::<_, bool>(0),
+    )?;
+    if was != favorite {
+        conn.execute(
+            "UPDATE tracks SET favorite = ?2, favorited = ?3 WHERE id = ?1",
+            &[&id, &favorite, &(history::unix_now() as i64)],
+        )?;
+    }
+    Ok(was != favorite)
+}
+
+fn rate_row(conn: &Connection, track: &BackendSearchResult, stars: usize) -> Result<()> {
+    if stars == 0 || stars > MAX_RATING as usize {
+        return Err(SurgeError::Usage(
+            format!("Ratings go from 1 to {0} stars", MAX_RATING),
+        ));
+    }
+    let id = track_row(conn, track)?;
+    conn.execute(
+        "UPDATE tracks SET rating = ?2 WHERE id = ?1",
+        &[&id, &(stars as i64)],
+    )?;
+    Ok(())
+}
+
+/// Take in the playlists, history and favorites surge kept as JSON files
+/// before it had a library. The files are left in place. Files and entries
+/// that don't make sense are skipped rather than keeping the library from
+/// opening; returns how many.
+fn import_json_stores(conn: &Connection, dir: &Path) -> Result<usize> {
+    let mut skipped = 0;
+
+    let mut playlists_dir = dir.to_path_buf();
+    playlists_dir.push("playlists");
+    if playlists_dir.is_dir() {
+        for entry in fs::read_dir(&playlists_dir)? {
+            let path = entry?.path();
+            let name = match path.file_stem() {
+                Some(x) if path.extension().map_or(false, |x| x == "json") => {
+                    x.to_string_lossy().into_owned()
+                }
+                _ => continue,
+            };
+            let tracks = match read_json(&path) {
+                Ok(Value::Array(xs)) => xs,
+                _ => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+            let mut found = vec![];
+            for x in &tracks {
+                match json_track(x) {
+                    Ok(track) => found.push(track),
+                    Err(_) => skipped += 1,
+                }
+            }
+            insert_playlist(conn, &name, &found)?;
+        }
+    }
+
+    let mut history_path = dir.to_path_buf();
+    history_path.push("history.jsonl");
+    if history_path.is_file() {
+        for line in BufReader::new(File::open(&history_path)?).lines() {
+            match json_play(&line?) {
+                Ok(play) => insert_play(conn, &play)?,
+                Err(_) => skipped += 1,
+            }
+        }
+    }
+
+    let mut favorites_path = dir.to_path_buf();
+    favorites_path.push("favorites.json");
+    let favorites = if favorites_path.is_file() {
+        match read_json(&favorites_path) {
+            Ok(Value::Array(xs)) => xs,
+            _ => {
+                skipped += 1;
+                vec![]
+            }
+        }
+    } else {
+        vec![]
+    };
+    for x in favorites {
+        let track = match json_track(&x["track"]) {
+            Ok(track) => track,
+            Err(_) => {
+                skipped += 1;
+                continue;
+            }
+        };
+        if x["favorite"].as_bool().unwrap_or(false) {
+            favorite_row(conn, &track, true)?;
+        }
+        if let Some(stars) = x["rating"].as_u64() {
+            // a rating out of range loses the rating, not the favorite
+            match rate_row(conn, &track, stars as usize) {
+                Ok(()) => (),
+                Err(SurgeError::Usage(_)) => skipped += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(skipped)
+}
+
+/// SQLite integers are signed; anything past them is as good as endless.
+fn sql_int(x: u64) -> i64 {
+    x.min(i64::max_value() as u64) as i64
+}
+
+/// A track from `TRACK_COLUMNS`, starting at column `i`.
+fn track_from_row(row: &Row, i: i32) -> Result<BackendSearchResult> {
+    let source = row.get_checked::<_, String>(i)?.parse()?;
+    Ok(BackendSearchResult {
+        artist: row.get_checked(i + 3)?,
+        duration: row.get_checked::<_, Option<i64>>(i + 4)?.map(|x| x as u64),
+        thumbnail: row.get_checked(i + 5)?,
+        published: row.get_checked(i + 6)?,
+        views: row.get_checked::<_, Option<i64>>(i + 7)?.map(|x| x as u64),
+        description: row.get_checked(i + 8)?,
+        ..BackendSearchResult::new(row.get_checked(i + 1)?, row.get_checked(i + 2)?, source)
+    })
+}
+
+/// A play from `TRACK_COLUMNS` and the `started`, `listened` and `outcome`
+/// of `plays`, starting at column `i`.
+fn play_from_row(row: &Row, i: i32) -> Result<Play> {
+    Ok(Play {
+        track: track_from_row(row, i)?,
+        started: row.get_checked::<_, i64>(i + 9)? as u64,
+        listened: row.get_checked::<_, i64>(i + 10)? as u64,
+        outcome: row.get_checked::<_, String>(i + 11)?.parse()?,
+    })
+}
+
+fn optional<T>(res: rusqlite::Result<T>) -> Result<Option<T>> {
+    match res {
+        Ok(x) => Ok(Some(x)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(SurgeError::from(e)),
+    }
+}
+
+fn read_json(path: &Path) -> Result<Value> {
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut x) => x.read_to_string(&mut contents)?,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Value::Null),
+        Err(e) => return Err(SurgeError::from(e)),
+    };
+    Ok(serde_json::from_str(&contents)?)
+}
+
+fn json_track(x: &Value) -> Result<BackendSearchResult> {
+    let field = |name: &str| x[name].as_str().map(String::from);
+    let (id, title, source) = match (field("id"), field("title"), field("source")) {
+        (Some(id), Some(title), Some(source)) => (id, title, source),
+        _ => {
+            return Err(SurgeError::Usage(
+                String::from("Saved track is missing its id, title or source"),
+            ))
+        }
+    };
+    Ok(BackendSearchResult {
+        thumbnail: field("thumbnail"),
+        artist: field("artist"),
+        duration: x["duration"].as_u64(),
+        ..BackendSearchResult::new(id, title, source.parse()?)
+    })
+}
+
+fn json_play(line: &str) -> Result<Play> {
+    let json = serde_json::from_str::<Value>(line)?;
+    Ok(Play {
+        track: json_track(&json["track"])?,
+        started: json["started"].as_u64().unwrap_or(0),
+        listened: json["listened"].as_u64().unwrap_or(0),
+        outcome: json["outcome"].as_str().unwrap_or("").parse::<Outcome>()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::BackendType;
+
+    use std::env;
+    use std::io::Write;
+
+    fn play(library: &mut Library, id: &str, artist: &str, started: u64) {
+        let track = BackendSearchResult {
+            artist: Some(String::from(artist)),
+            ..BackendSearchResult::new(String::from(id), id.to_uppercase(), BackendType::Ytdl)
+        };
+        library
+            .record_play(&Play {
+                track: track,
+                started: started,
+                listened: 60,
+                outcome: Outcome::Completed,
+            })
+            .unwrap();
+    }
+
+    /// Newest first: e, d, c, b, a, with b and c started at once
+    fn library() -> Library {
+        let mut library = Library::in_memory().unwrap();
+        play(&mut library, "a", "Daft Punk", 100);
+        play(&mut library, "b", "Justice", 200);
+        play(&mut library, "c", "Daft Punk", 200);
+        play(&mut library, "d", "Air", 300);
+        play(&mut library, "e", "Daft Punk", 400);
+        library
+    }
+
+    fn ids(plays: &[Play]) -> Vec<&str> {
+        plays.iter().map(|x| x.track.id.as_str()).collect()
+    }
+
+    #[test]
+    fn pages_plays() {
+        let library = library();
+        assert_eq!(ids(&library.plays(0, 100).unwrap()), vec!["e", "d", "c", "b", "a"]);
+        assert_eq!(ids(&library.plays(0, 2).unwrap()), vec!["e", "d"]);
+        assert_eq!(ids(&library.plays(2, 2).unwrap()), vec!["c", "b"]);
+        assert_eq!(ids(&library.plays(4, usize::max_value()).unwrap()), vec!["a"]);
+        assert!(library.plays(5, 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn searches_plays_with_their_positions() {
+        let library = library();
+        let found = library.search_plays(" daft PUNK ", 100).unwrap();
+        let positions = found
+            .iter()
+            .map(|&(i, ref x)| (i, x.track.id.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(positions, vec![(0, "e"), (2, "c"), (4, "a")]);
+        for &(i, ref x) in &found {
+            assert_eq!(library.plays(i, 1).unwrap()[0].track.id, x.track.id);
+        }
+        assert_eq!(library.search_plays("punk", 1).unwrap().len(), 1);
+        assert_eq!(library.search_plays("d", 100).unwrap()[1].0, 1);
+        assert!(library.search_plays("zz", 100).unwrap().is_empty());
+    }
+
+    /// An empty directory of its own for `name`.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("surge-library-{0}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path)
+            .unwrap()
+            .write_all(contents.as_bytes())
+            .unwrap();
+    }
+
+    /// The JSON files surge kept before the library, one of each kind of
+    /// entry that can't be imported included.
+    fn legacy_stores(name: &str) -> PathBuf {
+        let dir = temp_dir(name);
+        write(
+            &dir.join("playlists/chill.json"),
+            r#"[
+                {"id": "a", "title": "Alpha", "source": "youtube", "duration": 200,
+                 "artist": "Air", "thumbnail": "https://i.ytimg.com/vi/a/default.jpg"},
+                {"id": "nameless", "source": "youtube"},
+                {"id": "/music/b.flac", "title": "Bravo", "source": "local"}
+            ]"#,
+        );
+        write(&dir.join("playlists/notes.txt"), "not a playlist");
+        write(&dir.join("playlists/broken.json"), "{");
+        write(
+            &dir.join("history.jsonl"),
+            concat!(
+                r#"{"track": {"id": "a", "title": "Alpha", "source": "youtube"}, "#,
+                r#""started": 100, "listened": 200, "outcome": "completed"}"#,
+                "\n",
+                "garbage\n",
+                r#"{"track": {"id": "c", "title": "Charlie", "source": "ytdl"}, "#,
+                r#""started": 300, "listened": 5, "outcome": "skipped"}"#,
+                "\n"
+            ),
+        );
+        write(
+            &dir.join("favorites.json"),
+            r#"[
+                {"track": {"id": "c", "title": "Charlie", "source": "ytdl"},
+                 "favorite": true, "rating": 4},
+                {"track": {"id": "a", "title": "Alpha", "source": "youtube"},
+                 "favorite": false, "rating": 9},
+                {"track": {"title": "No id"}, "favorite": true}
+            ]"#,
+        );
+        dir
+    }
+
+    #[test]
+    fn imports_legacy_json_stores() {
+        let dir = legacy_stores("import");
+        let library = Library::open(&dir).unwrap();
+
+        assert_eq!(library.playlists().unwrap(), vec![(String::from("chill"), 2)]);
+        let chill = library.playlist("chill").unwrap();
+        assert_eq!(chill[0].id, "a");
+        assert_eq!(chill[0].source, BackendType::Youtube);
+        assert_eq!(chill[0].duration, Some(200));
+        assert_eq!(chill[0].artist, Some(String::from("Air")));
+        assert_eq!(chill[1].id, "/music/b.flac");
+        assert_eq!(chill[1].source, BackendType::Local);
+
+        let plays = library.plays(0, 100).unwrap();
+        assert_eq!(ids(&plays), vec!["c", "a"]);
+        assert_eq!((plays[0].started, plays[0].listened), (300, 5));
+        assert_eq!(plays[0].outcome, Outcome::Skipped);
+        assert_eq!(plays[0].track.source, BackendType::Ytdl);
+        assert_eq!(plays[1].outcome, Outcome::Completed);
+
+        let favorites = library.favorites().unwrap();
+        assert_eq!(favorites.len(), 1);
+        assert_eq!(favorites[0].track.id, "c");
+        assert_eq!(favorites[0].rating, Some(4));
+        // a rating out of range is dropped, not clamped
+        assert_eq!(library.rating("a").unwrap(), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopening_does_not_import_again() {
+        let dir = legacy_stores("reopen");
+        Library::open(&dir).unwrap();
+        let library = Library::open(&dir).unwrap();
+        assert_eq!(library.plays(0, 100).unwrap().len(), 2);
+        assert_eq!(library.playlist("chill").unwrap().len(), 2);
+        assert_eq!(library.favorites().unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn opening_without_stores_starts_empty() {
+        let dir = temp_dir("empty");
+        let library = Library::open(&dir).unwrap();
+        assert!(library.playlists().unwrap().is_empty());
+        assert!(library.plays(0, 100).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn records_downloads_that_are_still_there() {
+        let dir = temp_dir("downloads");
+        let file = dir.join("a.flac");
+        write(&file, "");
+        let mut library = Library::in_memory().unwrap();
+        let track =
+            BackendSearchResult::new(String::from("a"), String::from("Alpha"), BackendType::Ytdl);
+        assert_eq!(library.download(&track).unwrap(), None);
+        library.record_download(&track, &file.to_string_lossy()).unwrap();
+        assert_eq!(library.download(&track).unwrap(), Some(file.clone()));
+        fs::remove_file(&file).unwrap();
+        assert_eq!(library.download(&track).unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+//! Making sense of whatever gets pasted into `play url`: YouTube links,
+//! bare video ids, streams and local files.
+
+use url::Url;
+
+use std::path::{Path, PathBuf};
+
+const VIDEO_ID_LEN: usize = 11;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Link {
+    Video(String),
+    Playlist(String),
+    /// Anything else mpv can open by URL, e.g. internet radio
+    Stream(String),
+    File(PathBuf),
+}
+
+pub fn parse(x: &str) -> Option<Link> {
+    let x = x.trim();
+    if Path::new(x).is_file() {
+        return Some(Link::File(PathBuf::from(x)));
+    }
+    match Url::parse(x) {
+        Ok(url) => {
+            if url.scheme() == "file" {
+                return url.to_file_path().ok().map(Link::File);
+            }
+            Some(youtube_link(&url).unwrap_or_else(|| Link::Stream(String::from(x))))
+        }
+        Err(_) if is_bare_video_id(x) => Some(Link::Video(String::from(x))),
+        // links copied without their scheme, e.g. youtu.be/dQw4w9WgXcQ
+        Err(_) => {
+            Url::parse(&format!("https://{0}", x))
+                .ok()
+                .and_then(|url| youtube_link(&url))
+        }
+    }
+}
+
+/// watch, youtu.be, shorts, embed and playlist links, on any of YouTube's
+/// hosts, music.youtube.com included.
+fn youtube_link(url: &Url) -> Option<Link> {
+    let host = match url.host_str() {
+        Some(x) => x.trim_left_matches("www.").trim_left_matches("m."),
+        None => return None,
+    };
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|&(ref k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    };
+    let path = url.path();
+
+    let link = match host {
+        "youtu.be" => Link::Video(String::from(path.trim_left_matches('/'))),
+        "youtube.com" | "music.youtube.com" => {
+            if path == "/watch" {
+                match param("v") {
+                    Some(x) => Link::Video(x),
+                    None => return None,
+                }
+            } else if path == "/playlist" {
+                match param("list") {
+                    Some(x) => Link::Playlist(x),
+                    None => return None,
+                }
+            } else if path.starts_with("/shorts/") || path.starts_with("/embed/") {
+                Link::Video(String::from(path.splitn(3, '/').nth(2).unwrap_or("")))
+            } else {
+                return None;
+            }
+        }
+        _ => return None,
+    };
+    match link {
+        Link::Video(ref x) if !is_video_id(x) => None,
+        x => Some(x),
+    }
+}
+
+fn is_video_id(x: &str) -> bool {
+    x.len() == VIDEO_ID_LEN &&
+        x.chars().all(|c| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' => true,
+            _ => false,
+        })
+}
+
+/// Eleven lowercase letters are far more likely a word than a video id.
+fn is_bare_video_id(x: &str) -> bool {
+    is_video_id(x) && !x.chars().all(|c| c >= 'a' && c <= 'z')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs::{self, File};
+
+    fn video(x: &str) -> Option<Link> {
+        Some(Link::Video(String::from(x)))
+    }
+
+    #[test]
+    fn video_links() {
+        let cases = [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "https://www.youtube.com/watch?feature=share&v=dQw4w9WgXcQ&t=42s",
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI",
+            "https://m.youtube.com/watch?v=dQw4w9WgXcQ",
+            "https://music.youtube.com/watch?v=dQw4w9WgXcQ&si=abc",
+            "https://youtu.be/dQw4w9WgXcQ",
+            "https://youtu.be/dQw4w9WgXcQ?t=42&si=abc",
+            "https://www.youtube.com/shorts/dQw4w9WgXcQ",
+            "https://www.youtube.com/embed/dQw4w9WgXcQ?autoplay=1",
+            "youtu.be/dQw4w9WgXcQ",
+            "www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "  https://youtu.be/dQw4w9WgXcQ\n",
+        ];
+        for x in &cases {
+            assert_eq!(parse(x), video("dQw4w9WgXcQ"), "{0}", x);
+        }
+    }
+
+    #[test]
+    fn playlist_links() {
+        let cases = [
+            "https://www.youtube.com/playlist?list=PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI",
+            "https://music.youtube.com/playlist?list=PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI&si=x",
+            "youtube.com/playlist?list=PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI",
+        ];
+        for x in &cases {
+            assert_eq!(
+                parse(x),
+                Some(Link::Playlist(
+                    String::from("PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI"),
+                )),
+                "{0}",
+                x
+            );
+        }
+    }
+
+    #[test]
+    fn bare_ids_but_not_words() {
+        assert_eq!(parse("dQw4w9WgXcQ"), video("dQw4w9WgXcQ"));
+        assert_eq!(parse("-_-_-_-_-_-"), video("-_-_-_-_-_-"));
+        for x in &["programming", "hello", "dQw4w9WgXc", "dQw4w9WgXcQQ", "dQw4w9WgX!Q", ""] {
+            assert_eq!(parse(x), None, "{0}", x);
+        }
+    }
+
+    #[test]
+    fn youtube_links_without_a_valid_video_are_streams() {
+        let cases = [
+            "https://www.youtube.com/watch?v=short",
+            "https://www.youtube.com/@somechannel",
+            "https://youtu.be/",
+        ];
+        for x in &cases {
+            assert_eq!(parse(x), Some(Link::Stream(String::from(*x))), "{0}", x);
+        }
+        // without a scheme there's no telling them from words
+        assert_eq!(parse("youtube.com/watch?v=short"), None);
+    }
+
+    #[test]
+    fn streams() {
+        let cases = [
+            "http://radio.example.com:8000/live",
+            "https://ice.example.org/stream.mp3?token=1",
+            "rtmp://example.com/live",
+        ];
+        for x in &cases {
+            assert_eq!(parse(x), Some(Link::Stream(String::from(*x))), "{0}", x);
+        }
+    }
+
+    #[test]
+    fn file_urls() {
+        assert_eq!(
+            parse("file:///music/some%20album/01.flac"),
+            Some(Link::File(PathBuf::from("/music/some album/01.flac")))
+        );
+        assert_eq!(parse("file://remote-host/01.flac"), None);
+    }
+
+    #[test]
+    fn existing_files() {
+        let dir = env::temp_dir().join("surge-link-test");
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("dQw4w9WgXcQ");
+        File::create(&file).unwrap();
+        let path = file.to_string_lossy().into_owned();
+        assert_eq!(parse(&path), Some(Link::File(file)));
+        // once it's gone, the path is nothing surge can play
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(parse(&path), None);
+    }
+}
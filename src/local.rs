@@ -0,0 +1,258 @@
+use backend::{self, Backend, BackendSearchResult, BackendType, ResultPage, SearchQuery};
+use error::Result;
+use tags::{self, Tags};
+
+use strsim::jaro_winkler;
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// How close, on average, the words of a query have to come to the words of
+/// a track's tags for the track to be a match
+const FUZZY_THRESHOLD: f64 = 0.85;
+
+struct LocalTrack {
+    modified: SystemTime,
+    tags: Tags,
+}
+
+/// Searches the music on disk, surge's own downloads included, so search
+/// and play work offline.
+pub struct LocalBackend {
+    dirs: Vec<PathBuf>,
+    max_results: usize,
+    /// Tags by path, only re-read when a file changes
+    index: Mutex<HashMap<PathBuf, LocalTrack>>,
+}
+
+impl LocalBackend {
+    pub fn new(dirs: Vec<PathBuf>, max_results: usize) -> LocalBackend {
+        LocalBackend {
+            dirs: dirs,
+            max_results: max_results,
+            index: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Rescan the library dirs, picking up new downloads and dropping files
+    /// that are gone.
+    fn refresh(&self, index: &mut HashMap<PathBuf, LocalTrack>) {
+        let mut found = vec![];
+        let mut visited = HashSet::new();
+        for dir in &self.dirs {
+            walk(dir, &mut visited, &mut found);
+        }
+
+        let mut seen = HashSet::new();
+        for (path, modified) in found {
+            let stale = match index.get(&path) {
+                Some(x) => x.modified != modified,
+                None => true,
+            };
+            if stale {
+                let tags = tags::read_tags(&path);
+                index.insert(
+                    path.clone(),
+                    LocalTrack {
+                        modified: modified,
+                        tags: tags,
+                    },
+                );
+            }
+            seen.insert(path);
+        }
+        index.retain(|path, _| seen.contains(path));
+    }
+}
+
+impl Backend for LocalBackend {
+    /// Other tracks by the same artist or from the same album, or failing
+    /// that, from the same directory.
+    fn find_related_tracks(&self, id: &str, page: Option<&str>) -> Result<ResultPage> {
+        let offset = backend::parse_offset(page)?;
+        let mut index = self.index.lock().expect("Local index poisoned");
+        self.refresh(&mut index);
+
+        let path = Path::new(id);
+        let (artist, album) = match index.get(path) {
+            Some(x) => (lowercase(&x.tags.artist), lowercase(&x.tags.album)),
+            None => (None, None),
+        };
+        let dir = path.parent();
+
+        let mut results = index
+            .iter()
+            .filter(|&(p, x)| {
+                p.as_path() != path &&
+                    if artist.is_some() || album.is_some() {
+                        (artist.is_some() && lowercase(&x.tags.artist) == artist) ||
+                            (album.is_some() && lowercase(&x.tags.album) == album)
+                    } else {
+                        p.parent() == dir
+                    }
+            })
+            .map(|(p, x)| result(p, &x.tags))
+            .collect::<Vec<_>>();
+        results.sort_by(|a, b| a.title.cmp(&b.title));
+        Ok(backend::offset_page(results, offset, self.max_results))
+    }
+
+    fn lookup(&self, id: &str) -> Result<BackendSearchResult> {
+        let path = Path::new(id);
+        Ok(result(path, &tags::read_tags(path)))
+    }
+
+    /// Tags don't say how long or how popular a track is, so filters are
+    /// ignored.
+    fn search(&self, query: &SearchQuery, page: Option<&str>) -> Result<ResultPage> {
+        let offset = backend::parse_offset(page)?;
+        let mut index = self.index.lock().expect("Local index poisoned");
+        self.refresh(&mut index);
+
+        let query = words(&query.terms);
+        let mut scored = index
+            .iter()
+            .map(|(path, x)| (score(&query, &haystack(path, &x.tags)), path, x))
+            .filter(|&(score, _, _)| score >= FUZZY_THRESHOLD)
+            .collect::<Vec<_>>();
+        scored.sort_by(|a, b| best_first(a.0, b.0));
+        let results = scored
+            .into_iter()
+            .map(|(_, path, x)| result(path, &x.tags))
+            .collect();
+        Ok(backend::offset_page(results, offset, self.max_results))
+    }
+}
+
+/// Collect every audio file under `dir`. Unreadable or missing dirs are
+/// skipped; a configured dir that doesn't exist yet isn't an error.
+/// Symlinked dirs are followed, but each dir is only walked once, so
+/// symlink loops and dirs linked from several places aren't walked forever.
+fn walk(dir: &Path, visited: &mut HashSet<PathBuf>, out: &mut Vec<(PathBuf, SystemTime)>) {
+    match fs::canonicalize(dir) {
+        Ok(real) => {
+            if !visited.insert(real) {
+                return;
+            }
+        }
+        Err(_) => return,
+    }
+    let entries = match fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|x| x.ok()) {
+        let path = entry.path();
+        let meta = match fs::metadata(&path) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        if meta.is_dir() {
+            walk(&path, visited, out);
+        } else if tags::is_audio_file(&path) {
+            if let Ok(modified) = meta.modified() {
+                out.push((path, modified));
+            }
+        }
+    }
+}
+
+fn result(path: &Path, tags: &Tags) -> BackendSearchResult {
+    let stem = path.file_stem()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let title = match (tags.artist.as_ref(), tags.title.as_ref()) {
+        (Some(artist), Some(title)) => format!("{0} - {1}", artist, title),
+        (None, Some(title)) => title.clone(),
+        _ => stem,
+    };
+    BackendSearchResult {
+        artist: tags.artist.clone(),
+        ..BackendSearchResult::new(path.to_string_lossy().into_owned(), title, BackendType::Local)
+    }
+}
+
+fn lowercase(x: &Option<String>) -> Option<String> {
+    x.as_ref().map(|x| x.to_lowercase())
+}
+
+/// Everything a query can match: the tags, plus the file name for files
+/// without any.
+fn haystack(path: &Path, tags: &Tags) -> Vec<String> {
+    let mut ret = vec![];
+    for x in &[&tags.title, &tags.artist, &tags.album] {
+        if let Some(ref x) = **x {
+            ret.extend(words(x));
+        }
+    }
+    if let Some(stem) = path.file_stem() {
+        ret.extend(words(&stem.to_string_lossy()));
+    }
+    ret
+}
+
+fn words(x: &str) -> Vec<String> {
+    x.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Average over the query words of how well each matches its closest word
+/// in the haystack. Substrings count as exact matches so that partial
+/// words still find their track.
+fn score(query: &[String], haystack: &[String]) -> f64 {
+    if query.is_empty() {
+        return 0.0;
+    }
+    let total: f64 = query
+        .iter()
+        .map(|q| {
+            haystack
+                .iter()
+                .map(|h| if h.contains(q.as_str()) {
+                    1.0
+                } else {
+                    jaro_winkler(q, h)
+                })
+                .fold(0.0, f64::max)
+        })
+        .sum();
+    total / query.len() as f64
+}
+
+/// Highest score first. Scores shouldn't be NaN, but if one is it goes
+/// last instead of upsetting the sort.
+fn best_first(a: f64, b: f64) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (false, false) => b.partial_cmp(&a).unwrap_or(Ordering::Equal),
+        (a_nan, b_nan) => a_nan.cmp(&b_nan),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores_sort_best_first_with_nan_last() {
+        let mut scores = vec![0.9, ::std::f64::NAN, 1.0, 0.86, ::std::f64::NAN, 0.95];
+        scores.sort_by(|a, b| best_first(*a, *b));
+        assert_eq!(&scores[..4], &[1.0, 0.95, 0.9, 0.86]);
+        assert!(scores[4].is_nan() && scores[5].is_nan());
+    }
+
+    #[test]
+    fn partial_words_score_as_matches() {
+        let haystack = words("Daft Punk - One More Time.flac");
+        assert_eq!(score(&words("daft"), &haystack), 1.0);
+        assert_eq!(score(&words("punk one"), &haystack), 1.0);
+        assert!(score(&words("dfat"), &haystack) < 1.0);
+        assert_eq!(score(&[], &haystack), 0.0);
+    }
+}
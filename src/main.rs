@@ -4,6 +4,7 @@
 
 #[macro_use]
 extern crate lazy_static;
+#[macro_use]
 extern crate serde_json;
 extern crate hyper;
 extern crate hyper_native_tls;
@@ -16,26 +17,57 @@ extern crate mpv;
 extern crate app_dirs;
 extern crate app_setup;
 extern crate ini;
+extern crate dbus;
+extern crate metaflac;
+extern crate id3;
+extern crate strsim;
+extern crate url;
+extern crate rusqlite;
 
 mod youtube;
 mod download;
 mod command;
 mod backend;
 mod player;
+mod parser;
+mod error;
+mod daemon;
+mod rpc;
+mod mpd;
+mod mpris;
+mod ytdl;
+mod local;
+mod tags;
+mod link;
+mod radio;
+mod playlist_file;
+mod history;
+mod library;
+mod smart;
 
 use command::CommandCenter;
-use backend::MasterBackend;
+use backend::{BackendConfig, BackendMode, BackendType, MasterBackend};
 use download::Downloader;
 use player::AudioPlayer;
+use radio::Radio;
+use library::Library;
 
 use app_dirs::*;
-use app_setup::appsetup;
+use app_setup::appsetup_with_optional;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 use ini::Ini;
 
 use std::io::stdout;
 use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 const SURGE_APP_INFO: AppInfo = AppInfo {
     name: "surge",
@@ -43,45 +75,249 @@ const SURGE_APP_INFO: AppInfo = AppInfo {
 };
 const SURGE_PROMPT: &'static str = "surge ♫ ";
 const SURGE_CONF: &'static str = "surge.ini";
+const DEFAULT_SEARCH_TIMEOUT_SECS: u64 = 15;
+/// The YouTube api's own default page size
+const DEFAULT_MAX_RESULTS: usize = 5;
+const DEFAULT_RADIO_DEPTH: usize = 2;
+const MPD_DEFAULT_BIND: &'static str = "127.0.0.1";
 
 fn main() {
-    let out = stdout();
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    let socket_path = daemon::socket_path();
 
-    let mut history_path = get_app_root(AppDataType::UserCache, &SURGE_APP_INFO)
-        .expect("Couldn't get user cache dir");
-    history_path.push("history.txt");
+    match args.first().map(String::as_str) {
+        Some("daemon") => {
+            let config = load_config();
+            let cmd = Arc::new(Mutex::new(command_center(&config)));
+            spawn_mpris(&cmd);
+            spawn_radio(&cmd);
+            spawn_history(&cmd);
+            if let Some(addr) = mpd_address(&config) {
+                let cmd = cmd.clone();
+                thread::spawn(move || if let Err(e) = mpd::serve(&addr, cmd) {
+                    println!("{0}", e);
+                });
+            }
+            if let Err(e) = daemon::serve(&socket_path, cmd) {
+                println!("{0}", e);
+                process::exit(1);
+            }
+        }
+        Some(_) => {
+            // one-shot mode, e.g. `surge queue 2` from a script or keybinding
+            let out = stdout();
+            let res = daemon::Client::connect(&socket_path).and_then(|mut client| {
+                client.send(&args.join(" "), &mut out.lock())
+            });
+            if let Err(e) = res {
+                println!("Couldn't talk to the surge daemon: {0}", e);
+                process::exit(1);
+            }
+        }
+        None => {
+            match daemon::Client::connect(&socket_path) {
+                Ok(mut client) => {
+                    repl(|line| match client.send(line, &mut stdout().lock()) {
+                        Ok(_) => true,
+                        Err(e) => {
+                            println!("{0}", e);
+                            false
+                        }
+                    })
+                }
+                Err(_) => {
+                    let cmd = Arc::new(Mutex::new(command_center(&load_config())));
+                    spawn_mpris(&cmd);
+                    spawn_radio(&cmd);
+                    spawn_history(&cmd);
+                    repl(|line| {
+                        cmd.lock()
+                            .expect("CommandCenter poisoned")
+                            .handle_command(line, &mut stdout().lock());
+                        true
+                    });
+                    let res = cmd.lock().expect("CommandCenter poisoned").stop();
+                    if let Err(e) = res {
+                        println!("{0}", e);
+                    }
+                }
+            }
+        }
+    }
+}
 
-    let desired_config: HashMap<Option<&str>, Vec<&str>> = [(Some("global"), vec!["yt_api_key"])]
-        .iter()
-        .cloned()
-        .collect();
+fn load_config() -> Ini {
+    let desired_config: HashMap<Option<&str>, Vec<&str>> =
+        [(Some("global"), vec!["backend", "yt_api_key"])]
+            .iter()
+            .cloned()
+            .collect();
 
     let mut conf_file_path = get_app_root(AppDataType::UserConfig, &SURGE_APP_INFO)
         .expect("Couldn't get user config dir");
     conf_file_path.push(SURGE_CONF);
 
-    let config = if conf_file_path.exists() {
+    if conf_file_path.exists() {
         Ini::load_from_file(conf_file_path).expect("Couldn't load ini from config file")
     } else {
-        appsetup(&SURGE_APP_INFO, desired_config, SURGE_CONF)
+        appsetup_with_optional(
+            &SURGE_APP_INFO,
+            desired_config,
+            &["backend", "yt_api_key"],
+            SURGE_CONF,
+        )
+    }
+}
+
+fn command_center(config: &Ini) -> CommandCenter {
+    let global = config.section(Some("global"));
+    let setting = |key: &str| global.and_then(|x| x.get(key)).map(String::as_str);
+
+    let yt_api_key = setting("yt_api_key");
+    // keyless users get the youtube-dl backend unless they ask otherwise
+    let btypes = match setting("backend") {
+        Some(x) => x.split(',').map(|x| config_value("backend", x)).collect(),
+        None if yt_api_key.is_some() => vec![BackendType::Youtube],
+        None => vec![BackendType::Ytdl],
     };
+    let mode = setting("backend_mode")
+        .map(|x| config_value::<BackendMode>("backend_mode", x))
+        .unwrap_or(BackendMode::Merge);
+    let timeout = setting("search_timeout")
+        .map(|x| config_value::<u64>("search_timeout", x))
+        .unwrap_or(DEFAULT_SEARCH_TIMEOUT_SECS);
+    let max_results = setting("max_results")
+        .map(|x| config_value::<usize>("max_results", x))
+        .unwrap_or(DEFAULT_MAX_RESULTS)
+        .max(1);
+    let radio_depth = setting("radio_depth")
+        .map(|x| config_value::<usize>("radio_depth", x))
+        .unwrap_or(DEFAULT_RADIO_DEPTH)
+        .max(1);
 
-    let yt_api_key = config
-        .section(Some("global"))
-        .expect("Couldn't get None section of ini file")
-        .get("yt_api_key")
-        .expect("Missing yt_api_key config");
-
-    let mut backend = MasterBackend::new(yt_api_key);
-    let mut player = AudioPlayer::new();
-    let mut dloader = Downloader::new(
-        app_dir(AppDataType::UserData, &SURGE_APP_INFO, "music")
-            .expect("Couldn't get user data dir"),
+    let music_dir = app_dir(AppDataType::UserData, &SURGE_APP_INFO, "music")
+        .expect("Couldn't get user data dir");
+    let ytdl_program = ytdl::program(setting("ytdl_program"));
+
+    let backend = match MasterBackend::new(BackendConfig {
+        btypes: btypes,
+        mode: mode,
+        yt_api_key: yt_api_key.map(String::from),
+        ytdl_program: ytdl_program.clone(),
+        local_dirs: local_dirs(config, &music_dir),
+        timeout: Duration::from_secs(timeout),
+        max_results: max_results,
+    }) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("{0}", e);
+            process::exit(1);
+        }
+    };
+    let player = AudioPlayer::new().expect("Couldn't create audio player");
+    let dloader = Downloader::new(
+        music_dir,
         app_dir(AppDataType::UserCache, &SURGE_APP_INFO, "thumbnails")
             .expect("Couldn't get user cache dir"),
-    );
+        ytdl_program,
+    ).expect("Couldn't create downloader");
 
-    let mut cmd = CommandCenter::new(out.lock(), &mut player, &mut dloader, &mut backend);
+    let data_dir = app_root(AppDataType::UserData, &SURGE_APP_INFO)
+        .expect("Couldn't get user data dir");
+    let library = match Library::open(&data_dir) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("{0}", e);
+            process::exit(1);
+        }
+    };
+
+    // so the radio doesn't repeat what was played before a restart; a
+    // broken history shouldn't keep surge from starting
+    let mut radio = Radio::new(radio_depth);
+    let recent = library.plays(0, radio::RECENT_TRACKS).unwrap_or_default();
+    for play in recent.iter().rev() {
+        radio.remember(&play.track);
+    }
+
+    CommandCenter::new(player, dloader, backend, radio, library)
+}
+
+/// A setting of surge.ini. One that doesn't parse is reported and ends
+/// surge, rather than being ignored.
+fn config_value<T>(key: &str, value: &str) -> T
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match value.trim().parse() {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Invalid {0} '{1}' in {2}: {3}", key, value.trim(), SURGE_CONF, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// surge's own download dir, plus the `:`-separated `dirs` of the `[local]`
+/// section.
+fn local_dirs(config: &Ini, music_dir: &PathBuf) -> Vec<PathBuf> {
+    let mut dirs = vec![music_dir.clone()];
+    let configured = config.section(Some("local")).and_then(|x| x.get("dirs"));
+    if let Some(x) = configured {
+        for dir in x.split(':').map(str::trim).filter(|x| !x.is_empty()) {
+            dirs.push(match (dir.starts_with("~/"), env::home_dir()) {
+                (true, Some(mut home)) => {
+                    home.push(&dir[2..]);
+                    home
+                }
+                _ => PathBuf::from(dir),
+            });
+        }
+    }
+    dirs
+}
+
+fn spawn_radio(cmd: &Arc<Mutex<CommandCenter>>) {
+    let cmd = cmd.clone();
+    thread::spawn(move || radio::serve(cmd));
+}
+
+fn spawn_history(cmd: &Arc<Mutex<CommandCenter>>) {
+    let cmd = cmd.clone();
+    thread::spawn(move || history::serve(cmd));
+}
+
+/// Media keys are a nicety, so running without a session bus only gets a
+/// warning.
+fn spawn_mpris(cmd: &Arc<Mutex<CommandCenter>>) {
+    let cmd = cmd.clone();
+    thread::spawn(move || if let Err(e) = mpris::serve(cmd) {
+        println!("MPRIS unavailable: {0}", e);
+    });
+}
+
+/// The MPD server is opt-in: it only runs when surge.ini has an `[mpd]`
+/// section with a `port`.
+fn mpd_address(config: &Ini) -> Option<String> {
+    let section = match config.section(Some("mpd")) {
+        Some(x) => x,
+        None => return None,
+    };
+    section.get("port").map(|port| {
+        let bind = section.get("bind").map(String::as_str).unwrap_or(
+            MPD_DEFAULT_BIND,
+        );
+        format!("{0}:{1}", bind, port.trim())
+    })
+}
+
+/// Readline loop shared by the standalone player and the daemon client;
+/// `handle` returns false to end the session.
+fn repl<F: FnMut(&str) -> bool>(mut handle: F) {
+    let mut history_path = get_app_root(AppDataType::UserCache, &SURGE_APP_INFO)
+        .expect("Couldn't get user cache dir");
+    history_path.push("history.txt");
 
     let mut rl = Editor::<()>::new();
     if rl.load_history(&history_path).is_err() {
@@ -92,16 +328,17 @@ fn main() {
         match readline {
             Ok(line) => {
                 rl.add_history_entry(&line);
-                cmd.handle_command(&line);
-                continue;
+                if handle(&line) {
+                    continue;
+                }
+                break;
             }
             Err(ReadlineError::Interrupted) => continue,
             Err(ReadlineError::Eof) |
-            Err(_) => {
-                cmd.stop();
-                break;
-            }
+            Err(_) => break,
         }
     }
-    rl.save_history(&history_path).unwrap();
+    if let Err(e) = rl.save_history(&history_path) {
+        println!("Couldn't save history: {0}", e);
+    }
 }
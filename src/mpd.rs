@@ -0,0 +1,650 @@
+//! A subset of the MPD protocol, enough for ncmpcpp and the usual mobile
+//! clients to browse, queue and control playback.
+//!
+//! Songs are addressed by their position in the queue, which doubles as
+//! their MPD song id.
+
+use command::CommandCenter;
+use error::{Result, SurgeError};
+use player::{PlayerEvent, QueueEntry};
+
+use std::collections::BTreeSet;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::result;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+
+const MPD_GREETING: &'static str = "OK MPD 0.19.0";
+const IDLE_POLL_MS: u64 = 100;
+
+const ACK_ERROR_ARG: u32 = 2;
+const ACK_ERROR_UNKNOWN: u32 = 5;
+const ACK_ERROR_NO_EXIST: u32 = 50;
+const ACK_ERROR_SYSTEM: u32 = 52;
+
+const COMMANDS: &'static [&'static str] = &[
+    "add",
+    "clear",
+    "close",
+    "command_list_begin",
+    "command_list_end",
+    "command_list_ok_begin",
+    "commands",
+    "currentsong",
+    "delete",
+    "find",
+    "idle",
+    "move",
+    "next",
+    "noidle",
+    "pause",
+    "ping",
+    "play",
+    "playid",
+    "playlistid",
+    "playlistinfo",
+    "plchanges",
+    "previous",
+    "search",
+    "setvol",
+    "status",
+    "stop",
+];
+
+/// Commands clients send on startup that surge has nothing to report for.
+const EMPTY_COMMANDS: &'static [&'static str] = &[
+    "decoders",
+    "list",
+    "listplaylists",
+    "lsinfo",
+    "outputs",
+    "tagtypes",
+    "urlhandlers",
+];
+
+struct Ack {
+    code: u32,
+    message: String,
+}
+
+impl Ack {
+    fn new(code: u32, message: &str) -> Ack {
+        Ack {
+            code: code,
+            message: String::from(message),
+        }
+    }
+}
+
+impl From<SurgeError> for Ack {
+    fn from(e: SurgeError) -> Ack {
+        let code = match e {
+            SurgeError::Usage(_) => ACK_ERROR_ARG,
+            _ => ACK_ERROR_SYSTEM,
+        };
+        Ack::new(code, &e.to_string())
+    }
+}
+
+pub fn serve(addr: &str, cmd: Arc<Mutex<CommandCenter>>) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    println!("MPD server listening on {0}", addr);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(x) => x,
+            Err(e) => {
+                println!("Couldn't accept MPD client: {0}", e);
+                continue;
+            }
+        };
+        let cmd = cmd.clone();
+        thread::spawn(move || if let Err(e) = handle_client(stream, cmd) {
+            println!("MPD client disconnected: {0}", e);
+        });
+    }
+    Ok(())
+}
+
+struct Session {
+    cmd: Arc<Mutex<CommandCenter>>,
+    events: Receiver<PlayerEvent>,
+    changed: BTreeSet<&'static str>,
+    queue_version: u32,
+}
+
+fn handle_client(stream: TcpStream, cmd: Arc<Mutex<CommandCenter>>) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    writeln!(writer, "{0}", MPD_GREETING)?;
+
+    let (events, queue_version) = {
+        let mut cmd = cmd.lock().expect("CommandCenter poisoned");
+        (cmd.subscribe(), cmd.player().queue_version())
+    };
+    let mut session = Session {
+        cmd: cmd,
+        events: events,
+        changed: BTreeSet::new(),
+        queue_version: queue_version,
+    };
+
+    // (list_OK after every command, commands) while inside a command list
+    let mut list: Option<(bool, Vec<String>)> = None;
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        if reader.read_line(&mut buf)? == 0 {
+            return Ok(());
+        }
+        let line = String::from(buf.trim_right_matches(|c: char| c == '\n' || c == '\r'));
+
+        if let Some((list_ok, mut cmds)) = list.take() {
+            if line == "command_list_end" {
+                let resp = session.execute_all(&cmds, list_ok);
+                writer.write_all(&resp)?;
+            } else {
+                cmds.push(line);
+                list = Some((list_ok, cmds));
+            }
+            continue;
+        }
+
+        match line.as_str() {
+            "command_list_begin" => list = Some((false, vec![])),
+            "command_list_ok_begin" => list = Some((true, vec![])),
+            "close" => return Ok(()),
+            "noidle" => (),
+            x if x == "idle" || x.starts_with("idle ") => {
+                let subsystems = match tokenize(x) {
+                    Ok(args) => args[1..].to_vec(),
+                    Err(_) => vec![],
+                };
+                if !session.idle(&subsystems, &mut reader, &mut writer)? {
+                    return Ok(());
+                }
+            }
+            _ => {
+                let resp = session.execute_all(&[line.clone()], false);
+                writer.write_all(&resp)?;
+            }
+        }
+    }
+}
+
+impl Session {
+    /// Run a command, or a whole command list, stopping at the first error
+    /// like MPD does.
+    fn execute_all(&mut self, cmds: &[String], list_ok: bool) -> Vec<u8> {
+        let mut resp = vec![];
+        for (i, line) in cmds.iter().enumerate() {
+            let mut out = vec![];
+            let res = tokenize(line).and_then(|args| if args.is_empty() {
+                Err(Ack::new(ACK_ERROR_UNKNOWN, "No command given"))
+            } else {
+                self.execute(&args, &mut out)
+            });
+            match res {
+                Ok(_) => {
+                    resp.append(&mut out);
+                    if list_ok {
+                        resp.extend_from_slice(b"list_OK\n");
+                    }
+                }
+                Err(e) => {
+                    let name = line.split_whitespace().next().unwrap_or("");
+                    resp.extend_from_slice(
+                        format!("ACK [{0}@{1}] {{{2}}} {3}\n", e.code, i, name, e.message)
+                            .as_bytes(),
+                    );
+                    return resp;
+                }
+            }
+        }
+        resp.extend_from_slice(b"OK\n");
+        resp
+    }
+
+    fn execute(&mut self, args: &[String], out: &mut Vec<u8>) -> result::Result<(), Ack> {
+        let name = args[0].as_str();
+        let arg = |i: usize| args.get(i).map(String::as_str);
+
+        if EMPTY_COMMANDS.contains(&name) {
+            return Ok(());
+        }
+
+        let mut cmd = self.cmd.lock().expect("CommandCenter poisoned");
+        match name {
+            "ping" => (),
+            "commands" => {
+                for x in COMMANDS {
+                    writeln!(out, "command: {0}", x).expect("Write to Vec failed");
+                }
+            }
+            "status" => {
+                let player = cmd.player();
+                let (entries, pos) = player.queue_entries();
+                let state = match pos {
+                    None => "stop",
+                    Some(_) if player.stopped() => "stop",
+                    Some(_) if player.paused().unwrap_or(false) => "pause",
+                    Some(_) => "play",
+                };
+                write_pairs(
+                    out,
+                    &[
+                        ("volume", player.volume().unwrap_or(-1).to_string()),
+                        ("repeat", bool_flag(player.looping().unwrap_or(false))),
+                        ("random", String::from("0")),
+                        ("single", String::from("0")),
+                        ("consume", String::from("0")),
+                        ("playlist", player.queue_version().to_string()),
+                        ("playlistlength", entries.len().to_string()),
+                        ("state", String::from(state)),
+                    ],
+                );
+                if let Some(pos) = pos {
+                    write_pairs(
+                        out,
+                        &[("song", pos.to_string()), ("songid", pos.to_string())],
+                    );
+                    if let (Ok(elapsed), Ok(duration)) = (player.time_pos(), player.duration()) {
+                        write_pairs(
+                            out,
+                            &[
+                                (
+                                    "time",
+                                    format!("{0}:{1}", elapsed as u64, duration as u64),
+                                ),
+                                ("elapsed", format!("{0:.3}", elapsed)),
+                                ("duration", format!("{0:.3}", duration)),
+                            ],
+                        );
+                    }
+                }
+            }
+            "currentsong" => {
+                let (entries, pos) = cmd.player().queue_entries();
+                if let Some(pos) = pos {
+                    write_song(out, &entries[pos], pos);
+                }
+            }
+            "playlistinfo" | "playlistid" | "plchanges" => {
+                let (entries, _) = cmd.player().queue_entries();
+                // plchanges gets the whole queue, which is always correct if
+                // not minimal
+                let only = if name == "plchanges" {
+                    None
+                } else {
+                    parse_opt_index(arg(1))?
+                };
+                for (i, x) in entries.iter().enumerate() {
+                    if only.is_none() || only == Some(i) {
+                        write_song(out, x, i);
+                    }
+                }
+                if let Some(i) = only {
+                    if i >= entries.len() {
+                        return Err(Ack::new(ACK_ERROR_NO_EXIST, "Bad song index"));
+                    }
+                }
+            }
+            "add" => {
+                let id = match arg(1) {
+                    Some(x) => x,
+                    None => return Err(Ack::new(ACK_ERROR_ARG, "Missing uri")),
+                };
+                let track = cmd.find_track(id);
+                cmd.queue_later(track)?;
+            }
+            "play" | "playid" => {
+                if let Some(pos) = parse_opt_index(arg(1))? {
+                    if !cmd.player().jump(pos)? {
+                        return Err(Ack::new(ACK_ERROR_NO_EXIST, "Bad song index"));
+                    }
+                }
+                cmd.play(None)?;
+            }
+            "pause" => {
+                let pause = match arg(1) {
+                    Some("1") => true,
+                    Some("0") => false,
+                    Some(_) => return Err(Ack::new(ACK_ERROR_ARG, "Expected 0 or 1")),
+                    None => !cmd.player().paused()?,
+                };
+                if pause {
+                    cmd.pause()?;
+                } else {
+                    cmd.play(None)?;
+                }
+            }
+            "next" => {
+                cmd.player().skip()?;
+            }
+            "previous" => {
+                cmd.player().prev()?;
+            }
+            "stop" => cmd.halt()?,
+            "clear" => cmd.stop()?,
+            "delete" => {
+                let pos = parse_index(arg(1))?;
+                if !cmd.player().remove_entry(pos)? {
+                    return Err(Ack::new(ACK_ERROR_NO_EXIST, "Bad song index"));
+                }
+            }
+            "move" => {
+                let (from, to) = (parse_index(arg(1))?, parse_index(arg(2))?);
+                if !cmd.player().move_entry(from, to)? {
+                    return Err(Ack::new(ACK_ERROR_NO_EXIST, "Bad song index"));
+                }
+            }
+            "search" | "find" => {
+                let query = search_query(&args[1..]);
+                if query.trim().is_empty() {
+                    return Err(Ack::new(ACK_ERROR_ARG, "Missing search terms"));
+                }
+                for x in cmd.search(&query)? {
+                    write_pairs(
+                        out,
+                        &[("file", x.id.clone()), ("Title", x.title.clone())],
+                    );
+                }
+            }
+            "setvol" => {
+                let volume = match arg(1).and_then(|x| x.parse::<i64>().ok()) {
+                    Some(x) if x >= 0 && x <= 100 => x,
+                    _ => return Err(Ack::new(ACK_ERROR_ARG, "Invalid volume value")),
+                };
+                cmd.player().set_volume(volume)?;
+            }
+            _ => {
+                return Err(Ack::new(
+                    ACK_ERROR_UNKNOWN,
+                    &format!("unknown command \"{0}\"", name),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn collect_events(&mut self) {
+        while let Ok(_) = self.events.try_recv() {
+            self.changed.insert("player");
+        }
+        let version = self.cmd
+            .lock()
+            .expect("CommandCenter poisoned")
+            .player()
+            .queue_version();
+        if version != self.queue_version {
+            self.queue_version = version;
+            self.changed.insert("playlist");
+        }
+    }
+
+    /// Block until one of `subsystems` (or any, if empty) changes or the
+    /// client sends `noidle`. Returns false if the client went away.
+    fn idle(
+        &mut self,
+        subsystems: &[String],
+        reader: &mut BufReader<TcpStream>,
+        writer: &mut TcpStream,
+    ) -> Result<bool> {
+        let wanted = |x: &str| subsystems.is_empty() || subsystems.iter().any(|s| s == x);
+
+        reader.get_ref().set_read_timeout(
+            Some(Duration::from_millis(IDLE_POLL_MS)),
+        )?;
+        let mut buf = String::new();
+        let mut connected = true;
+        loop {
+            self.collect_events();
+            if self.changed.iter().any(|x| wanted(*x)) {
+                break;
+            }
+            match reader.read_line(&mut buf) {
+                Ok(0) => {
+                    connected = false;
+                    break;
+                }
+                Ok(_) => {
+                    if buf.trim() == "noidle" {
+                        break;
+                    }
+                    buf.clear();
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                                  e.kind() == ErrorKind::TimedOut => (),
+                Err(e) => return Err(SurgeError::from(e)),
+            }
+        }
+        reader.get_ref().set_read_timeout(None)?;
+        if !connected {
+            return Ok(false);
+        }
+
+        let reported = self.changed
+            .iter()
+            .cloned()
+            .filter(|x| wanted(*x))
+            .collect::<Vec<_>>();
+        for x in reported {
+            writeln!(writer, "changed: {0}", x)?;
+            self.changed.remove(x);
+        }
+        writeln!(writer, "OK")?;
+        Ok(true)
+    }
+}
+
+/// Split a command line into arguments, honouring MPD's double quotes and
+/// backslash escapes.
+fn tokenize(line: &str) -> result::Result<Vec<String>, Ack> {
+    let mut ret = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        while let Some(&c) = chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            chars.next();
+        }
+        let quoted = match chars.peek() {
+            Some(&c) => c == '"',
+            None => break,
+        };
+
+        let mut tok = String::new();
+        if quoted {
+            chars.next();
+            let mut closed = false;
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some(escaped) = chars.next() {
+                            tok.push(escaped);
+                        }
+                    }
+                    '"' => {
+                        closed = true;
+                        break;
+                    }
+                    _ => tok.push(c),
+                }
+            }
+            if !closed {
+                return Err(Ack::new(ACK_ERROR_ARG, "Missing closing '\"'"));
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                tok.push(c);
+                chars.next();
+            }
+        }
+        ret.push(tok);
+    }
+    Ok(ret)
+}
+
+/// Both the old `search <tag> <what> ...` form and a single filter
+/// expression like `(any contains 'lofi')` are flattened to plain terms,
+/// since the backends only do free text search.
+fn search_query(args: &[String]) -> String {
+    if args.len() == 1 {
+        let x = args[0].trim();
+        if x.starts_with('(') {
+            return x.split(|c: char| c == '\'' || c == '"')
+                .nth(1)
+                .unwrap_or("")
+                .to_string();
+        }
+        return String::from(x);
+    }
+    args.chunks(2)
+        .filter_map(|pair| pair.get(1))
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn parse_index(arg: Option<&str>) -> result::Result<usize, Ack> {
+    match parse_opt_index(arg)? {
+        Some(x) => Ok(x),
+        None => Err(Ack::new(ACK_ERROR_ARG, "Missing song position")),
+    }
+}
+
+fn parse_opt_index(arg: Option<&str>) -> result::Result<Option<usize>, Ack> {
+    match arg {
+        // a range like "3:4" is answered with its start
+        Some(x) => {
+            match x.split(':').next().unwrap_or(x).parse() {
+                Ok(x) => Ok(Some(x)),
+                Err(_) => Err(Ack::new(ACK_ERROR_ARG, "Expected a song position")),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+fn bool_flag(x: bool) -> String {
+    String::from(if x { "1" } else { "0" })
+}
+
+fn write_pairs(out: &mut Vec<u8>, pairs: &[(&str, String)]) {
+    for &(ref k, ref v) in pairs {
+        // keep values from breaking the line-based protocol
+        writeln!(out, "{0}: {1}", k, v.replace('\n', " ")).expect("Write to Vec failed");
+    }
+}
+
+fn write_song(out: &mut Vec<u8>, x: &QueueEntry, pos: usize) {
+    write_pairs(
+        out,
+        &[
+            ("file", x.url.clone()),
+            ("Title", x.track.title.clone()),
+            ("Pos", pos.to_string()),
+            ("Id", pos.to_string()),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{BackendType, ResultKind};
+    use command::link_result;
+
+    fn tokens(line: &str) -> Vec<String> {
+        match tokenize(line) {
+            Ok(x) => x,
+            Err(e) => panic!("'{0}' gave {1}", line, e.message),
+        }
+    }
+
+    fn strings(xs: &[&str]) -> Vec<String> {
+        xs.iter().map(|x| String::from(*x)).collect()
+    }
+
+    #[test]
+    fn splits_on_whitespace() {
+        assert_eq!(tokens("status"), strings(&["status"]));
+        assert_eq!(tokens("  playid \t 3  "), strings(&["playid", "3"]));
+        assert!(tokens("   ").is_empty());
+    }
+
+    #[test]
+    fn keeps_quoted_tokens_whole() {
+        assert_eq!(
+            tokens("search any \"daft punk\""),
+            strings(&["search", "any", "daft punk"])
+        );
+        assert_eq!(tokens("find \"\""), strings(&["find", ""]));
+        assert_eq!(tokens("a\"b c"), strings(&["a\"b", "c"]));
+    }
+
+    #[test]
+    fn unescapes_quoted_tokens() {
+        assert_eq!(
+            tokens(r#"search any "say \"hi\" \\o/""#),
+            strings(&["search", "any", r#"say "hi" \o/"#])
+        );
+        assert_eq!(
+            tokens(r#"search "(any contains 'don\\'t')""#),
+            strings(&["search", r#"(any contains 'don\'t')"#])
+        );
+    }
+
+    #[test]
+    fn rejects_unclosed_quotes() {
+        for line in &["search \"daft punk", "search \"daft\\\""] {
+            match tokenize(line) {
+                Err(e) => {
+                    assert_eq!(e.code, ACK_ERROR_ARG);
+                    assert_eq!(e.message, "Missing closing '\"'");
+                }
+                Ok(x) => panic!("'{0}' gave {1:?}", line, x),
+            }
+        }
+    }
+
+    #[test]
+    fn flattens_searches() {
+        assert_eq!(search_query(&strings(&["any", "lofi"])), "lofi");
+        assert_eq!(
+            search_query(&strings(&["artist", "Daft Punk", "title", "One More Time"])),
+            "Daft Punk One More Time"
+        );
+        assert_eq!(search_query(&strings(&["(any contains 'lofi beats')"])), "lofi beats");
+        assert_eq!(search_query(&strings(&["(title == \"Around\")"])), "Around");
+        assert_eq!(search_query(&strings(&[" lofi "])), "lofi");
+        assert_eq!(search_query(&strings(&["artist"])), "artist");
+        assert_eq!(search_query(&[]), "");
+    }
+
+    #[test]
+    fn adds_urls_as_what_they_link_to() {
+        let args = tokens("add \"https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=42\"");
+        let x = link_result(&args[1]);
+        assert_eq!((x.id.as_str(), x.source), ("dQw4w9WgXcQ", BackendType::Youtube));
+        assert_eq!(x.kind, ResultKind::Track);
+
+        let x = link_result(&tokens("add http://radio.example.com:8000/live")[1]);
+        assert_eq!(x.id, "http://radio.example.com:8000/live");
+        assert_eq!(x.source, BackendType::Stream);
+
+        let x = link_result(&tokens("add https://youtube.com/playlist?list=PL123")[1]);
+        assert_eq!((x.id.as_str(), x.kind), ("PL123", ResultKind::Playlist));
+
+        let x = link_result(&tokens("add file:///surely/not/there.flac")[1]);
+        assert_eq!(x.id, "/surely/not/there.flac");
+        assert_eq!(x.source, BackendType::Local);
+    }
+}
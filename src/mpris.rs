@@ -0,0 +1,443 @@
+//! MPRIS2 on the session bus, so media keys and desktop widgets can see and
+//! control surge.
+
+use dbus::{BusType, Connection, Message, MessageItem, NameFlag, Path};
+use dbus::arg::{Append, Arg, Variant};
+use dbus::tree::{Access, EmitsChangedSignal, Factory, Interface, Method, MethodErr, MTFn};
+use dbus::tree::Property;
+
+use backend::BackendSearchResult;
+use command::CommandCenter;
+use error::Result;
+use player::PlayerEvent;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+const BUS_NAME: &'static str = "org.mpris.MediaPlayer2.surge";
+const OBJECT_PATH: &'static str = "/org/mpris/MediaPlayer2";
+const ROOT_IFACE: &'static str = "org.mpris.MediaPlayer2";
+const PLAYER_IFACE: &'static str = "org.mpris.MediaPlayer2.Player";
+const PROPERTIES_IFACE: &'static str = "org.freedesktop.DBus.Properties";
+const TRACK_PATH: &'static str = "/org/surge/track";
+const POLL_MS: i32 = 100;
+
+/// What a PropertiesChanged signal carries for each kind of player event
+const TRACK_PROPERTIES: &'static [&'static str] = &["PlaybackStatus", "Metadata"];
+const STATUS_PROPERTIES: &'static [&'static str] = &["PlaybackStatus"];
+/// What changes once a thumbnail has been fetched
+const METADATA_PROPERTIES: &'static [&'static str] = &["Metadata"];
+
+type Cmd = Arc<Mutex<CommandCenter>>;
+type Iface = Interface<MTFn<()>, ()>;
+type Prop = Property<MTFn<()>, ()>;
+
+/// Publish surge on the session bus and answer MPRIS calls until the bus
+/// goes away.
+pub fn serve(cmd: Cmd) -> Result<()> {
+    let conn = Connection::get_private(BusType::Session)?;
+    conn.register_name(BUS_NAME, NameFlag::DoNotQueue as u32)?;
+
+    let f = Factory::new_fn::<()>();
+    let tree = f.tree(()).add(
+        f.object_path(OBJECT_PATH, ())
+            .introspectable()
+            .add(root_interface(&f))
+            .add(player_interface(&f, &cmd)),
+    );
+    tree.set_registered(&conn, true)?;
+
+    let events = cmd.lock().expect("CommandCenter poisoned").subscribe();
+    let (fetched_tx, fetched) = channel();
+    for _ in tree.run(&conn, conn.iter(POLL_MS)) {
+        let mut changed = vec![];
+        while let Ok(ev) = events.try_recv() {
+            if let PlayerEvent::TrackChanged(ref x) = ev {
+                fetch_thumbnail(&cmd, x, &fetched_tx);
+            }
+            changed.extend(changed_properties(&ev));
+        }
+        while let Ok(()) = fetched.try_recv() {
+            changed.push(METADATA_PROPERTIES);
+        }
+        for names in changed {
+            let msg = properties_changed(
+                &mut cmd.lock().expect("CommandCenter poisoned"),
+                names,
+            );
+            // nobody to tell if the bus hung up; run() ends on its own
+            let _ = conn.send(msg);
+        }
+    }
+    Ok(())
+}
+
+/// The properties a PropertiesChanged signal should carry for `ev`, if any.
+fn changed_properties(ev: &PlayerEvent) -> Option<&'static [&'static str]> {
+    match *ev {
+        PlayerEvent::TrackChanged(_) |
+        PlayerEvent::QueueFinished |
+        PlayerEvent::Stopped => Some(TRACK_PROPERTIES),
+        PlayerEvent::Paused |
+        PlayerEvent::Resumed => Some(STATUS_PROPERTIES),
+        PlayerEvent::TrackFinished(_) |
+        PlayerEvent::TrackFailed(_) => None,
+    }
+}
+
+/// Download the thumbnail of `track` on a thread of its own unless it's
+/// cached already, so neither the bus nor the `CommandCenter` waits on it.
+/// `done` hears once it's there to show in the metadata.
+fn fetch_thumbnail(cmd: &Cmd, track: &BackendSearchResult, done: &Sender<()>) {
+    let downloader = {
+        let c = cmd.lock().expect("CommandCenter poisoned");
+        if track.thumbnail.is_none() || c.cached_thumbnail(track).is_some() {
+            return;
+        }
+        c.downloader()
+    };
+    let track = track.clone();
+    let done = done.clone();
+    thread::spawn(move || {
+        let url = track.thumbnail.as_ref().map(String::as_str);
+        match downloader.download_thumbnail(url, &track.id) {
+            Ok(_) => {
+                let _ = done.send(());
+            }
+            Err(e) => println!("Couldn't fetch thumbnail of {0}: {1}", track.title, e),
+        }
+    });
+}
+
+fn root_interface(f: &Factory<MTFn<()>, ()>) -> Iface {
+    f.interface(ROOT_IFACE, ())
+        .add_m(f.method("Raise", (), |m| Ok(vec![m.msg.method_return()])))
+        .add_m(f.method("Quit", (), |m| Ok(vec![m.msg.method_return()])))
+        .add_p(constant(f, "CanQuit", false))
+        .add_p(constant(f, "CanRaise", false))
+        .add_p(constant(f, "HasTrackList", false))
+        .add_p(constant(f, "Identity", String::from("surge")))
+        .add_p(constant(f, "SupportedUriSchemes", Vec::<String>::new()))
+        .add_p(constant(f, "SupportedMimeTypes", Vec::<String>::new()))
+}
+
+fn player_interface(f: &Factory<MTFn<()>, ()>, cmd: &Cmd) -> Iface {
+    let seek_cmd = cmd.clone();
+    let set_position_cmd = cmd.clone();
+    let loop_cmd = cmd.clone();
+    let volume_cmd = cmd.clone();
+
+    f.interface(PLAYER_IFACE, ())
+        .add_m(action(f, "Play", cmd, |c| c.play(None).map(|_| ())))
+        .add_m(action(f, "Pause", cmd, |c| c.pause()))
+        .add_m(action(f, "PlayPause", cmd, |c| {
+            if playback_status(c) == "Playing" {
+                c.pause()
+            } else {
+                c.play(None).map(|_| ())
+            }
+        }))
+        .add_m(action(f, "Stop", cmd, |c| c.halt()))
+        .add_m(action(f, "Next", cmd, |c| c.player().skip().map(|_| ())))
+        .add_m(action(f, "Previous", cmd, |c| c.player().prev().map(|_| ())))
+        .add_m(
+            f.method("Seek", (), move |m| {
+                let offset: i64 = m.msg.read1()?;
+                let mut c = seek_cmd.lock().expect("CommandCenter poisoned");
+                c.player().seek(offset as f64 / 1e6).map_err(
+                    |e| MethodErr::failed(&e),
+                )?;
+                Ok(vec![m.msg.method_return(), seeked(&mut c)])
+            }).inarg::<i64, _>("Offset"),
+        )
+        .add_m(
+            f.method("SetPosition", (), move |m| {
+                let (track_id, pos): (Path, i64) = m.msg.read2()?;
+                let mut c = set_position_cmd.lock().expect("CommandCenter poisoned");
+                // stale requests for a track that's no longer playing are
+                // ignored, as the spec asks
+                if Some(track_id) != current_track_id(&mut c) || pos < 0 {
+                    return Ok(vec![m.msg.method_return()]);
+                }
+                c.player().seek_to(pos as f64 / 1e6).map_err(
+                    |e| MethodErr::failed(&e),
+                )?;
+                Ok(vec![m.msg.method_return(), seeked(&mut c)])
+            }).inarg::<Path, _>("TrackId")
+                .inarg::<i64, _>("Position"),
+        )
+        .add_s(f.signal("Seeked", ()).sarg::<i64, _>("Position"))
+        .add_p(state::<String>(f, "PlaybackStatus", cmd))
+        .add_p(
+            state::<String>(f, "LoopStatus", cmd)
+                .access(Access::ReadWrite)
+                .on_set(move |i, _| {
+                    let status: &str = i.read()?;
+                    let mut c = loop_cmd.lock().expect("CommandCenter poisoned");
+                    let looping = c.player().looping().unwrap_or(false);
+                    if looping != (status == "Track") {
+                        c.toggle_loop().map_err(|e| MethodErr::failed(&e))?;
+                    }
+                    Ok(())
+                }),
+        )
+        .add_p(
+            state::<f64>(f, "Volume", cmd)
+                .access(Access::ReadWrite)
+                .on_set(move |i, _| {
+                    let volume: f64 = i.read()?;
+                    let volume = (volume.max(0.0).min(1.0) * 100.0).round() as i64;
+                    volume_cmd
+                        .lock()
+                        .expect("CommandCenter poisoned")
+                        .player()
+                        .set_volume(volume)
+                        .map_err(|e| MethodErr::failed(&e))
+                }),
+        )
+        .add_p(state::<HashMap<String, Variant<MessageItem>>>(f, "Metadata", cmd))
+        .add_p(state::<i64>(f, "Position", cmd).emits_changed(
+            EmitsChangedSignal::False,
+        ))
+        .add_p(constant(f, "Rate", 1.0))
+        .add_p(constant(f, "MinimumRate", 1.0))
+        .add_p(constant(f, "MaximumRate", 1.0))
+        .add_p(constant(f, "Shuffle", false))
+        .add_p(constant(f, "CanGoNext", true))
+        .add_p(constant(f, "CanGoPrevious", true))
+        .add_p(constant(f, "CanPlay", true))
+        .add_p(constant(f, "CanPause", true))
+        .add_p(constant(f, "CanSeek", true))
+        .add_p(constant(f, "CanControl", true))
+}
+
+/// A method that runs `run` and returns nothing.
+fn action<F>(
+    f: &Factory<MTFn<()>, ()>,
+    name: &'static str,
+    cmd: &Cmd,
+    run: F,
+) -> Method<MTFn<()>, ()>
+where
+    F: Fn(&mut CommandCenter) -> Result<()> + 'static,
+{
+    let cmd = cmd.clone();
+    f.method(name, (), move |m| {
+        run(&mut cmd.lock().expect("CommandCenter poisoned")).map_err(
+            |e| MethodErr::failed(&e),
+        )?;
+        Ok(vec![m.msg.method_return()])
+    })
+}
+
+/// A read-only property backed by `property_value`; `A` only gives the
+/// property its D-Bus signature.
+fn state<A: Arg>(f: &Factory<MTFn<()>, ()>, name: &'static str, cmd: &Cmd) -> Prop {
+    let cmd = cmd.clone();
+    f.property::<A, _>(name, ()).on_get(move |i, _| {
+        i.append(property_value(
+            &mut cmd.lock().expect("CommandCenter poisoned"),
+            name,
+        ));
+        Ok(())
+    })
+}
+
+fn constant<A>(f: &Factory<MTFn<()>, ()>, name: &'static str, value: A) -> Prop
+where
+    A: Arg + Append + Clone + 'static,
+{
+    f.property::<A, _>(name, ()).on_get(move |i, _| {
+        i.append(value.clone());
+        Ok(())
+    })
+}
+
+fn property_value(cmd: &mut CommandCenter, name: &str) -> MessageItem {
+    match name {
+        "PlaybackStatus" => MessageItem::from(playback_status(cmd)),
+        "LoopStatus" => {
+            let looping = cmd.player().looping().unwrap_or(false);
+            MessageItem::from(if looping { "Track" } else { "None" })
+        }
+        "Volume" => MessageItem::Double(cmd.player().volume().unwrap_or(0) as f64 / 100.0),
+        "Position" => MessageItem::Int64(micros(cmd.player().time_pos().unwrap_or(0.0))),
+        "Metadata" => metadata(cmd),
+        _ => unreachable!(),
+    }
+}
+
+fn playback_status(cmd: &mut CommandCenter) -> &'static str {
+    let stopped = cmd.now_playing().is_none() || cmd.player().stopped();
+    status_name(stopped, !stopped && cmd.player().paused().unwrap_or(false))
+}
+
+fn status_name(stopped: bool, paused: bool) -> &'static str {
+    if stopped {
+        "Stopped"
+    } else if paused {
+        "Paused"
+    } else {
+        "Playing"
+    }
+}
+
+fn current_track_id(cmd: &mut CommandCenter) -> Option<Path<'static>> {
+    cmd.player().queue_entries().1.map(track_id)
+}
+
+/// Queue positions make for valid object paths, unlike YouTube ids.
+fn track_id(pos: usize) -> Path<'static> {
+    Path::from(format!("{0}/{1}", TRACK_PATH, pos))
+}
+
+fn metadata(cmd: &mut CommandCenter) -> MessageItem {
+    let items = match (cmd.player().queue_entries().1, cmd.now_playing()) {
+        (Some(pos), Some(track)) => {
+            let duration = cmd.player().duration().ok();
+            let art = cmd.cached_thumbnail(&track);
+            track_metadata(pos, track, duration, art)
+        }
+        _ => vec![],
+    };
+    MessageItem::from_dict(items.into_iter().map(Ok::<_, ()>)).expect("Infallible dict")
+}
+
+/// The metadata entries of `track`, playing at queue position `pos`. Only a
+/// thumbnail that's already on disk makes it in as the art.
+fn track_metadata(
+    pos: usize,
+    track: BackendSearchResult,
+    duration: Option<f64>,
+    art: Option<PathBuf>,
+) -> Vec<(String, MessageItem)> {
+    let mut items = vec![(String::from("mpris:trackid"), MessageItem::from(track_id(pos)))];
+    if let Some(duration) = duration {
+        items.push((
+            String::from("mpris:length"),
+            MessageItem::Int64(micros(duration)),
+        ));
+    }
+    if let Some(path) = art {
+        items.push((
+            String::from("mpris:artUrl"),
+            MessageItem::from(format!("file://{0}", path.display())),
+        ));
+    }
+    items.push((String::from("xesam:title"), MessageItem::from(track.title)));
+    items
+}
+
+fn micros(secs: f64) -> i64 {
+    (secs * 1e6) as i64
+}
+
+fn seeked(cmd: &mut CommandCenter) -> Message {
+    Message::signal(
+        &Path::from(OBJECT_PATH),
+        &PLAYER_IFACE.into(),
+        &"Seeked".into(),
+    ).append1(micros(cmd.player().time_pos().unwrap_or(0.0)))
+}
+
+fn properties_changed(cmd: &mut CommandCenter, names: &[&str]) -> Message {
+    let changed = names
+        .iter()
+        .map(|name| Ok::<_, ()>((String::from(*name), property_value(cmd, name))));
+    Message::signal(
+        &Path::from(OBJECT_PATH),
+        &PROPERTIES_IFACE.into(),
+        &"PropertiesChanged".into(),
+    ).append3(
+        PLAYER_IFACE,
+        MessageItem::from_dict(changed).expect("Infallible dict"),
+        Vec::<String>::new(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::BackendType;
+
+    fn track() -> BackendSearchResult {
+        BackendSearchResult::new(
+            String::from("dQw4w9WgXcQ"),
+            String::from("Never Gonna Give You Up"),
+            BackendType::Youtube,
+        )
+    }
+
+    #[test]
+    fn status_names() {
+        assert_eq!(status_name(true, false), "Stopped");
+        assert_eq!(status_name(true, true), "Stopped");
+        assert_eq!(status_name(false, true), "Paused");
+        assert_eq!(status_name(false, false), "Playing");
+    }
+
+    #[test]
+    fn track_ids_are_queue_positions() {
+        assert_eq!(track_id(3), Path::from("/org/surge/track/3"));
+    }
+
+    #[test]
+    fn metadata_of_a_track() {
+        let items = track_metadata(
+            2,
+            track(),
+            Some(1.5),
+            Some(PathBuf::from("/tmp/thumbs/x.jpg")),
+        );
+        assert_eq!(
+            items,
+            vec![
+                (
+                    String::from("mpris:trackid"),
+                    MessageItem::from(Path::from("/org/surge/track/2")),
+                ),
+                (String::from("mpris:length"), MessageItem::Int64(1500000)),
+                (
+                    String::from("mpris:artUrl"),
+                    MessageItem::from("file:///tmp/thumbs/x.jpg"),
+                ),
+                (
+                    String::from("xesam:title"),
+                    MessageItem::from("Never Gonna Give You Up"),
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn metadata_leaves_out_what_isnt_known() {
+        let items = track_metadata(0, track(), None, None);
+        let keys: Vec<&str> = items.iter().map(|x| x.0.as_str()).collect();
+        assert_eq!(keys, vec!["mpris:trackid", "xesam:title"]);
+    }
+
+    #[test]
+    fn events_change_the_properties_they_affect() {
+        assert_eq!(
+            changed_properties(&PlayerEvent::TrackChanged(track())),
+            Some(TRACK_PROPERTIES)
+        );
+        assert_eq!(
+            changed_properties(&PlayerEvent::Stopped),
+            Some(TRACK_PROPERTIES)
+        );
+        assert_eq!(
+            changed_properties(&PlayerEvent::QueueFinished),
+            Some(TRACK_PROPERTIES)
+        );
+        assert_eq!(
+            changed_properties(&PlayerEvent::Paused),
+            Some(STATUS_PROPERTIES)
+        );
+        assert_eq!(changed_properties(&PlayerEvent::TrackFailed(track())), None);
+        assert_eq!(changed_properties(&PlayerEvent::TrackFinished(track())), None);
+    }
+}
@@ -0,0 +1,771 @@
+use backend::ResultKind;
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Empty,
+    Play(Option<usize>),
+    Queue(usize),
+    PlayUrl(String),
+    QueueUrl(String),
+    QueueList,
+    QueueMove(usize, usize),
+    QueueRemove(usize),
+    QueueClear,
+    QueueImport(String),
+    QueueExport(String),
+    Skip,
+    Prev,
+    Jump(usize),
+    Download,
+    Loop,
+    Radio(Option<usize>),
+    Pause,
+    Related,
+    More,
+    NextPage,
+    PrevPage,
+    Cycle,
+    List(bool),
+    Now,
+    Stop,
+    Search(String),
+    SearchCollections(String, ResultKind),
+    Open(usize),
+    PlaylistSave(String),
+    PlaylistLoad(String),
+    PlaylistList,
+    PlaylistDelete(String),
+    PlaylistAdd(String, usize),
+    PlaylistRemove(String, usize),
+    PlaylistShow(String),
+    PlaylistImport(String, String),
+    PlaylistExport(String, String),
+    Fav(Option<usize>),
+    Unfav(Option<usize>),
+    Rate(usize, Option<usize>),
+    Favs,
+    History,
+    HistorySearch(String),
+    HistoryReplay(usize),
+    Smart(String),
+    SmartSave(String, String),
+    SmartList,
+    SmartDelete(String),
+    Help(Option<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgKind {
+    /// A non-negative integer, e.g. a search result or queue position
+    Index,
+    /// A single word, e.g. a playlist name
+    Word,
+    /// Everything left on the line
+    Text,
+}
+
+#[derive(Debug)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub optional: bool,
+}
+
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub args: &'static [ArgSpec],
+    pub description: &'static str,
+    /// Makes the command out of arguments that match `args`
+    pub build: fn(Args) -> Command,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ArgValue {
+    Index(usize),
+    Text(String),
+}
+
+/// The arguments of a command, one per `ArgSpec`, `None` for optional ones
+/// left out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Args(Vec<Option<ArgValue>>);
+
+impl Args {
+    /// A required `Index` argument.
+    pub fn index(&self, i: usize) -> usize {
+        self.optional_index(i).unwrap_or(0)
+    }
+
+    pub fn optional_index(&self, i: usize) -> Option<usize> {
+        match self.0.get(i) {
+            Some(&Some(ArgValue::Index(x))) => Some(x),
+            _ => None,
+        }
+    }
+
+    /// A required `Word` or `Text` argument.
+    pub fn text(&self, i: usize) -> String {
+        self.optional_text(i).unwrap_or_default()
+    }
+
+    pub fn optional_text(&self, i: usize) -> Option<String> {
+        match self.0.get(i) {
+            Some(&Some(ArgValue::Text(ref x))) => Some(x.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Unknown(String),
+    Usage(&'static CommandSpec),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Unknown(ref x) => write!(f, "Unrecognized command '{0}'! Try 'help'", x),
+            ParseError::Usage(spec) => write!(f, "Usage: {0}", spec.usage()),
+        }
+    }
+}
+
+const POSITION: ArgSpec = ArgSpec {
+    name: "position",
+    kind: ArgKind::Index,
+    optional: false,
+};
+
+const OPTIONAL_SELECTION: ArgSpec = ArgSpec {
+    name: "selection",
+    kind: ArgKind::Index,
+    optional: true,
+};
+
+const FILE: ArgSpec = ArgSpec {
+    name: "file",
+    kind: ArgKind::Text,
+    optional: false,
+};
+
+const PLAYLIST: ArgSpec = ArgSpec {
+    name: "name",
+    kind: ArgKind::Word,
+    optional: false,
+};
+
+const RULES: ArgSpec = ArgSpec {
+    name: "rules",
+    kind: ArgKind::Text,
+    optional: false,
+};
+
+pub static COMMANDS: &'static [CommandSpec] = &[
+    CommandSpec {
+        name: "search",
+        args: &[
+            ArgSpec {
+                name: "terms",
+                kind: ArgKind::Text,
+                optional: false,
+            },
+        ],
+        description: "Search for tracks and show the first result",
+        build: |args| Command::Search(args.text(0)),
+    },
+    CommandSpec {
+        name: "search playlists",
+        args: &[
+            ArgSpec {
+                name: "terms",
+                kind: ArgKind::Text,
+                optional: false,
+            },
+        ],
+        description: "Search for playlists instead of tracks",
+        build: |args| Command::SearchCollections(args.text(0), ResultKind::Playlist),
+    },
+    CommandSpec {
+        name: "search channels",
+        args: &[
+            ArgSpec {
+                name: "terms",
+                kind: ArgKind::Text,
+                optional: false,
+            },
+        ],
+        description: "Search for channels instead of tracks",
+        build: |args| Command::SearchCollections(args.text(0), ResultKind::Channel),
+    },
+    CommandSpec {
+        name: "open",
+        args: &[
+            ArgSpec {
+                name: "selection",
+                kind: ArgKind::Index,
+                optional: false,
+            },
+        ],
+        description: "List the tracks of a playlist or the uploads of a channel",
+        build: |args| Command::Open(args.index(0)),
+    },
+    CommandSpec {
+        name: "related",
+        args: &[],
+        description: "Search for tracks related to the current selection",
+        build: |_| Command::Related,
+    },
+    CommandSpec {
+        name: "more",
+        args: &[],
+        description: "Add the next page of results to the current ones",
+        build: |_| Command::More,
+    },
+    CommandSpec {
+        name: "next page",
+        args: &[],
+        description: "Replace the current results with the next page",
+        build: |_| Command::NextPage,
+    },
+    CommandSpec {
+        name: "prev page",
+        args: &[],
+        description: "Replace the current results with the previous page",
+        build: |_| Command::PrevPage,
+    },
+    CommandSpec {
+        name: "cycle",
+        args: &[],
+        description: "Show the next search result",
+        build: |_| Command::Cycle,
+    },
+    CommandSpec {
+        name: "list",
+        args: &[],
+        description: "Show all search results as a table",
+        build: |_| Command::List(false),
+    },
+    CommandSpec {
+        name: "list thumbnails",
+        args: &[],
+        description: "Show all search results as a table, with small thumbnails",
+        build: |_| Command::List(true),
+    },
+    CommandSpec {
+        name: "play",
+        args: &[
+            ArgSpec {
+                name: "selection",
+                kind: ArgKind::Index,
+                optional: true,
+            },
+        ],
+        description: "Play a search result immediately, or resume playback without one",
+        build: |args| Command::Play(args.optional_index(0)),
+    },
+    CommandSpec {
+        name: "queue",
+        args: &[
+            ArgSpec {
+                name: "selection",
+                kind: ArgKind::Index,
+                optional: true,
+            },
+        ],
+        description: "Queue a search result, or list the queue without one",
+        build: |args| args.optional_index(0).map_or(Command::QueueList, Command::Queue),
+    },
+    CommandSpec {
+        name: "play url",
+        args: &[
+            ArgSpec {
+                name: "url",
+                kind: ArgKind::Text,
+                optional: false,
+            },
+        ],
+        description: "Play a YouTube link or video id, a stream or a local file",
+        build: |args| Command::PlayUrl(args.text(0)),
+    },
+    CommandSpec {
+        name: "queue url",
+        args: &[
+            ArgSpec {
+                name: "url",
+                kind: ArgKind::Text,
+                optional: false,
+            },
+        ],
+        description: "Queue a YouTube link or video id, a stream or a local file",
+        build: |args| Command::QueueUrl(args.text(0)),
+    },
+    CommandSpec {
+        name: "queue list",
+        args: &[],
+        description: "List the queue, marking the track currently playing",
+        build: |_| Command::QueueList,
+    },
+    CommandSpec {
+        name: "queue move",
+        args: &[
+            ArgSpec {
+                name: "from",
+                kind: ArgKind::Index,
+                optional: false,
+            },
+            ArgSpec {
+                name: "to",
+                kind: ArgKind::Index,
+                optional: false,
+            },
+        ],
+        description: "Move a queue entry to another position",
+        build: |args| Command::QueueMove(args.index(0), args.index(1)),
+    },
+    CommandSpec {
+        name: "queue remove",
+        args: &[POSITION],
+        description: "Remove an entry from the queue",
+        build: |args| Command::QueueRemove(args.index(0)),
+    },
+    CommandSpec {
+        name: "queue clear",
+        args: &[],
+        description: "Remove everything from the queue except the current track",
+        build: |_| Command::QueueClear,
+    },
+    CommandSpec {
+        name: "queue import",
+        args: &[FILE],
+        description: "Queue every track of an M3U, PLS or XSPF file",
+        build: |args| Command::QueueImport(args.text(0)),
+    },
+    CommandSpec {
+        name: "queue export",
+        args: &[FILE],
+        description: "Write the queue to an M3U, PLS or XSPF file, going by its extension",
+        build: |args| Command::QueueExport(args.text(0)),
+    },
+    CommandSpec {
+        name: "skip",
+        args: &[],
+        description: "Play the next track in the queue",
+        build: |_| Command::Skip,
+    },
+    CommandSpec {
+        name: "prev",
+        args: &[],
+        description: "Play the previous track in the queue",
+        build: |_| Command::Prev,
+    },
+    CommandSpec {
+        name: "jump",
+        args: &[POSITION],
+        description: "Play the queue entry at the given position",
+        build: |args| Command::Jump(args.index(0)),
+    },
+    CommandSpec {
+        name: "pause",
+        args: &[],
+        description: "Pause playback",
+        build: |_| Command::Pause,
+    },
+    CommandSpec {
+        name: "stop",
+        args: &[],
+        description: "Stop playback and clear the queue",
+        build: |_| Command::Stop,
+    },
+    CommandSpec {
+        name: "loop",
+        args: &[],
+        description: "Toggle looping the current track",
+        build: |_| Command::Loop,
+    },
+    CommandSpec {
+        name: "radio",
+        args: &[
+            ArgSpec {
+                name: "selection",
+                kind: ArgKind::Index,
+                optional: true,
+            },
+        ],
+        description: "Toggle queueing related tracks automatically, or start from a search result",
+        build: |args| Command::Radio(args.optional_index(0)),
+    },
+    CommandSpec {
+        name: "now",
+        args: &[],
+        description: "Show the track currently playing and the time remaining",
+        build: |_| Command::Now,
+    },
+    CommandSpec {
+        name: "download",
+        args: &[],
+        description: "Toggle downloading audio files instead of streaming",
+        build: |_| Command::Download,
+    },
+    CommandSpec {
+        name: "playlist save",
+        args: &[PLAYLIST],
+        description: "Save the queue as a playlist, replacing any of the same name",
+        build: |args| Command::PlaylistSave(args.text(0)),
+    },
+    CommandSpec {
+        name: "playlist load",
+        args: &[PLAYLIST],
+        description: "Queue every track of a playlist",
+        build: |args| Command::PlaylistLoad(args.text(0)),
+    },
+    CommandSpec {
+        name: "playlist list",
+        args: &[],
+        description: "List the saved playlists",
+        build: |_| Command::PlaylistList,
+    },
+    CommandSpec {
+        name: "playlist delete",
+        args: &[PLAYLIST],
+        description: "Delete a playlist",
+        build: |args| Command::PlaylistDelete(args.text(0)),
+    },
+    CommandSpec {
+        name: "playlist add",
+        args: &[
+            PLAYLIST,
+            ArgSpec {
+                name: "selection",
+                kind: ArgKind::Index,
+                optional: false,
+            },
+        ],
+        description: "Add a search result to a playlist, creating it if needed",
+        build: |args| Command::PlaylistAdd(args.text(0), args.index(1)),
+    },
+    CommandSpec {
+        name: "playlist remove",
+        args: &[PLAYLIST, POSITION],
+        description: "Remove a track from a playlist",
+        build: |args| Command::PlaylistRemove(args.text(0), args.index(1)),
+    },
+    CommandSpec {
+        name: "playlist show",
+        args: &[PLAYLIST],
+        description: "List the tracks of a playlist",
+        build: |args| Command::PlaylistShow(args.text(0)),
+    },
+    CommandSpec {
+        name: "playlist import",
+        args: &[PLAYLIST, FILE],
+        description: "Save an M3U, PLS or XSPF file as a playlist",
+        build: |args| Command::PlaylistImport(args.text(0), args.text(1)),
+    },
+    CommandSpec {
+        name: "playlist export",
+        args: &[PLAYLIST, FILE],
+        description: "Write a playlist to an M3U, PLS or XSPF file, going by its extension",
+        build: |args| Command::PlaylistExport(args.text(0), args.text(1)),
+    },
+    CommandSpec {
+        name: "fav",
+        args: &[OPTIONAL_SELECTION],
+        description: "Make a search result, or what's playing, a favorite",
+        build: |args| Command::Fav(args.optional_index(0)),
+    },
+    CommandSpec {
+        name: "unfav",
+        args: &[OPTIONAL_SELECTION],
+        description: "Stop a search result, or what's playing, being a favorite",
+        build: |args| Command::Unfav(args.optional_index(0)),
+    },
+    CommandSpec {
+        name: "rate",
+        args: &[
+            ArgSpec {
+                name: "stars",
+                kind: ArgKind::Index,
+                optional: false,
+            },
+            OPTIONAL_SELECTION,
+        ],
+        description: "Rate a search result, or what's playing, from 1 to 5 stars",
+        build: |args| Command::Rate(args.index(0), args.optional_index(1)),
+    },
+    CommandSpec {
+        name: "favs",
+        args: &[],
+        description: "List the favorites as results to play or queue",
+        build: |_| Command::Favs,
+    },
+    CommandSpec {
+        name: "history",
+        args: &[],
+        description: "List the tracks played lately, newest first",
+        build: |_| Command::History,
+    },
+    CommandSpec {
+        name: "history search",
+        args: &[
+            ArgSpec {
+                name: "terms",
+                kind: ArgKind::Text,
+                optional: false,
+            },
+        ],
+        description: "Find played tracks by title or artist",
+        build: |args| Command::HistorySearch(args.text(0)),
+    },
+    CommandSpec {
+        name: "history replay",
+        args: &[POSITION],
+        description: "Play a track from the history again",
+        build: |args| Command::HistoryReplay(args.index(0)),
+    },
+    CommandSpec {
+        name: "smart",
+        args: &[RULES],
+        description: "Queue the library tracks matching rules, or a saved smart playlist",
+        build: |args| Command::Smart(args.text(0)),
+    },
+    CommandSpec {
+        name: "smart save",
+        args: &[PLAYLIST, RULES],
+        description: "Save rules as a smart playlist, replacing any of the same name",
+        build: |args| Command::SmartSave(args.text(0), args.text(1)),
+    },
+    CommandSpec {
+        name: "smart list",
+        args: &[],
+        description: "List the saved smart playlists and their rules",
+        build: |_| Command::SmartList,
+    },
+    CommandSpec {
+        name: "smart delete",
+        args: &[PLAYLIST],
+        description: "Delete a smart playlist",
+        build: |args| Command::SmartDelete(args.text(0)),
+    },
+    CommandSpec {
+        name: "help",
+        args: &[
+            ArgSpec {
+                name: "command",
+                kind: ArgKind::Text,
+                optional: true,
+            },
+        ],
+        description: "Show all commands, or the usage of one command",
+        build: |args| Command::Help(args.optional_text(0)),
+    },
+];
+
+impl CommandSpec {
+    pub fn usage(&self) -> String {
+        let mut ret = String::from(self.name);
+        for arg in self.args {
+            if arg.optional {
+                ret.push_str(&format!(" [{0}]", arg.name));
+            } else {
+                ret.push_str(&format!(" <{0}>", arg.name));
+            }
+        }
+        ret
+    }
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, ParseError> {
+        let words = line.split_whitespace().collect::<Vec<&str>>();
+        if words.is_empty() {
+            return Ok(Command::Empty);
+        }
+
+        let spec = match find_spec(&words) {
+            Some(x) => x,
+            None => return Err(ParseError::Unknown(String::from(words[0]))),
+        };
+        let args = parse_args(spec, &words[spec.name.split(' ').count()..])?;
+        Ok((spec.build)(args))
+    }
+}
+
+/// Longest command name that prefixes the input, so that `queue move 1 2`
+/// resolves to `queue move` rather than to `queue` with bad arguments.
+fn find_spec(words: &[&str]) -> Option<&'static CommandSpec> {
+    COMMANDS
+        .iter()
+        .filter(|spec| {
+            let name = spec.name.split(' ').collect::<Vec<&str>>();
+            name.len() <= words.len() && name[..] == words[..name.len()]
+        })
+        .max_by_key(|spec| spec.name.len())
+}
+
+fn parse_args(spec: &'static CommandSpec, words: &[&str]) -> Result<Args, ParseError> {
+    let mut ret = vec![];
+    let mut rest = words;
+    for arg in spec.args {
+        if rest.is_empty() {
+            if !arg.optional {
+                return Err(ParseError::Usage(spec));
+            }
+            ret.push(None);
+            continue;
+        }
+        match arg.kind {
+            ArgKind::Index => {
+                match rest[0].parse() {
+                    Ok(x) => ret.push(Some(ArgValue::Index(x))),
+                    Err(_) => return Err(ParseError::Usage(spec)),
+                }
+                rest = &rest[1..];
+            }
+            ArgKind::Word => {
+                ret.push(Some(ArgValue::Text(String::from(rest[0]))));
+                rest = &rest[1..];
+            }
+            ArgKind::Text => {
+                ret.push(Some(ArgValue::Text(rest.join(" "))));
+                rest = &[];
+            }
+        }
+    }
+    if !rest.is_empty() {
+        return Err(ParseError::Usage(spec));
+    }
+    Ok(Args(ret))
+}
+
+/// Generated help: every command when `topic` is empty, otherwise every
+/// command whose name starts with `topic` (so `help queue` shows all of the
+/// queue subcommands).
+pub fn help(topic: Option<&str>) -> Result<String, ParseError> {
+    let specs = COMMANDS
+        .iter()
+        .filter(|spec| match topic {
+            Some(t) => spec.name == t || spec.name.starts_with(&format!("{0} ", t)),
+            None => true,
+        })
+        .collect::<Vec<_>>();
+    if specs.is_empty() {
+        return Err(ParseError::Unknown(String::from(topic.unwrap_or(""))));
+    }
+
+    let width = specs.iter().map(|spec| spec.usage().len()).max().unwrap_or(0);
+    let mut ret = String::new();
+    for spec in specs {
+        ret.push_str(&format!(
+            "  {0:1$}  {2}\n",
+            spec.usage(),
+            width,
+            spec.description
+        ));
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Command {
+        Command::parse(line).unwrap()
+    }
+
+    /// The usage shown for `line`, which has to have bad arguments.
+    fn usage(line: &str) -> String {
+        match Command::parse(line) {
+            Err(ParseError::Usage(spec)) => spec.usage(),
+            x => panic!("'{0}' gave {1:?}", line, x),
+        }
+    }
+
+    fn spec_name(line: &str) -> Option<&'static str> {
+        find_spec(&line.split_whitespace().collect::<Vec<&str>>()).map(|x| x.name)
+    }
+
+    #[test]
+    fn finds_longest_name() {
+        assert_eq!(spec_name("queue move 1 2"), Some("queue move"));
+        assert_eq!(spec_name("queue 3"), Some("queue"));
+        assert_eq!(spec_name("search playlists lofi"), Some("search playlists"));
+        assert_eq!(spec_name("search lofi playlists"), Some("search"));
+        assert_eq!(spec_name("list thumbnails"), Some("list thumbnails"));
+        assert_eq!(spec_name("history"), Some("history"));
+        assert_eq!(spec_name("playlist"), None);
+        assert_eq!(spec_name("queuemove 1 2"), None);
+    }
+
+    #[test]
+    fn names_are_unique() {
+        for spec in COMMANDS {
+            assert_eq!(COMMANDS.iter().filter(|x| x.name == spec.name).count(), 1);
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse("   "), Command::Empty);
+        assert_eq!(parse("queue move 1 2"), Command::QueueMove(1, 2));
+        assert_eq!(parse("queue 3"), Command::Queue(3));
+        assert_eq!(parse("queue"), Command::QueueList);
+        assert_eq!(parse("queue list"), Command::QueueList);
+        assert_eq!(parse("play"), Command::Play(None));
+        assert_eq!(parse("play 0"), Command::Play(Some(0)));
+        assert_eq!(
+            parse("search playlists  lofi   beats"),
+            Command::SearchCollections(String::from("lofi beats"), ResultKind::Playlist)
+        );
+        assert_eq!(parse("list thumbnails"), Command::List(true));
+        assert_eq!(parse("rate 4"), Command::Rate(4, None));
+        assert_eq!(parse("rate 4 2"), Command::Rate(4, Some(2)));
+        assert_eq!(
+            parse("playlist import road ~/Music/Road Trip.m3u"),
+            Command::PlaylistImport(String::from("road"), String::from("~/Music/Road Trip.m3u"))
+        );
+        assert_eq!(
+            parse("smart save best rating>=4 played>30d"),
+            Command::SmartSave(String::from("best"), String::from("rating>=4 played>30d"))
+        );
+        assert_eq!(parse("help"), Command::Help(None));
+        assert_eq!(parse("help queue"), Command::Help(Some(String::from("queue"))));
+    }
+
+    #[test]
+    fn rejects_unknown_commands() {
+        match Command::parse("frobnicate 1") {
+            Err(ParseError::Unknown(x)) => assert_eq!(x, "frobnicate"),
+            x => panic!("{0:?}", x),
+        }
+        assert!(Command::parse("playlist").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_eq!(usage("queue move 1"), "queue move <from> <to>");
+        assert_eq!(usage("queue move 1 x"), "queue move <from> <to>");
+        assert_eq!(usage("jump"), "jump <position>");
+        assert_eq!(usage("jump -1"), "jump <position>");
+        assert_eq!(usage("jump 1 2"), "jump <position>");
+        assert_eq!(usage("queue x"), "queue [selection]");
+        assert_eq!(usage("rate 4 2 1"), "rate <stars> [selection]");
+        assert_eq!(usage("playlist add mix"), "playlist add <name> <selection>");
+        assert_eq!(usage("playlist export mix"), "playlist export <name> <file>");
+        assert_eq!(usage("search"), "search <terms>");
+        assert_eq!(usage("related 1"), "related");
+    }
+
+    #[test]
+    fn shows_help() {
+        let queue = help(Some("queue")).unwrap();
+        assert!(queue.contains("queue move <from> <to>"));
+        assert!(!queue.contains("playlist"));
+        assert!(help(Some("que")).is_err());
+        assert_eq!(help(None).unwrap().lines().count(), COMMANDS.len());
+    }
+}
@@ -1,81 +1,536 @@
-use mpv::{MpvHandler, MpvHandlerBuilder};
+use mpv::{MpvHandler, MpvHandlerBuilder, Event, EndFileReason};
 
-use std::marker::{Send, Sync};
+use backend::BackendSearchResult;
+use error::{Result, SurgeError};
 
-pub struct AudioPlayer {
+use std::marker::Send;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+const EVENT_POLL_INTERVAL_MS: u64 = 50;
+
+#[derive(Debug, Clone)]
+pub struct QueueEntry {
+    pub track: BackendSearchResult,
+    pub url: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum PlayerEvent {
+    TrackChanged(BackendSearchResult),
+    TrackFinished(BackendSearchResult),
+    TrackFailed(BackendSearchResult),
+    QueueFinished,
+    /// `stop` cleared the queue
+    Stopped,
+    Paused,
+    Resumed,
+}
+
+/// Mirror of the mpv playlist: every entry handed to mpv, in mpv's order,
+/// and the index mpv is currently playing.
+struct Queue {
+    entries: Vec<QueueEntry>,
+    pos: Option<usize>,
+    /// Bumped whenever entries are added, removed or reordered
+    version: u32,
+}
+
+impl Queue {
+    fn changed(&mut self) {
+        self.version = self.version.wrapping_add(1);
+    }
+
+    fn current(&self) -> Option<&QueueEntry> {
+        match self.pos {
+            Some(pos) => self.entries.get(pos),
+            None => None,
+        }
+    }
+
+    /// Move the entry at `from` to index `to`, keeping `pos` on the entry
+    /// it pointed at.
+    fn move_entry(&mut self, from: usize, to: usize) {
+        let entry = self.entries.remove(from);
+        self.entries.insert(to, entry);
+        self.pos = self.pos.map(|pos| if pos == from {
+            to
+        } else if from < pos && pos <= to {
+            pos - 1
+        } else if to <= pos && pos < from {
+            pos + 1
+        } else {
+            pos
+        });
+        self.changed();
+    }
+
+    /// Drop the entry at `idx`. Dropping the current entry leaves no
+    /// position until mpv starts the next one.
+    fn remove(&mut self, idx: usize) {
+        self.entries.remove(idx);
+        self.pos = match self.pos {
+            Some(pos) if pos == idx => None,
+            Some(pos) if pos > idx => Some(pos - 1),
+            x => x,
+        };
+        self.changed();
+    }
+}
+
+/// The index mpv's `playlist-move` needs to put the entry at `from` at
+/// index `to`: mpv inserts before the entry at its target, so moving
+/// forward has to aim at the slot after `to`.
+fn playlist_move_target(from: usize, to: usize) -> usize {
+    if from < to { to + 1 } else { to }
+}
+
+struct PlayerState {
     mpv: MpvHandler,
+    queue: Queue,
+    /// Halted with the queue kept, until playback resumes
+    stopped: bool,
+}
+
+unsafe impl Send for PlayerState {}
+
+impl PlayerState {
+    fn sync_pos(&mut self) {
+        self.queue.pos = match self.mpv.get_property::<i64>("playlist-pos") {
+            Ok(x) if x >= 0 && (x as usize) < self.queue.entries.len() => Some(x as usize),
+            _ => None,
+        };
+    }
 }
 
-unsafe impl Send for AudioPlayer {}
-unsafe impl Sync for AudioPlayer {}
+pub struct AudioPlayer {
+    state: Arc<Mutex<PlayerState>>,
+    subscribers: Arc<Mutex<Vec<Sender<PlayerEvent>>>>,
+    running: Arc<AtomicBool>,
+}
 
 impl AudioPlayer {
-    pub fn new() -> AudioPlayer {
-        let mut mpv = MpvHandlerBuilder::new()
-            .expect("Couldn't initialize MpvHandlerBuilder")
-            .build()
-            .expect("Couldn't build MpvHandler");
-        mpv.set_option("ytdl", "yes").expect(
-            "Couldn't enable ytdl in libmpv",
-        );
-        mpv.set_option("vo", "null").expect(
-            "Couldn't set vo=null in libmpv",
-        );
-        AudioPlayer { mpv: mpv }
-    }
-
-    pub fn queue(&mut self, new: String) {
-        self.mpv
-            .command(&["loadfile", &new, "append-play"])
-            .expect("Error loading file");
-    }
-
-    pub fn queue_and_play(&mut self, new: String) {
-        self.mpv.command(&["loadfile", &new, "replace"]).expect(
-            "Error loading file",
-        );
-    }
-
-    pub fn stop(&mut self) {
-        self.mpv.command(&["stop"]).expect("Error stopping mpv");
-    }
-
-    pub fn pause(&mut self) {
-        self.mpv.set_property("pause", true).expect(
-            "Toggling pause property",
-        );
-    }
-
-    pub fn resume(&mut self) {
-        self.mpv.set_property("pause", false).expect(
-            "Toggling pause property",
-        );
-    }
-
-    pub fn loop_(&mut self) {
-        let next_loop = match self.mpv.get_property::<&str>("loop-file") {
-            Ok(x) => {
-                if x == "inf" || x == "yes" {
-                    println!("Toggling loop off");
-                    "no"
-                } else if x == "no" || x == "1" {
-                    println!("Toggling loop on");
-                    "inf"
-                } else {
-                    panic!("Unexpected value for loop-file property")
+    pub fn new() -> Result<AudioPlayer> {
+        let mut mpv = MpvHandlerBuilder::new()?.build()?;
+        mpv.set_option("ytdl", "yes")?;
+        mpv.set_option("vo", "null")?;
+
+        let player = AudioPlayer {
+            state: Arc::new(Mutex::new(PlayerState {
+                mpv: mpv,
+                queue: Queue {
+                    entries: vec![],
+                    pos: None,
+                    version: 0,
+                },
+                stopped: false,
+            })),
+            subscribers: Arc::new(Mutex::new(vec![])),
+            running: Arc::new(AtomicBool::new(true)),
+        };
+        player.spawn_event_loop();
+        Ok(player)
+    }
+
+    fn spawn_event_loop(&self) {
+        let state = self.state.clone();
+        let subscribers = self.subscribers.clone();
+        let running = self.running.clone();
+
+        thread::spawn(move || while running.load(Ordering::SeqCst) {
+            let events = drain_mpv_events(&mut state.lock().expect("Player state poisoned"));
+            if !events.is_empty() {
+                let mut subs = subscribers.lock().expect("Player subscribers poisoned");
+                for ev in events {
+                    subs.retain(|s| s.send(ev.clone()).is_ok());
                 }
             }
-            Err(e) => panic!(e),
+            thread::sleep(Duration::from_millis(EVENT_POLL_INTERVAL_MS));
+        });
+    }
+
+    pub fn subscribe(&self) -> Receiver<PlayerEvent> {
+        let (tx, rx) = channel();
+        self.subscribers
+            .lock()
+            .expect("Player subscribers poisoned")
+            .push(tx);
+        rx
+    }
+
+    pub fn queue(&mut self, track: BackendSearchResult, new: String) -> Result<()> {
+        let mut state = self.state.lock().expect("Player state poisoned");
+        state.mpv.command(&["loadfile", &new, "append-play"])?;
+        state.queue.entries.push(QueueEntry {
+            track: track,
+            url: new,
+        });
+        state.queue.changed();
+        Ok(())
+    }
+
+    pub fn queue_and_play(&mut self, track: BackendSearchResult, new: String) -> Result<()> {
+        let mut state = self.state.lock().expect("Player state poisoned");
+        state.mpv.command(&["loadfile", &new, "replace"])?;
+        state.queue.entries = vec![
+            QueueEntry {
+                track: track,
+                url: new,
+            },
+        ];
+        state.queue.pos = Some(0);
+        state.queue.changed();
+        state.stopped = false;
+        Ok(())
+    }
+
+    pub fn queue_entries(&self) -> (Vec<QueueEntry>, Option<usize>) {
+        let state = self.state.lock().expect("Player state poisoned");
+        (state.queue.entries.clone(), state.queue.pos)
+    }
+
+    pub fn queue_version(&self) -> u32 {
+        self.state.lock().expect("Player state poisoned").queue.version
+    }
+
+    pub fn now_playing(&self) -> Option<BackendSearchResult> {
+        let state = self.state.lock().expect("Player state poisoned");
+        state.queue.current().map(|x| x.track.clone())
+    }
+
+    /// Move the entry at `from` so that it ends up at index `to`.
+    pub fn move_entry(&mut self, from: usize, to: usize) -> Result<bool> {
+        let mut state = self.state.lock().expect("Player state poisoned");
+        let len = state.queue.entries.len();
+        if from >= len || to >= len {
+            return Ok(false);
+        }
+        if from == to {
+            return Ok(true);
+        }
+        state.mpv.command(&[
+            "playlist-move",
+            &from.to_string(),
+            &playlist_move_target(from, to).to_string(),
+        ])?;
+        state.queue.move_entry(from, to);
+        state.sync_pos();
+        Ok(true)
+    }
+
+    pub fn remove_entry(&mut self, idx: usize) -> Result<bool> {
+        let mut state = self.state.lock().expect("Player state poisoned");
+        if idx >= state.queue.entries.len() {
+            return Ok(false);
+        }
+        state.mpv.command(&["playlist-remove", &idx.to_string()])?;
+        state.queue.remove(idx);
+        state.sync_pos();
+        Ok(true)
+    }
+
+    /// Start playing the entry at `idx`; mpv fires StartFile once it has
+    /// switched, which is when the queue position gets updated.
+    pub fn jump(&mut self, idx: usize) -> Result<bool> {
+        let mut state = self.state.lock().expect("Player state poisoned");
+        if idx >= state.queue.entries.len() {
+            return Ok(false);
+        }
+        state.mpv.set_property("playlist-pos", idx as i64)?;
+        Ok(true)
+    }
+
+    pub fn skip(&mut self) -> Result<bool> {
+        let next = match self.queue_entries().1 {
+            Some(pos) => pos + 1,
+            None => return Ok(false),
         };
-        self.mpv.set_property("loop-file", next_loop).expect(
-            "Toggling loop-file property",
-        );
+        self.jump(next)
+    }
+
+    pub fn prev(&mut self) -> Result<bool> {
+        match self.queue_entries().1 {
+            Some(pos) if pos > 0 => self.jump(pos - 1),
+            _ => Ok(false),
+        }
+    }
+
+    /// Drop every entry except the one currently playing.
+    pub fn clear_queue(&mut self) -> Result<()> {
+        let mut state = self.state.lock().expect("Player state poisoned");
+        state.mpv.command(&["playlist-clear"])?;
+        let current = state.queue.current().cloned();
+        match current {
+            Some(x) => {
+                state.queue.entries = vec![x];
+                state.queue.pos = Some(0);
+            }
+            None => {
+                state.queue.entries.clear();
+                state.queue.pos = None;
+            }
+        }
+        state.queue.changed();
+        Ok(())
+    }
+
+    /// Stop and clear the queue. mpv goes idle without a position to
+    /// report, so subscribers hear about it from here instead.
+    pub fn stop(&mut self) -> Result<()> {
+        {
+            let mut state = self.state.lock().expect("Player state poisoned");
+            state.mpv.command(&["stop"])?;
+            state.queue.entries.clear();
+            state.queue.pos = None;
+            state.queue.changed();
+            state.stopped = false;
+        }
+        self.subscribers
+            .lock()
+            .expect("Player subscribers poisoned")
+            .retain(|s| s.send(PlayerEvent::Stopped).is_ok());
+        Ok(())
+    }
+
+    /// Stop playing but keep the queue, the way MPD and MPRIS clients
+    /// expect: the current entry goes back to its start and waits there
+    /// until playback resumes. mpv's own `stop` would drop its playlist.
+    pub fn halt(&mut self) -> Result<()> {
+        let mut state = self.state.lock().expect("Player state poisoned");
+        if state.queue.current().is_none() {
+            return Ok(());
+        }
+        state.mpv.set_property("pause", true)?;
+        state.mpv.command(&["seek", "0", "absolute"])?;
+        state.stopped = true;
+        Ok(())
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.state.lock().expect("Player state poisoned").stopped
+    }
+
+    pub fn pause(&mut self) -> Result<()> {
+        let mut state = self.state.lock().expect("Player state poisoned");
+        state.mpv.set_property("pause", true)?;
+        Ok(())
+    }
+
+    pub fn resume(&mut self) -> Result<()> {
+        let mut state = self.state.lock().expect("Player state poisoned");
+        state.mpv.set_property("pause", false)?;
+        state.stopped = false;
+        Ok(())
+    }
+
+    /// Toggle looping the current file, returning whether looping is now on.
+    pub fn loop_(&mut self) -> Result<bool> {
+        let mut state = self.state.lock().expect("Player state poisoned");
+        let looping = match state.mpv.get_property::<&str>("loop-file")? {
+            "inf" | "yes" => false,
+            "no" | "1" => true,
+            x => {
+                return Err(SurgeError::Player(
+                    format!("Unexpected value for loop-file property: {0}", x),
+                ))
+            }
+        };
+        state.mpv.set_property(
+            "loop-file",
+            if looping { "inf" } else { "no" },
+        )?;
+        Ok(looping)
+    }
+
+    pub fn paused(&self) -> Result<bool> {
+        let state = self.state.lock().expect("Player state poisoned");
+        Ok(state.mpv.get_property::<bool>("pause")?)
+    }
+
+    pub fn volume(&self) -> Result<i64> {
+        let state = self.state.lock().expect("Player state poisoned");
+        Ok(state.mpv.get_property::<f64>("volume")? as i64)
+    }
+
+    pub fn set_volume(&mut self, volume: i64) -> Result<()> {
+        let mut state = self.state.lock().expect("Player state poisoned");
+        state.mpv.set_property("volume", volume as f64)?;
+        Ok(())
+    }
+
+    pub fn looping(&self) -> Result<bool> {
+        let state = self.state.lock().expect("Player state poisoned");
+        Ok(state.mpv.get_property::<&str>("loop-file")? != "no")
+    }
+
+    /// Seconds into the current track.
+    pub fn time_pos(&self) -> Result<f64> {
+        let state = self.state.lock().expect("Player state poisoned");
+        Ok(state.mpv.get_property::<f64>("time-pos")?)
+    }
+
+    /// Length of the current track in seconds.
+    pub fn duration(&self) -> Result<f64> {
+        let state = self.state.lock().expect("Player state poisoned");
+        Ok(state.mpv.get_property::<f64>("duration")?)
+    }
+
+    /// Seek `secs` seconds forward, or backward if negative.
+    pub fn seek(&mut self, secs: f64) -> Result<()> {
+        let mut state = self.state.lock().expect("Player state poisoned");
+        state.mpv.command(&["seek", &secs.to_string(), "relative"])?;
+        Ok(())
+    }
+
+    pub fn seek_to(&mut self, secs: f64) -> Result<()> {
+        let mut state = self.state.lock().expect("Player state poisoned");
+        state.mpv.command(&["seek", &secs.to_string(), "absolute"])?;
+        Ok(())
+    }
+
+    pub fn time_remain(&self) -> Result<i64> {
+        let state = self.state.lock().expect("Player state poisoned");
+        Ok(state.mpv.get_property::<i64>("time-remain")?)
+    }
+}
+
+impl Drop for AudioPlayer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+fn drain_mpv_events(state: &mut PlayerState) -> Vec<PlayerEvent> {
+    let mut ret = vec![];
+    while let Some(ev) = state.mpv.wait_event(0.0) {
+        match ev {
+            // the failure and end events of a file come before the next
+            // FileLoaded, so the position has to move as soon as it starts
+            Event::StartFile => state.sync_pos(),
+            Event::FileLoaded => {
+                if let Some(x) = state.queue.current() {
+                    ret.push(PlayerEvent::TrackChanged(x.track.clone()));
+                }
+            }
+            Event::EndFile(Ok(EndFileReason::MPV_END_FILE_REASON_EOF)) => {
+                if let Some(x) = state.queue.current() {
+                    ret.push(PlayerEvent::TrackFinished(x.track.clone()));
+                }
+            }
+            Event::EndFile(Err(_)) => {
+                if let Some(x) = state.queue.current() {
+                    ret.push(PlayerEvent::TrackFailed(x.track.clone()));
+                }
+            }
+            Event::Pause => ret.push(PlayerEvent::Paused),
+            Event::Unpause => ret.push(PlayerEvent::Resumed),
+            Event::Idle => {
+                if state.queue.pos.is_some() {
+                    state.queue.pos = None;
+                    ret.push(PlayerEvent::QueueFinished);
+                }
+            }
+            _ => (),
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::BackendType;
+
+    fn queue(ids: &[&str], pos: Option<usize>) -> Queue {
+        Queue {
+            entries: ids.iter()
+                .map(|x| {
+                    QueueEntry {
+                        track: BackendSearchResult::new(
+                            x.to_string(),
+                            x.to_string(),
+                            BackendType::Local,
+                        ),
+                        url: x.to_string(),
+                    }
+                })
+                .collect(),
+            pos: pos,
+            version: 0,
+        }
+    }
+
+    fn ids(q: &Queue) -> Vec<&str> {
+        q.entries.iter().map(|x| x.url.as_str()).collect()
+    }
+
+    #[test]
+    fn playlist_move_targets_the_slot_after_when_moving_forward() {
+        assert_eq!(playlist_move_target(0, 2), 3);
+        assert_eq!(playlist_move_target(2, 0), 0);
+        assert_eq!(playlist_move_target(3, 1), 1);
+    }
+
+    #[test]
+    fn move_forward_shifts_the_entries_between_back() {
+        let mut q = queue(&["a", "b", "c", "d"], Some(2));
+        q.move_entry(0, 2);
+        assert_eq!(ids(&q), vec!["b", "c", "a", "d"]);
+        assert_eq!(q.pos, Some(1));
+        assert_eq!(q.version, 1);
+    }
+
+    #[test]
+    fn move_backward_shifts_the_entries_between_forward() {
+        let mut q = queue(&["a", "b", "c", "d"], Some(1));
+        q.move_entry(3, 0);
+        assert_eq!(ids(&q), vec!["d", "a", "b", "c"]);
+        assert_eq!(q.pos, Some(2));
+    }
+
+    #[test]
+    fn moving_the_current_entry_moves_the_position() {
+        let mut q = queue(&["a", "b", "c"], Some(0));
+        q.move_entry(0, 2);
+        assert_eq!(q.pos, Some(2));
+        q.move_entry(2, 1);
+        assert_eq!(q.pos, Some(1));
+        assert_eq!(q.current().unwrap().url, "a");
+    }
+
+    #[test]
+    fn moves_outside_the_position_leave_it() {
+        let mut q = queue(&["a", "b", "c", "d"], Some(0));
+        q.move_entry(1, 3);
+        assert_eq!(ids(&q), vec!["a", "c", "d", "b"]);
+        assert_eq!(q.pos, Some(0));
+    }
+
+    #[test]
+    fn remove_before_the_position_shifts_it() {
+        let mut q = queue(&["a", "b", "c"], Some(2));
+        q.remove(0);
+        assert_eq!(ids(&q), vec!["b", "c"]);
+        assert_eq!(q.pos, Some(1));
+        assert_eq!(q.current().unwrap().url, "c");
+    }
+
+    #[test]
+    fn remove_after_the_position_leaves_it() {
+        let mut q = queue(&["a", "b", "c"], Some(0));
+        q.remove(2);
+        assert_eq!(ids(&q), vec!["a", "b"]);
+        assert_eq!(q.pos, Some(0));
     }
 
-    pub fn print_time_remain(&mut self) {
-        println!(
-            "Time remaining: {:?}",
-            self.mpv.get_property::<i64>("time-remain").unwrap_or(-9999)
-        );
+    #[test]
+    fn remove_current_clears_the_position() {
+        let mut q = queue(&["a", "b"], Some(1));
+        q.remove(1);
+        assert_eq!(q.pos, None);
+        assert!(q.current().is_none());
     }
 }
@@ -0,0 +1,436 @@
+//! The playlist files other players read and write: M3U/M3U8 (with
+//! `#EXTINF`), PLS and XSPF.
+
+use url::Url;
+
+use backend::{BackendSearchResult, BackendType};
+use error::{Result, SurgeError};
+use link::{self, Link};
+
+use std::char;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    M3u,
+    Pls,
+    Xspf,
+}
+
+impl Format {
+    /// Going by the file extension.
+    pub fn of(path: &Path) -> Result<Format> {
+        let ext = path.extension()
+            .map(|x| x.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        match ext.as_str() {
+            "m3u" | "m3u8" => Ok(Format::M3u),
+            "pls" => Ok(Format::Pls),
+            "xspf" => Ok(Format::Xspf),
+            _ => Err(SurgeError::Usage(format!(
+                "Can't tell the format of '{0}'. Use .m3u, .m3u8, .pls or .xspf",
+                path.display()
+            ))),
+        }
+    }
+}
+
+/// One entry as the file has it, before it's made sense of.
+struct Entry {
+    location: String,
+    title: Option<String>,
+    artist: Option<String>,
+    duration: Option<u64>,
+}
+
+/// The tracks of the playlist file at `path`, and how many of its entries
+/// surge couldn't make sense of, e.g. YouTube playlists or relative paths
+/// to files that aren't there.
+pub fn read(path: &Path) -> Result<(Vec<BackendSearchResult>, usize)> {
+    let format = Format::of(path)?;
+    let mut bytes = vec![];
+    File::open(path)?.read_to_end(&mut bytes)?;
+    let contents = String::from_utf8_lossy(&bytes);
+    let entries = match format {
+        Format::M3u => read_m3u(&contents),
+        Format::Pls => read_pls(&contents),
+        Format::Xspf => read_xspf(&contents),
+    };
+
+    // relative locations are relative to the playlist file
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut tracks = vec![];
+    let mut skipped = 0;
+    for entry in entries {
+        let file = Path::new(&entry.location);
+        let relative = dir.join(file);
+        let found = if file.is_relative() && relative.is_file() {
+            Some(Link::File(relative))
+        } else {
+            // files that are missing, e.g. on an unmounted drive, are kept,
+            // as they are when given as file:// URLs
+            link::parse(&entry.location).or_else(|| if file.is_absolute() {
+                Some(Link::File(file.to_path_buf()))
+            } else {
+                None
+            })
+        };
+        let (id, source) = match found {
+            Some(Link::Video(id)) => (id, BackendType::Youtube),
+            Some(Link::File(path)) => (path.to_string_lossy().into_owned(), BackendType::Local),
+            Some(Link::Stream(url)) => (url, BackendType::Stream),
+            // surge playlists only hold tracks
+            Some(Link::Playlist(_)) | None => {
+                skipped += 1;
+                continue;
+            }
+        };
+        let title = entry.title.unwrap_or_else(|| id.clone());
+        tracks.push(BackendSearchResult {
+            artist: entry.artist,
+            duration: entry.duration,
+            ..BackendSearchResult::new(id, title, source)
+        });
+    }
+    Ok((tracks, skipped))
+}
+
+/// Write `tracks` to `path` in the format its extension names.
+pub fn write(path: &Path, tracks: &[BackendSearchResult]) -> Result<()> {
+    let contents = match Format::of(path)? {
+        Format::M3u => write_m3u(tracks),
+        Format::Pls => write_pls(tracks),
+        Format::Xspf => write_xspf(tracks),
+    };
+    File::create(path)?.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+/// Where another player finds `x`: YouTube videos as watch URLs, which mpv
+/// plays through youtube-dl.
+fn location(x: &BackendSearchResult) -> String {
+    match x.source {
+        BackendType::Youtube |
+        BackendType::Ytdl => format!("https://www.youtube.com/watch?v={0}", x.id),
+        BackendType::Local |
+        BackendType::Stream => x.id.clone(),
+    }
+}
+
+fn read_m3u(contents: &str) -> Vec<Entry> {
+    let mut entries = vec![];
+    let mut info = None;
+    // M3U8 files saved by Windows tools start with a byte order mark
+    let contents = contents.trim_left_matches('\u{feff}');
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with("#EXTINF:") {
+            // #EXTINF:<seconds> [attributes],<title>, seconds being -1 if
+            // unknown
+            let mut parts = line["#EXTINF:".len()..].splitn(2, ',');
+            let duration = parts
+                .next()
+                .and_then(|x| x.split_whitespace().next())
+                .and_then(|x| x.parse::<i64>().ok())
+                .and_then(|x| if x > 0 { Some(x as u64) } else { None });
+            let title = parts.next().map(str::trim).and_then(|x| if x.is_empty() {
+                None
+            } else {
+                Some(String::from(x))
+            });
+            info = Some((duration, title));
+        } else if !line.is_empty() && !line.starts_with('#') {
+            let (duration, title) = info.take().unwrap_or((None, None));
+            entries.push(Entry {
+                location: String::from(line),
+                title: title,
+                artist: None,
+                duration: duration,
+            });
+        }
+    }
+    entries
+}
+
+fn write_m3u(tracks: &[BackendSearchResult]) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    for x in tracks {
+        let duration = x.duration.map_or(-1, |secs| secs as i64);
+        out.push_str(&format!("#EXTINF:{0},{1}\n{2}\n", duration, x.title, location(x)));
+    }
+    out
+}
+
+/// PLS is INI-like: FileN, TitleN and LengthN keys, numbered from 1.
+fn read_pls(contents: &str) -> Vec<Entry> {
+    let mut entries = BTreeMap::new();
+    for line in contents.lines() {
+        let mut parts = line.splitn(2, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(k), Some(v)) => (k.trim().to_lowercase(), v.trim()),
+            _ => continue,
+        };
+        let split = key.find(|c: char| c.is_digit(10)).unwrap_or(key.len());
+        let n = match key[split..].parse::<usize>() {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        let entry = entries.entry(n).or_insert_with(|| {
+            Entry {
+                location: String::new(),
+                title: None,
+                artist: None,
+                duration: None,
+            }
+        });
+        match &key[..split] {
+            "file" => entry.location = String::from(value),
+            "title" => entry.title = Some(String::from(value)),
+            "length" => {
+                entry.duration = value.parse::<i64>().ok().and_then(|x| if x > 0 {
+                    Some(x as u64)
+                } else {
+                    None
+                })
+            }
+            _ => (),
+        }
+    }
+    entries
+        .into_iter()
+        .map(|(_, x)| x)
+        .filter(|x| !x.location.is_empty())
+        .collect()
+}
+
+fn write_pls(tracks: &[BackendSearchResult]) -> String {
+    let mut out = String::from("[playlist]\n");
+    for (i, x) in tracks.iter().enumerate() {
+        out.push_str(&format!(
+            "File{0}={1}\nTitle{0}={2}\nLength{0}={3}\n",
+            i + 1,
+            location(x),
+            x.title,
+            x.duration.map_or(-1, |secs| secs as i64)
+        ));
+    }
+    out.push_str(&format!("NumberOfEntries={0}\nVersion=2\n", tracks.len()));
+    out
+}
+
+/// Only what surge keeps of a track is read, so this gets by without an
+/// XML parser: the location, title, creator and duration of each track.
+fn read_xspf(contents: &str) -> Vec<Entry> {
+    let mut entries = vec![];
+    let mut rest = contents;
+    while let Some(track) = element(rest, "track") {
+        rest = track.1;
+        let location = match element(track.0, "location") {
+            Some((x, _)) => unescape(x),
+            None => continue,
+        };
+        let text = |tag: &str| element(track.0, tag).map(|(x, _)| unescape(x));
+        entries.push(Entry {
+            location: location,
+            title: text("title"),
+            artist: text("creator"),
+            // in milliseconds
+            duration: text("duration")
+                .and_then(|x| x.parse::<u64>().ok())
+                .map(|ms| ms / 1000),
+        });
+    }
+    entries
+}
+
+fn write_xspf(tracks: &[BackendSearchResult]) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  <trackList>\n",
+    );
+    for x in tracks {
+        // XSPF locations are URIs, so local files become file:// URLs
+        let location = match x.source {
+            BackendType::Local => {
+                Url::from_file_path(&x.id)
+                    .map(|url| url.into_string())
+                    .unwrap_or_else(|_| x.id.clone())
+            }
+            _ => location(x),
+        };
+        out.push_str("    <track>\n");
+        out.push_str(&format!("      <location>{0}</location>\n", escape(&location)));
+        out.push_str(&format!("      <title>{0}</title>\n", escape(&x.title)));
+        if let Some(ref artist) = x.artist {
+            out.push_str(&format!("      <creator>{0}</creator>\n", escape(artist)));
+        }
+        if let Some(secs) = x.duration {
+            out.push_str(&format!("      <duration>{0}</duration>\n", secs * 1000));
+        }
+        out.push_str("    </track>\n");
+    }
+    out.push_str("  </trackList>\n</playlist>\n");
+    out
+}
+
+/// The contents of the first `tag` element in `xml`, and what follows it.
+fn element<'a>(xml: &'a str, tag: &str) -> Option<(&'a str, &'a str)> {
+    let open = format!("<{0}", tag);
+    let close = format!("</{0}>", tag);
+    let mut from = 0;
+    loop {
+        let start = match xml[from..].find(&open) {
+            Some(x) => from + x + open.len(),
+            None => return None,
+        };
+        // <tracklist> isn't a <track>
+        match xml[start..].chars().next() {
+            Some('>') | Some(' ') | Some('\t') | Some('\n') | Some('\r') => (),
+            _ => {
+                from = start;
+                continue;
+            }
+        }
+        let body = match xml[start..].find('>') {
+            Some(x) => start + x + 1,
+            None => return None,
+        };
+        return match xml[body..].find(&close) {
+            Some(x) => Some((&xml[body..body + x], &xml[body + x + close.len()..])),
+            None => None,
+        };
+    }
+}
+
+fn escape(x: &str) -> String {
+    x.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Entities that don't make sense are left as they are.
+fn unescape(x: &str) -> String {
+    let mut out = String::new();
+    let mut rest = x.trim();
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let decoded = rest.find(';').and_then(|end| entity(&rest[1..end]).map(|c| (c, end)));
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The character of `&name;`, named or numbered, e.g. `&amp;`, `&#39;` or
+/// `&#x27;`.
+fn entity(name: &str) -> Option<char> {
+    match name {
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "amp" => Some('&'),
+        _ if name.starts_with("#x") || name.starts_with("#X") => {
+            u32::from_str_radix(&name[2..], 16).ok().and_then(char::from_u32)
+        }
+        _ if name.starts_with('#') => name[1..].parse().ok().and_then(char::from_u32),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+
+    fn track(id: &str, title: &str, source: BackendType) -> BackendSearchResult {
+        BackendSearchResult::new(String::from(id), String::from(title), source)
+    }
+
+    fn tracks() -> Vec<BackendSearchResult> {
+        vec![
+            BackendSearchResult {
+                artist: Some(String::from("Rick Astley")),
+                duration: Some(213),
+                ..track("dQw4w9WgXcQ", "Never Gonna Give You Up", BackendType::Youtube)
+            },
+            BackendSearchResult {
+                artist: Some(String::from("AC/DC")),
+                duration: Some(255),
+                ..track("/music/AC & DC/Back in Black.mp3", "Back in Black", BackendType::Local)
+            },
+            track("http://radio.example.com:8000/live", "Radio <Live>", BackendType::Stream),
+        ]
+    }
+
+    /// Write `tracks()` to a file of this extension and read it back.
+    fn round_trip(ext: &str) -> Vec<BackendSearchResult> {
+        let mut path = env::temp_dir();
+        path.push(format!("surge-round-trip.{0}", ext));
+        write(&path, &tracks()).unwrap();
+        let (read, skipped) = read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(skipped, 0);
+        read
+    }
+
+    fn summary(xs: &[BackendSearchResult]) -> Vec<(&str, &str, BackendType, Option<u64>)> {
+        xs.iter()
+            .map(|x| (x.id.as_str(), x.title.as_str(), x.source, x.duration))
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_m3u() {
+        assert_eq!(summary(&round_trip("m3u8")), summary(&tracks()));
+    }
+
+    #[test]
+    fn round_trips_pls() {
+        assert_eq!(summary(&round_trip("pls")), summary(&tracks()));
+    }
+
+    #[test]
+    fn round_trips_xspf() {
+        let read = round_trip("xspf");
+        assert_eq!(summary(&read), summary(&tracks()));
+        let artists = read.iter().map(|x| x.artist.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            artists,
+            tracks().iter().map(|x| x.artist.clone()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn reads_m3u_with_byte_order_mark() {
+        let entries = read_m3u("\u{feff}#EXTM3U\n#EXTINF:61,One\n/music/one.mp3\n");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].location, "/music/one.mp3");
+        assert_eq!(entries[0].title, Some(String::from("One")));
+        assert_eq!(entries[0].duration, Some(61));
+    }
+
+    #[test]
+    fn unescapes_entities() {
+        assert_eq!(unescape(" Rock &amp; Roll "), "Rock & Roll");
+        assert_eq!(unescape("Don&#39;t &#x2F; Won&#X27;t"), "Don't / Won't");
+        assert_eq!(unescape("&lt;b&gt; &quot;x&quot; &apos;"), "<b> \"x\" '");
+        assert_eq!(unescape("&amp;lt;"), "&lt;");
+        assert_eq!(unescape("AT&T; & &bogus; &#xD800;"), "AT&T; & &bogus; &#xD800;");
+    }
+}
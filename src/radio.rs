@@ -0,0 +1,268 @@
+//! Radio mode: keep the queue topped up with tracks related to what's
+//! playing, so surge can run unattended.
+
+use backend::{BackendSearchResult, MasterBackend};
+use command::CommandCenter;
+use download::Downloader;
+use error::Result;
+use player::PlayerEvent;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Tracks the radio won't pick again until this many others have played
+pub const RECENT_TRACKS: usize = 100;
+/// Channels or artists the radio tries not to repeat within this many picks
+const RECENT_ARTISTS: usize = 5;
+
+#[derive(Clone)]
+pub struct Radio {
+    on: bool,
+    /// How many tracks to keep queued after the one playing
+    depth: usize,
+    /// Newest last
+    recent_ids: VecDeque<String>,
+    recent_artists: VecDeque<String>,
+}
+
+impl Radio {
+    pub fn new(depth: usize) -> Radio {
+        Radio {
+            on: false,
+            depth: depth,
+            recent_ids: VecDeque::new(),
+            recent_artists: VecDeque::new(),
+        }
+    }
+
+    pub fn on(&self) -> bool {
+        self.on
+    }
+
+    pub fn set(&mut self, on: bool) {
+        self.on = on;
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Keep `x` from being picked again for a while.
+    pub fn remember(&mut self, x: &BackendSearchResult) {
+        push_bounded(&mut self.recent_ids, x.id.clone(), RECENT_TRACKS);
+        if let Some(ref artist) = x.artist {
+            push_bounded(&mut self.recent_artists, artist.to_lowercase(), RECENT_ARTISTS);
+        }
+    }
+
+    /// The best of `candidates` to play next: one not played lately, or
+    /// `queued`, preferably by someone else than the last few tracks.
+    pub fn pick(
+        &self,
+        candidates: &[BackendSearchResult],
+        queued: &[String],
+    ) -> Option<BackendSearchResult> {
+        let fresh = candidates
+            .iter()
+            .filter(|x| !self.recent_ids.contains(&x.id) && !queued.contains(&x.id))
+            .collect::<Vec<_>>();
+        let new_artist = fresh.iter().find(|x| match x.artist {
+            Some(ref artist) => !self.recent_artists.contains(&artist.to_lowercase()),
+            None => true,
+        });
+        new_artist.or_else(|| fresh.first()).map(|x| (*x).clone())
+    }
+}
+
+/// A top-up worked out under the `CommandCenter` lock and carried out
+/// without it, so slow backends and downloads don't hold up the prompt, the
+/// daemon or MPD and MPRIS clients.
+pub struct TopUp {
+    /// A copy to pick with; what gets queued is remembered by the real one
+    radio: Radio,
+    backend: Arc<MasterBackend>,
+    pub downloader: Arc<Downloader>,
+    seed: BackendSearchResult,
+    queued_ids: Vec<String>,
+    needed: usize,
+}
+
+impl TopUp {
+    pub fn new(
+        radio: Radio,
+        backend: Arc<MasterBackend>,
+        downloader: Arc<Downloader>,
+        seed: BackendSearchResult,
+        queued_ids: Vec<String>,
+        needed: usize,
+    ) -> TopUp {
+        TopUp {
+            radio: radio,
+            backend: backend,
+            downloader: downloader,
+            seed: seed,
+            queued_ids: queued_ids,
+            needed: needed,
+        }
+    }
+
+    /// Tracks related to the end of the queue, each one the seed of the
+    /// next. A backend failing after the first pick only cuts it short.
+    pub fn pick(&mut self) -> Result<Vec<BackendSearchResult>> {
+        let mut picked = vec![];
+        while picked.len() < self.needed {
+            let outcome = match self.backend.related_outcome(&self.seed.id, None) {
+                Ok(x) => x,
+                Err(_) if !picked.is_empty() => break,
+                Err(e) => return Err(e),
+            };
+            let next = match self.radio.pick(&outcome.results, &self.queued_ids) {
+                Some(x) => x,
+                None => break,
+            };
+            self.radio.remember(&next);
+            self.queued_ids.push(next.id.clone());
+            picked.push(next.clone());
+            self.seed = next;
+        }
+        Ok(picked)
+    }
+}
+
+fn push_bounded(xs: &mut VecDeque<String>, x: String, max: usize) {
+    xs.push_back(x);
+    while xs.len() > max {
+        xs.pop_front();
+    }
+}
+
+/// Top the queue up whenever a track starts or the queue runs out, until
+/// the player goes away.
+pub fn serve(cmd: Arc<Mutex<CommandCenter>>) {
+    let events = cmd.lock().expect("CommandCenter poisoned").subscribe();
+    for ev in events.iter() {
+        match ev {
+            PlayerEvent::TrackChanged(_) |
+            PlayerEvent::TrackFailed(_) |
+            PlayerEvent::QueueFinished => (),
+            PlayerEvent::TrackFinished(_) |
+            PlayerEvent::Stopped |
+            PlayerEvent::Paused |
+            PlayerEvent::Resumed => continue,
+        }
+        let top_up = cmd.lock().expect("CommandCenter poisoned").radio_top_up();
+        if let Some(x) = top_up {
+            if let Err(e) = top_up_unlocked(&cmd, x) {
+                println!("Radio couldn't find a next track: {0}", e);
+            }
+        }
+    }
+}
+
+/// Only lock the `CommandCenter` to look up and record downloads and to
+/// queue, not while asking backends or downloading.
+fn top_up_unlocked(cmd: &Arc<Mutex<CommandCenter>>, mut top_up: TopUp) -> Result<()> {
+    let picked = top_up.pick()?;
+    let known = {
+        let c = cmd.lock().expect("CommandCenter poisoned");
+        picked
+            .iter()
+            .map(|x| c.known_location(x))
+            .collect::<Result<Vec<_>>>()?
+    };
+    let mut tracks = vec![];
+    for (x, location) in picked.into_iter().zip(known) {
+        match location {
+            Some(location) => tracks.push((x, location, false)),
+            None => {
+                match top_up.downloader.fetch_audio(&x) {
+                    Ok(path) => tracks.push((x, path, true)),
+                    Err(e) => println!("Radio couldn't download {0}: {1}", x.title, e),
+                }
+            }
+        }
+    }
+    cmd.lock().expect("CommandCenter poisoned").finish_top_up(tracks)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::BackendType;
+
+    fn track(id: &str, artist: Option<&str>) -> BackendSearchResult {
+        BackendSearchResult {
+            artist: artist.map(String::from),
+            ..BackendSearchResult::new(String::from(id), id.to_uppercase(), BackendType::Youtube)
+        }
+    }
+
+    fn picked(
+        radio: &Radio,
+        candidates: &[BackendSearchResult],
+        queued: &[&str],
+    ) -> Option<String> {
+        let queued = queued.iter().map(|x| String::from(*x)).collect::<Vec<_>>();
+        radio.pick(candidates, &queued).map(|x| x.id)
+    }
+
+    #[test]
+    fn picks_the_first_candidate_by_default() {
+        let candidates = [track("a", Some("Air")), track("b", Some("Justice"))];
+        assert_eq!(picked(&Radio::new(2), &candidates, &[]), Some(String::from("a")));
+    }
+
+    #[test]
+    fn skips_queued_tracks() {
+        let candidates = [track("a", Some("Air")), track("b", Some("Justice"))];
+        assert_eq!(picked(&Radio::new(2), &candidates, &["a"]), Some(String::from("b")));
+    }
+
+    #[test]
+    fn skips_tracks_in_the_history() {
+        let mut radio = Radio::new(2);
+        radio.remember(&track("a", None));
+        let candidates = [track("a", None), track("b", None)];
+        assert_eq!(picked(&radio, &candidates, &[]), Some(String::from("b")));
+    }
+
+    #[test]
+    fn forgets_tracks_after_enough_others() {
+        let mut radio = Radio::new(2);
+        radio.remember(&track("a", None));
+        for i in 0..RECENT_TRACKS - 1 {
+            radio.remember(&track(&i.to_string(), None));
+        }
+        let candidates = [track("a", None)];
+        assert_eq!(picked(&radio, &candidates, &[]), None);
+        radio.remember(&track("b", None));
+        assert_eq!(picked(&radio, &candidates, &[]), Some(String::from("a")));
+    }
+
+    #[test]
+    fn prefers_artists_not_heard_lately() {
+        let mut radio = Radio::new(2);
+        radio.remember(&track("x", Some("Daft Punk")));
+        let candidates = [
+            track("a", Some("daft punk")),
+            track("b", Some("Justice")),
+            track("c", None),
+        ];
+        assert_eq!(picked(&radio, &candidates, &[]), Some(String::from("b")));
+        assert_eq!(picked(&radio, &candidates[..1], &[]), Some(String::from("a")));
+        assert_eq!(
+            picked(&radio, &[candidates[0].clone(), candidates[2].clone()], &[]),
+            Some(String::from("c"))
+        );
+    }
+
+    #[test]
+    fn picks_nothing_without_fresh_candidates() {
+        let mut radio = Radio::new(2);
+        assert_eq!(picked(&radio, &[], &[]), None);
+        radio.remember(&track("a", None));
+        let candidates = [track("a", None), track("b", None)];
+        assert_eq!(picked(&radio, &candidates, &["b"]), None);
+    }
+}
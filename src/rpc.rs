@@ -0,0 +1,510 @@
+use serde_json::{self, Value};
+
+use backend::{BackendSearchResult, ResultKind};
+use command::CommandCenter;
+use error::SurgeError;
+use history::Play;
+use player::PlayerEvent;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+pub struct Request {
+    /// Absent for notifications, which get no response
+    pub id: Option<Value>,
+    pub method: String,
+    params: Value,
+}
+
+pub struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> RpcError {
+        RpcError {
+            code: code,
+            message: String::from(message),
+        }
+    }
+}
+
+impl From<SurgeError> for RpcError {
+    fn from(e: SurgeError) -> RpcError {
+        let code = match e {
+            SurgeError::Usage(_) => INVALID_PARAMS,
+            _ => SERVER_ERROR,
+        };
+        RpcError::new(code, &e.to_string())
+    }
+}
+
+/// Any line starting with `{`, or `[` for a batch, on the daemon socket is
+/// JSON-RPC; everything else is a prompt command.
+pub fn is_request(line: &str) -> bool {
+    let line = line.trim_left();
+    line.starts_with('{') || line.starts_with('[')
+}
+
+/// Answer a line of JSON-RPC, `dispatch` running each request in it. A
+/// batch is answered with an array of responses, in the order of its
+/// requests. Nothing is returned if there were only notifications.
+pub fn handle<F>(line: &str, mut dispatch: F) -> Option<Value>
+where
+    F: FnMut(&Request) -> Result<Value, RpcError>,
+{
+    let v = match serde_json::from_str::<Value>(line) {
+        Ok(x) => x,
+        Err(e) => return Some(error_response(RpcError::new(PARSE_ERROR, &e.to_string()))),
+    };
+    match v {
+        Value::Array(ref xs) if xs.is_empty() => {
+            Some(error_response(RpcError::new(INVALID_REQUEST, "Empty batch")))
+        }
+        Value::Array(xs) => {
+            let responses = xs.iter()
+                .filter_map(|x| answer(x, &mut dispatch))
+                .collect::<Vec<_>>();
+            if responses.is_empty() {
+                None
+            } else {
+                Some(Value::Array(responses))
+            }
+        }
+        x => answer(&x, &mut dispatch),
+    }
+}
+
+fn answer<F>(v: &Value, dispatch: &mut F) -> Option<Value>
+where
+    F: FnMut(&Request) -> Result<Value, RpcError>,
+{
+    match parse_request(v) {
+        Ok(req) => {
+            let result = dispatch(&req);
+            req.id.as_ref().map(|id| response(id, result))
+        }
+        Err(e) => Some(error_response(e)),
+    }
+}
+
+fn parse_request(v: &Value) -> Result<Request, RpcError> {
+    if v["jsonrpc"].as_str() != Some("2.0") {
+        return Err(RpcError::new(INVALID_REQUEST, "Expected jsonrpc: \"2.0\""));
+    }
+    let method = match v["method"].as_str() {
+        Some(x) => String::from(x),
+        None => return Err(RpcError::new(INVALID_REQUEST, "Missing method")),
+    };
+    Ok(Request {
+        id: v.get("id").cloned(),
+        method: method,
+        params: v.get("params").cloned().unwrap_or(Value::Null),
+    })
+}
+
+fn response(id: &Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(x) => {
+            json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "result": x,
+            })
+        }
+        Err(e) => {
+            json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {
+                    "code": e.code,
+                    "message": e.message,
+                },
+            })
+        }
+    }
+}
+
+fn error_response(e: RpcError) -> Value {
+    response(&Value::Null, Err(e))
+}
+
+pub fn notification(ev: &PlayerEvent) -> Value {
+    let (method, params) = match *ev {
+        PlayerEvent::TrackChanged(ref x) => ("track_changed", track(x)),
+        PlayerEvent::TrackFinished(ref x) => ("track_finished", track(x)),
+        PlayerEvent::TrackFailed(ref x) => ("track_failed", track(x)),
+        PlayerEvent::QueueFinished => ("queue_finished", Value::Null),
+        PlayerEvent::Stopped => ("stopped", Value::Null),
+        PlayerEvent::Paused => ("paused", Value::Null),
+        PlayerEvent::Resumed => ("resumed", Value::Null),
+    };
+    json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": params,
+    })
+}
+
+pub fn track(x: &BackendSearchResult) -> Value {
+    json!({
+        "id": x.id,
+        "title": x.title,
+        "thumbnail": x.thumbnail,
+        "source": x.source.to_string(),
+        "kind": x.kind.to_string(),
+        "artist": x.artist,
+        "duration": x.duration,
+        "published": x.published,
+        "views": x.views,
+        "description": x.description,
+    })
+}
+
+fn play(pos: usize, x: &Play) -> Value {
+    json!({
+        "position": pos,
+        "track": track(&x.track),
+        "started": x.started,
+        "listened": x.listened,
+        "outcome": x.outcome.to_string(),
+    })
+}
+
+fn tracks(xs: &[BackendSearchResult]) -> Value {
+    Value::Array(xs.iter().map(track).collect())
+}
+
+/// Params may be given by position or by name.
+fn param<'a>(params: &'a Value, pos: usize, name: &str) -> Option<&'a Value> {
+    match *params {
+        Value::Array(ref xs) => xs.get(pos),
+        Value::Object(ref xs) => xs.get(name),
+        _ => None,
+    }
+}
+
+fn index_param(params: &Value, pos: usize, name: &str) -> Result<Option<usize>, RpcError> {
+    match param(params, pos, name) {
+        None | Some(&Value::Null) => Ok(None),
+        Some(x) => {
+            match x.as_u64() {
+                Some(x) => Ok(Some(x as usize)),
+                None => Err(RpcError::new(
+                    INVALID_PARAMS,
+                    &format!("{0} must be a non-negative integer", name),
+                )),
+            }
+        }
+    }
+}
+
+fn required_index(params: &Value, pos: usize, name: &str) -> Result<usize, RpcError> {
+    match index_param(params, pos, name)? {
+        Some(x) => Ok(x),
+        None => Err(RpcError::new(INVALID_PARAMS, &format!("Missing {0}", name))),
+    }
+}
+
+fn required_text(params: &Value, pos: usize, name: &str) -> Result<String, RpcError> {
+    match param(params, pos, name).and_then(Value::as_str) {
+        Some(x) if !x.trim().is_empty() => Ok(String::from(x)),
+        _ => Err(RpcError::new(INVALID_PARAMS, &format!("Missing {0}", name))),
+    }
+}
+
+/// How many tracks a playlist file had, and how many of its entries were
+/// skipped.
+fn imported((tracks, skipped): (usize, usize)) -> Value {
+    json!({ "tracks": tracks, "skipped": skipped })
+}
+
+fn queue_state(cmd: &mut CommandCenter) -> Value {
+    let (entries, pos) = cmd.player().queue_entries();
+    json!({
+        "entries": entries.iter().map(|x| track(&x.track)).collect::<Vec<_>>(),
+        "position": pos,
+    })
+}
+
+fn check(ok: bool, message: &str) -> Result<(), RpcError> {
+    if ok {
+        Ok(())
+    } else {
+        Err(RpcError::new(INVALID_PARAMS, message))
+    }
+}
+
+pub fn call(cmd: &mut CommandCenter, req: &Request) -> Result<Value, RpcError> {
+    let params = &req.params;
+    let ret = match req.method.as_str() {
+        "search" => {
+            let query = match param(params, 0, "query").and_then(Value::as_str) {
+                Some(x) if !x.trim().is_empty() => x,
+                _ => return Err(RpcError::new(INVALID_PARAMS, "Missing query")),
+            };
+            tracks(cmd.search(query)?)
+        }
+        "search_playlists" | "search_channels" => {
+            let query = match param(params, 0, "query").and_then(Value::as_str) {
+                Some(x) if !x.trim().is_empty() => x,
+                _ => return Err(RpcError::new(INVALID_PARAMS, "Missing query")),
+            };
+            let kind = if req.method == "search_channels" {
+                ResultKind::Channel
+            } else {
+                ResultKind::Playlist
+            };
+            tracks(cmd.search_collections(query, kind)?)
+        }
+        "open" => tracks(cmd.open(required_index(params, 0, "selection")?)?),
+        "related" => tracks(cmd.related()?),
+        "more" => tracks(cmd.more()?),
+        "next_page" => tracks(cmd.next_page()?),
+        "prev_page" => tracks(cmd.prev_page()?),
+        "play" => {
+            match cmd.play(index_param(params, 0, "selection")?)? {
+                Some(ref x) => track(x),
+                None => Value::Null,
+            }
+        }
+        "queue" => track(&cmd.queue(required_index(params, 0, "selection")?)?),
+        "play_url" | "queue_url" => {
+            let url = match param(params, 0, "url").and_then(Value::as_str) {
+                Some(x) if !x.trim().is_empty() => x,
+                _ => return Err(RpcError::new(INVALID_PARAMS, "Missing url")),
+            };
+            if req.method == "play_url" {
+                track(&cmd.play_url(url)?)
+            } else {
+                track(&cmd.queue_url(url)?)
+            }
+        }
+        "queue_list" => queue_state(cmd),
+        "playlist_save" => json!(cmd.playlist_save(&required_text(params, 0, "name")?)?),
+        "playlist_load" => json!(cmd.playlist_load(&required_text(params, 0, "name")?)?),
+        "playlist_list" => {
+            Value::Array(
+                cmd.playlist_list()?
+                    .into_iter()
+                    .map(|(name, n)| json!({ "name": name, "tracks": n }))
+                    .collect(),
+            )
+        }
+        "playlist_delete" => {
+            cmd.playlist_delete(&required_text(params, 0, "name")?)?;
+            Value::Null
+        }
+        "playlist_add" => {
+            let name = required_text(params, 0, "name")?;
+            tracks(&cmd.playlist_add(&name, required_index(params, 1, "selection")?)?)
+        }
+        "playlist_remove" => {
+            let name = required_text(params, 0, "name")?;
+            track(&cmd.playlist_remove(&name, required_index(params, 1, "position")?)?)
+        }
+        "playlist_show" => tracks(&cmd.playlist_tracks(&required_text(params, 0, "name")?)?),
+        "playlist_import" => {
+            let name = required_text(params, 0, "name")?;
+            let file = required_text(params, 1, "file")?;
+            imported(cmd.playlist_import(&name, &file)?)
+        }
+        "playlist_export" => {
+            let name = required_text(params, 0, "name")?;
+            json!(cmd.playlist_export(&name, &required_text(params, 1, "file")?)?)
+        }
+        "queue_import" => imported(cmd.queue_import(&required_text(params, 0, "file")?)?),
+        "queue_export" => json!(cmd.queue_export(&required_text(params, 0, "file")?)?),
+        "queue_move" => {
+            let from = required_index(params, 0, "from")?;
+            let to = required_index(params, 1, "to")?;
+            check(
+                cmd.player().move_entry(from, to)?,
+                "Invalid queue positions",
+            )?;
+            queue_state(cmd)
+        }
+        "queue_remove" => {
+            let pos = required_index(params, 0, "position")?;
+            check(cmd.player().remove_entry(pos)?, "Invalid queue position")?;
+            queue_state(cmd)
+        }
+        "queue_clear" => {
+            cmd.player().clear_queue()?;
+            queue_state(cmd)
+        }
+        "skip" => {
+            check(cmd.player().skip()?, "Nothing left in the queue")?;
+            Value::Null
+        }
+        "prev" => {
+            check(cmd.player().prev()?, "Already at the start of the queue")?;
+            Value::Null
+        }
+        "jump" => {
+            let pos = required_index(params, 0, "position")?;
+            check(cmd.player().jump(pos)?, "Invalid queue position")?;
+            Value::Null
+        }
+        "pause" => {
+            cmd.pause()?;
+            Value::Null
+        }
+        "stop" => {
+            cmd.stop()?;
+            Value::Null
+        }
+        "loop" => json!({ "looping": cmd.toggle_loop()? }),
+        "radio" => {
+            let (on, queued) = cmd.radio(index_param(params, 0, "selection")?)?;
+            json!({ "radio": on, "queued": tracks(&queued) })
+        }
+        "fav" => {
+            let (x, added) = cmd.fav(index_param(params, 0, "selection")?)?;
+            json!({ "track": track(&x), "added": added })
+        }
+        "unfav" => track(&cmd.unfav(index_param(params, 0, "selection")?)?),
+        "rate" => {
+            let stars = required_index(params, 0, "stars")?;
+            track(&cmd.rate(stars, index_param(params, 1, "selection")?)?)
+        }
+        "favs" => {
+            Value::Array(
+                cmd.favs()?
+                    .iter()
+                    .map(|x| json!({ "track": track(&x.track), "rating": x.rating }))
+                    .collect(),
+            )
+        }
+        "history" => {
+            let limit = index_param(params, 0, "limit")?;
+            Value::Array(
+                cmd.history(limit.unwrap_or(usize::max_value()))?
+                    .iter()
+                    .enumerate()
+                    .map(|(i, x)| play(i, x))
+                    .collect(),
+            )
+        }
+        "history_search" => {
+            let query = required_text(params, 0, "query")?;
+            let limit = index_param(params, 1, "limit")?;
+            Value::Array(
+                cmd.history_search(&query, limit.unwrap_or(usize::max_value()))?
+                    .iter()
+                    .map(|&(i, ref x)| play(i, x))
+                    .collect(),
+            )
+        }
+        "history_replay" => track(&cmd.history_replay(required_index(params, 0, "position")?)?),
+        "smart" => tracks(&cmd.smart(&required_text(params, 0, "rules")?)?),
+        "smart_save" => {
+            let name = required_text(params, 0, "name")?;
+            cmd.smart_save(&name, &required_text(params, 1, "rules")?)?;
+            Value::Null
+        }
+        "smart_list" => {
+            Value::Array(
+                cmd.smart_list()?
+                    .into_iter()
+                    .map(|(name, rules)| json!({ "name": name, "rules": rules }))
+                    .collect(),
+            )
+        }
+        "smart_delete" => {
+            cmd.smart_delete(&required_text(params, 0, "name")?)?;
+            Value::Null
+        }
+        "now" => {
+            json!({
+                "track": cmd.now_playing().as_ref().map(track),
+                "time_remaining": cmd.time_remain(),
+            })
+        }
+        _ => {
+            return Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                &format!("No such method: {0}", req.method),
+            ))
+        }
+    };
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers each request with its method, or fails for `fail`.
+    fn echo(line: &str) -> Option<Value> {
+        handle(line, |req| if req.method == "fail" {
+            Err(RpcError::new(SERVER_ERROR, "Failed"))
+        } else {
+            Ok(Value::String(req.method.clone()))
+        })
+    }
+
+    fn error_code(v: &Value) -> Option<i64> {
+        v["error"]["code"].as_i64()
+    }
+
+    #[test]
+    fn tells_requests_from_commands() {
+        assert!(is_request("{\"jsonrpc\": \"2.0\"}"));
+        assert!(is_request("  [{\"jsonrpc\": \"2.0\"}]"));
+        assert!(!is_request("queue 2"));
+        assert!(!is_request(""));
+    }
+
+    #[test]
+    fn answers_a_request() {
+        let resp = echo(r#"{"jsonrpc": "2.0", "id": 7, "method": "now"}"#).unwrap();
+        assert_eq!(resp, json!({"jsonrpc": "2.0", "id": 7, "result": "now"}));
+        let resp = echo(r#"{"jsonrpc": "2.0", "id": "a", "method": "fail"}"#).unwrap();
+        assert_eq!(resp["id"], json!("a"));
+        assert_eq!(error_code(&resp), Some(SERVER_ERROR));
+    }
+
+    #[test]
+    fn leaves_notifications_unanswered() {
+        assert_eq!(echo(r#"{"jsonrpc": "2.0", "method": "skip"}"#), None);
+        let batch = r#"[{"jsonrpc": "2.0", "method": "skip"},
+                        {"jsonrpc": "2.0", "method": "pause"}]"#;
+        assert_eq!(echo(batch), None);
+    }
+
+    #[test]
+    fn answers_a_batch_in_order() {
+        let resp = echo(
+            r#"[{"jsonrpc": "2.0", "id": 1, "method": "skip"},
+                {"jsonrpc": "2.0", "method": "pause"},
+                {"jsonrpc": "2.0", "id": 2, "method": "fail"},
+                {"jsonrpc": "1.0", "id": 3, "method": "now"},
+                4,
+                {"jsonrpc": "2.0", "id": 5, "method": "now"}]"#,
+        ).unwrap();
+        let resps = resp.as_array().unwrap();
+        assert_eq!(resps.len(), 5);
+        assert_eq!(resps[0], json!({"jsonrpc": "2.0", "id": 1, "result": "skip"}));
+        assert_eq!(error_code(&resps[1]), Some(SERVER_ERROR));
+        assert_eq!(error_code(&resps[2]), Some(INVALID_REQUEST));
+        assert_eq!(resps[2]["id"], Value::Null);
+        assert_eq!(error_code(&resps[3]), Some(INVALID_REQUEST));
+        assert_eq!(resps[4]["result"], json!("now"));
+    }
+
+    #[test]
+    fn rejects_bad_json() {
+        assert_eq!(error_code(&echo("[]").unwrap()), Some(INVALID_REQUEST));
+        assert_eq!(error_code(&echo("{\"jsonrpc\"").unwrap()), Some(PARSE_ERROR));
+        assert_eq!(error_code(&echo("[1, 2").unwrap()), Some(PARSE_ERROR));
+        assert_eq!(
+            error_code(&echo(r#"{"jsonrpc": "2.0", "id": 1}"#).unwrap()),
+            Some(INVALID_REQUEST)
+        );
+    }
+}
@@ -0,0 +1,421 @@
+//! Smart playlists: rules that pick tracks out of the library by ratings,
+//! plays and downloads, e.g. `rating>=4 played>30d` for well liked tracks
+//! not heard in a month.
+
+use backend::BackendType;
+use error::{Result, SurgeError};
+
+use std::str::FromStr;
+
+/// Tracks queued when a query doesn't say `limit:`
+const DEFAULT_LIMIT: usize = 50;
+const ORDERS: &'static [&'static str] = &["plays", "rating", "recent", "title", "random"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cmp {
+    Lt,
+    Le,
+    Eq,
+    Ge,
+    Gt,
+}
+
+impl Cmp {
+    pub fn sql(&self) -> &'static str {
+        match *self {
+            Cmp::Lt => "<",
+            Cmp::Le => "<=",
+            Cmp::Eq => "=",
+            Cmp::Ge => ">=",
+            Cmp::Gt => ">",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rule {
+    /// Stars; unrated tracks never match
+    Rating(Cmp, u64),
+    /// Plays that didn't fail, counting only those within `since`
+    Plays(Cmp, u64),
+    /// Seconds since the track was last played, never played tracks being
+    /// as long ago as can be
+    Played(Cmp, u64),
+    Downloaded(bool),
+    Favorite(bool),
+    Source(BackendType),
+    /// Found in the title or artist, ignoring case
+    Text(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    /// Most played first
+    Plays,
+    /// Best rated first
+    Rating,
+    /// Most recently played first
+    Recent,
+    Title,
+    Random,
+}
+
+/// E.g. `rating>=4 played>30d`, `since:30d plays>=1 order:plays limit:20`
+/// or `downloaded:yes plays=0`. Every rule has to match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SmartQuery {
+    pub rules: Vec<Rule>,
+    /// Plays longer ago than this many seconds don't count for `plays`
+    pub since: Option<u64>,
+    pub order: Option<Order>,
+    pub limit: usize,
+}
+
+impl FromStr for SmartQuery {
+    type Err = SurgeError;
+
+    /// `rating`, `plays` and `played` compare with `<`, `<=`, `=`, `>=` or
+    /// `>`; `since`, `downloaded`, `fav`, `source`, `order` and `limit` take
+    /// `key:value`. Any other word is looked for in titles and artists.
+    fn from_str(s: &str) -> Result<SmartQuery> {
+        let mut query = SmartQuery {
+            rules: vec![],
+            since: None,
+            order: None,
+            limit: DEFAULT_LIMIT,
+        };
+        for word in s.split_whitespace() {
+            let split = word.find(|c| match c {
+                '<' | '=' | '>' | ':' => true,
+                _ => false,
+            });
+            let (key, rest) = match split {
+                Some(i) => (&word[..i], &word[i..]),
+                None => {
+                    query.rules.push(Rule::Text(word.to_lowercase()));
+                    continue;
+                }
+            };
+            match key {
+                "rating" => {
+                    let (cmp, value) = comparison(word, rest)?;
+                    query.rules.push(Rule::Rating(cmp, number(word, value)?));
+                }
+                "plays" => {
+                    let (cmp, value) = comparison(word, rest)?;
+                    query.rules.push(Rule::Plays(cmp, number(word, value)?));
+                }
+                "played" => {
+                    let (cmp, value) = comparison(word, rest)?;
+                    query.rules.push(Rule::Played(cmp, age(value)?));
+                }
+                "since" => query.since = Some(age(setting(word, rest)?)?),
+                "downloaded" => query.rules.push(Rule::Downloaded(yes_no(word, rest)?)),
+                "fav" => query.rules.push(Rule::Favorite(yes_no(word, rest)?)),
+                "source" => {
+                    query.rules.push(Rule::Source(setting(word, rest)?.parse()?));
+                }
+                "order" => {
+                    query.order = Some(match setting(word, rest)? {
+                        "plays" => Order::Plays,
+                        "rating" => Order::Rating,
+                        "recent" => Order::Recent,
+                        "title" => Order::Title,
+                        "random" => Order::Random,
+                        x => {
+                            return Err(SurgeError::Usage(format!(
+                                "Invalid order '{0}', expected one of: {1}",
+                                x,
+                                ORDERS.join(", ")
+                            )))
+                        }
+                    })
+                }
+                "limit" => {
+                    query.limit = number(word, setting(word, rest)?)? as usize;
+                    if query.limit == 0 {
+                        return Err(SurgeError::Usage(String::from("limit has to be at least 1")));
+                    }
+                }
+                _ => query.rules.push(Rule::Text(word.to_lowercase())),
+            }
+        }
+        if query.rules.is_empty() {
+            return Err(SurgeError::Usage(String::from(
+                "Missing rules, e.g. 'rating>=4 played>30d' or 'downloaded:yes plays=0'",
+            )));
+        }
+        Ok(query)
+    }
+}
+
+/// `>=4` as `(Ge, "4")`.
+fn comparison<'a>(word: &str, rest: &'a str) -> Result<(Cmp, &'a str)> {
+    // two-character operators first, so `>=` isn't read as `>`
+    let ops = [
+        ("<=", Cmp::Le),
+        (">=", Cmp::Ge),
+        ("<", Cmp::Lt),
+        (">", Cmp::Gt),
+        ("=", Cmp::Eq),
+    ];
+    for &(op, cmp) in &ops {
+        if rest.starts_with(op) && rest.len() > op.len() {
+            return Ok((cmp, &rest[op.len()..]));
+        }
+    }
+    Err(SurgeError::Usage(format!(
+        "Invalid rule '{0}', expected a comparison like rating>=4",
+        word
+    )))
+}
+
+/// The value of `key:value`.
+fn setting<'a>(word: &str, rest: &'a str) -> Result<&'a str> {
+    if rest.starts_with(':') && rest.len() > 1 {
+        Ok(&rest[1..])
+    } else {
+        Err(SurgeError::Usage(
+            format!("Invalid rule '{0}', expected key:value", word),
+        ))
+    }
+}
+
+fn number(word: &str, x: &str) -> Result<u64> {
+    x.parse().map_err(|_| {
+        SurgeError::Usage(format!("Invalid rule '{0}', '{1}' isn't a number", word, x))
+    })
+}
+
+fn yes_no(word: &str, rest: &str) -> Result<bool> {
+    match setting(word, rest)? {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(SurgeError::Usage(
+            format!("Invalid rule '{0}', expected yes or no", word),
+        )),
+    }
+}
+
+/// `12h`, `30d`, `2w` or `1y` in seconds.
+fn age(x: &str) -> Result<u64> {
+    let split = x.find(|c: char| !c.is_digit(10)).unwrap_or(x.len());
+    let unit = match &x[split..] {
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        "y" => 365 * 86400,
+        _ => 0,
+    };
+    let n = match x[..split].parse::<u64>() {
+        Ok(n) if unit > 0 => n,
+        _ => {
+            return Err(SurgeError::Usage(format!(
+                "Invalid age '{0}', expected e.g. 12h, 30d, 2w or 1y",
+                x
+            )))
+        }
+    };
+    n.checked_mul(unit).ok_or_else(|| {
+        SurgeError::Usage(format!("Invalid age '{0}', that's too long ago", x))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::BackendSearchResult;
+    use history::{self, Outcome, Play};
+    use library::Library;
+
+    const DAY: u64 = 86400;
+
+    fn query(s: &str) -> SmartQuery {
+        s.parse().unwrap()
+    }
+
+    fn usage(s: &str) -> String {
+        match s.parse::<SmartQuery>() {
+            Err(SurgeError::Usage(x)) => x,
+            x => panic!("'{0}' gave {1:?}", s, x),
+        }
+    }
+
+    fn track(id: &str, title: &str) -> BackendSearchResult {
+        BackendSearchResult::new(String::from(id), String::from(title), BackendType::Ytdl)
+    }
+
+    fn play(library: &mut Library, x: &BackendSearchResult, days_ago: u64, outcome: Outcome) {
+        library
+            .record_play(&Play {
+                track: x.clone(),
+                started: history::unix_now() - days_ago * DAY,
+                listened: 60,
+                outcome: outcome,
+            })
+            .unwrap();
+    }
+
+    fn ids(library: &Library, s: &str) -> Vec<String> {
+        library
+            .smart(&query(s))
+            .unwrap()
+            .into_iter()
+            .map(|x| x.id)
+            .collect()
+    }
+
+    /// a: rated 5, last played 40 days ago
+    /// b: rated 4, played yesterday
+    /// c: played three times this week and once two months ago, downloaded
+    /// d: downloaded, never played
+    /// e: only ever a search result
+    /// f: only failed to play
+    fn library() -> Library {
+        let mut library = Library::in_memory().unwrap();
+        let (a, b, c) = (track("a", "Alpha"), track("b", "Bravo"), track("c", "Charlie"));
+        let (d, e, f) = (track("d", "Delta"), track("e", "Echo"), track("f", "Foxtrot"));
+        library.cache_tracks(&[a.clone(), e.clone()]).unwrap();
+        library.rate(&a, 5).unwrap();
+        play(&mut library, &a, 40, Outcome::Completed);
+        library.rate(&b, 4).unwrap();
+        play(&mut library, &b, 1, Outcome::Skipped);
+        for &days_ago in &[1, 2, 3, 60] {
+            play(&mut library, &c, days_ago, Outcome::Completed);
+        }
+        library.record_download(&c, "/music/c.m4a").unwrap();
+        library.record_download(&d, "/music/d.m4a").unwrap();
+        play(&mut library, &f, 1, Outcome::Failed);
+        library
+    }
+
+    #[test]
+    fn parses_examples() {
+        assert_eq!(
+            query("rating>=4 played>30d"),
+            SmartQuery {
+                rules: vec![Rule::Rating(Cmp::Ge, 4), Rule::Played(Cmp::Gt, 30 * DAY)],
+                since: None,
+                order: None,
+                limit: DEFAULT_LIMIT,
+            }
+        );
+        assert_eq!(
+            query("since:30d plays>=1 order:plays"),
+            SmartQuery {
+                rules: vec![Rule::Plays(Cmp::Ge, 1)],
+                since: Some(30 * DAY),
+                order: Some(Order::Plays),
+                limit: DEFAULT_LIMIT,
+            }
+        );
+        assert_eq!(
+            query("downloaded:yes plays=0").rules,
+            vec![Rule::Downloaded(true), Rule::Plays(Cmp::Eq, 0)]
+        );
+    }
+
+    #[test]
+    fn parses_settings_and_text() {
+        let x = query("Daft fav:no source:local limit:5 order:random");
+        assert_eq!(
+            x.rules,
+            vec![
+                Rule::Text(String::from("daft")),
+                Rule::Favorite(false),
+                Rule::Source(BackendType::Local),
+            ]
+        );
+        assert_eq!((x.order, x.limit), (Some(Order::Random), 5));
+        assert_eq!(query("source:stream").rules, vec![Rule::Source(BackendType::Stream)]);
+    }
+
+    #[test]
+    fn two_character_operators_win() {
+        assert_eq!(query("rating>=4").rules, vec![Rule::Rating(Cmp::Ge, 4)]);
+        assert_eq!(query("rating>4").rules, vec![Rule::Rating(Cmp::Gt, 4)]);
+        assert_eq!(query("plays<=2").rules, vec![Rule::Plays(Cmp::Le, 2)]);
+        assert_eq!(query("plays<2").rules, vec![Rule::Plays(Cmp::Lt, 2)]);
+        assert_eq!(query("played<12h").rules, vec![Rule::Played(Cmp::Lt, 12 * 3600)]);
+        assert_eq!(query("played>=2w").rules, vec![Rule::Played(Cmp::Ge, 14 * DAY)]);
+        assert_eq!(query("played=1y").rules, vec![Rule::Played(Cmp::Eq, 365 * DAY)]);
+    }
+
+    #[test]
+    fn rejects_bad_comparisons() {
+        assert_eq!(
+            usage("rating>"),
+            "Invalid rule 'rating>', expected a comparison like rating>=4"
+        );
+        assert_eq!(
+            usage("rating:4"),
+            "Invalid rule 'rating:4', expected a comparison like rating>=4"
+        );
+        assert_eq!(usage("plays>=x"), "Invalid rule 'plays>=x', 'x' isn't a number");
+        assert_eq!(usage("rating>=-1"), "Invalid rule 'rating>=-1', '-1' isn't a number");
+    }
+
+    #[test]
+    fn rejects_bad_ages() {
+        for x in &["played>30", "played>30m", "played>d", "since:x"] {
+            assert!(usage(x).starts_with("Invalid age"), "{0}", x);
+        }
+        assert_eq!(
+            usage("played>99999999999999y"),
+            "Invalid age '99999999999999y', that's too long ago"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        assert_eq!(usage("since=30d"), "Invalid rule 'since=30d', expected key:value");
+        assert_eq!(usage("order:"), "Invalid rule 'order:', expected key:value");
+        assert_eq!(usage("fav:maybe"), "Invalid rule 'fav:maybe', expected yes or no");
+        assert_eq!(
+            usage("order:loudest plays=0"),
+            "Invalid order 'loudest', expected one of: plays, rating, recent, title, random"
+        );
+        assert_eq!(usage("limit:0 plays=0"), "limit has to be at least 1");
+        assert!("source:cassette".parse::<SmartQuery>().is_err());
+    }
+
+    #[test]
+    fn needs_a_rule() {
+        for x in &["", "  ", "limit:5", "since:30d order:plays"] {
+            assert!(usage(x).starts_with("Missing rules"), "{0}", x);
+        }
+    }
+
+    #[test]
+    fn finds_examples() {
+        let library = library();
+        assert_eq!(ids(&library, "rating>=4 played>30d"), vec!["a"]);
+        assert_eq!(ids(&library, "since:30d plays>=1 order:plays"), vec!["c", "b"]);
+        assert_eq!(ids(&library, "downloaded:yes plays=0"), vec!["d"]);
+    }
+
+    #[test]
+    fn leaves_out_search_results_and_failed_plays() {
+        let library = library();
+        assert_eq!(ids(&library, "plays>=0"), vec!["a", "b", "c", "d", "f"]);
+        assert_eq!(ids(&library, "plays>=1"), vec!["a", "b", "c"]);
+        assert_eq!(ids(&library, "echo"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn orders_and_limits() {
+        let library = library();
+        assert_eq!(ids(&library, "plays>=1 order:plays limit:1"), vec!["c"]);
+        assert_eq!(ids(&library, "plays>=0 order:rating limit:3"), vec!["a", "b", "c"]);
+        assert_eq!(ids(&library, "plays>=1 order:recent"), vec!["b", "c", "a"]);
+        assert_eq!(ids(&library, "CHAR order:title"), vec!["c"]);
+    }
+
+    #[test]
+    fn clamps_huge_numbers() {
+        let library = library();
+        assert_eq!(ids(&library, "rating<18446744073709551615"), vec!["a", "b"]);
+        assert_eq!(ids(&library, "played<9999999999y plays>=1"), vec!["a", "b", "c"]);
+        assert_eq!(ids(&library, "since:9999999999y plays>=4"), vec!["c"]);
+    }
+}
@@ -0,0 +1,294 @@
+//! Just enough tag reading to index a music library: title, artist and
+//! album from FLAC, MP3, Ogg Vorbis and Opus files.
+
+use id3;
+use metaflac;
+
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read};
+use std::mem;
+use std::path::Path;
+
+pub const AUDIO_EXTENSIONS: &'static [&'static str] = &["flac", "mp3", "ogg", "oga", "opus"];
+
+/// Stop looking for the comment header of an Ogg file after this much data,
+/// which leaves room for embedded cover art
+const OGG_MAX_HEADER_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .map(|x| x.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+pub fn is_audio_file(path: &Path) -> bool {
+    AUDIO_EXTENSIONS.contains(&extension(path).as_str())
+}
+
+/// Tags of an audio file; anything that can't be read is left out rather
+/// than failing, so untagged files can still be found by name.
+pub fn read_tags(path: &Path) -> Tags {
+    match extension(path).as_str() {
+        "flac" => {
+            metaflac::Tag::read_from_path(path)
+                .ok()
+                .and_then(|tag| {
+                    tag.vorbis_comments().map(|c| {
+                        let first = |x: Option<&Vec<String>>| x.and_then(|x| x.first().cloned());
+                        Tags {
+                            title: first(c.title()),
+                            artist: first(c.artist()),
+                            album: first(c.album()),
+                        }
+                    })
+                })
+                .unwrap_or_default()
+        }
+        "mp3" => {
+            id3::Tag::read_from_path(path)
+                .map(|tag| {
+                    Tags {
+                        title: tag.title().map(String::from),
+                        artist: tag.artist().map(String::from),
+                        album: tag.album().map(String::from),
+                    }
+                })
+                .unwrap_or_default()
+        }
+        "ogg" | "oga" | "opus" => read_ogg_tags(path).unwrap_or_default(),
+        _ => Tags::default(),
+    }
+}
+
+fn read_ogg_tags(path: &Path) -> io::Result<Tags> {
+    ogg_tags(&mut BufReader::new(File::open(path)?))
+}
+
+/// Vorbis and Opus both keep their tags in the second packet of the
+/// stream, as a Vorbis comment block behind a codec-specific magic.
+fn ogg_tags<R: Read>(reader: &mut R) -> io::Result<Tags> {
+    let mut packets = vec![];
+    let mut packet = vec![];
+    let mut read = 0;
+
+    while packets.len() < 2 {
+        if read > OGG_MAX_HEADER_BYTES {
+            return Err(invalid_data("Ogg comment header too large"));
+        }
+        let mut header = [0u8; 27];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != b"OggS" {
+            return Err(invalid_data("Not an Ogg page"));
+        }
+        let mut lacing = vec![0u8; header[26] as usize];
+        reader.read_exact(&mut lacing)?;
+        for len in lacing {
+            let mut segment = vec![0u8; len as usize];
+            reader.read_exact(&mut segment)?;
+            read += segment.len();
+            packet.extend_from_slice(&segment);
+            // a segment shorter than 255 bytes ends the packet
+            if len < 255 {
+                packets.push(mem::replace(&mut packet, vec![]));
+            }
+        }
+    }
+
+    let comments = &packets[1];
+    if comments.starts_with(b"\x03vorbis") {
+        read_vorbis_comments(&mut Cursor::new(&comments[7..]))
+    } else if comments.starts_with(b"OpusTags") {
+        read_vorbis_comments(&mut Cursor::new(&comments[8..]))
+    } else {
+        Err(invalid_data("Unknown Ogg codec"))
+    }
+}
+
+/// Lengths are checked against what's actually there, so a corrupt one
+/// fails the read instead of allocating for it.
+fn read_vorbis_comments<R: Read>(r: &mut R) -> io::Result<Tags> {
+    let vendor_len = read_u32_le(r)? as u64;
+    if io::copy(&mut r.by_ref().take(vendor_len), &mut io::sink())? < vendor_len {
+        return Err(invalid_data("Vorbis vendor string cut short"));
+    }
+
+    let mut tags = Tags::default();
+    for _ in 0..read_u32_le(r)? {
+        let len = read_u32_le(r)? as usize;
+        if len > OGG_MAX_HEADER_BYTES {
+            return Err(invalid_data("Vorbis comment too large"));
+        }
+        let mut comment = vec![];
+        if r.by_ref().take(len as u64).read_to_end(&mut comment)? < len {
+            return Err(invalid_data("Vorbis comment cut short"));
+        }
+
+        let comment = String::from_utf8_lossy(&comment);
+        let mut kv = comment.splitn(2, '=');
+        if let (Some(k), Some(v)) = (kv.next(), kv.next()) {
+            let slot = match k.to_lowercase().as_str() {
+                "title" => &mut tags.title,
+                "artist" => &mut tags.artist,
+                "album" => &mut tags.album,
+                _ => continue,
+            };
+            // the first of repeated fields wins, like metaflac's first()
+            if slot.is_none() {
+                *slot = Some(String::from(v));
+            }
+        }
+    }
+    Ok(tags)
+}
+
+fn read_u32_le<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(
+        (buf[0] as u32) | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24,
+    )
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn le(n: u32) -> Vec<u8> {
+        vec![n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+    }
+
+    /// A Vorbis comment block with `vendor` and `comments`.
+    fn comment_block(vendor: &str, comments: &[&str]) -> Vec<u8> {
+        let mut ret = le(vendor.len() as u32);
+        ret.extend_from_slice(vendor.as_bytes());
+        ret.extend(le(comments.len() as u32));
+        for x in comments {
+            ret.extend(le(x.len() as u32));
+            ret.extend_from_slice(x.as_bytes());
+        }
+        ret
+    }
+
+    /// An Ogg page of `body`, cut into segments by `lacing`. Checksums
+    /// aren't checked, so they're left at 0.
+    fn raw_page(lacing: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut ret = b"OggS".to_vec();
+        ret.extend(vec![0u8; 22]);
+        ret.push(lacing.len() as u8);
+        ret.extend_from_slice(lacing);
+        ret.extend_from_slice(body);
+        ret
+    }
+
+    /// An Ogg page holding whole `packets`.
+    fn ogg_page(packets: &[&[u8]]) -> Vec<u8> {
+        let mut lacing = vec![];
+        for x in packets {
+            lacing.extend(vec![255u8; x.len() / 255]);
+            lacing.push((x.len() % 255) as u8);
+        }
+        raw_page(&lacing, &packets.concat())
+    }
+
+    fn vorbis_file(comments: &[u8]) -> Vec<u8> {
+        let mut packet = b"\x03vorbis".to_vec();
+        packet.extend_from_slice(comments);
+        let mut ret = ogg_page(&[b"\x01vorbis identification"]);
+        ret.extend(ogg_page(&[&packet, b"\x05vorbis setup"]));
+        ret
+    }
+
+    fn tags_of(bytes: &[u8]) -> io::Result<Tags> {
+        ogg_tags(&mut Cursor::new(bytes))
+    }
+
+    #[test]
+    fn vorbis_tags() {
+        let block = comment_block(
+            "libVorbis",
+            &["TITLE=One More Time", "Artist=Daft Punk", "album=Discovery", "GENRE=House"],
+        );
+        let tags = tags_of(&vorbis_file(&block)).unwrap();
+        assert_eq!(tags.title.as_ref().unwrap(), "One More Time");
+        assert_eq!(tags.artist.as_ref().unwrap(), "Daft Punk");
+        assert_eq!(tags.album.as_ref().unwrap(), "Discovery");
+    }
+
+    #[test]
+    fn opus_tags() {
+        let mut packet = b"OpusTags".to_vec();
+        packet.extend(comment_block("libopus", &["title=a=b", "artist=First", "artist=Second"]));
+        let mut bytes = ogg_page(&[b"OpusHead"]);
+        bytes.extend(ogg_page(&[&packet]));
+        let tags = tags_of(&bytes).unwrap();
+        assert_eq!(tags.title.as_ref().unwrap(), "a=b");
+        assert_eq!(tags.artist.as_ref().unwrap(), "First");
+        assert!(tags.album.is_none());
+    }
+
+    #[test]
+    fn comment_packets_can_span_pages() {
+        let title = format!("TITLE={0}", "x".repeat(600));
+        let mut packet = b"\x03vorbis".to_vec();
+        packet.extend(comment_block("libVorbis", &[&title]));
+        // full segments only, so the packet carries on into the next page
+        let mut bytes = ogg_page(&[b"\x01vorbis"]);
+        bytes.extend(raw_page(&[255, 255], &packet[..510]));
+        bytes.extend(ogg_page(&[&packet[510..]]));
+        assert_eq!(tags_of(&bytes).unwrap().title.unwrap(), "x".repeat(600));
+    }
+
+    #[test]
+    fn truncated_files_fail_without_panicking() {
+        let bytes = vorbis_file(&comment_block("libVorbis", &["TITLE=x", "ARTIST=y"]));
+        for len in 0..bytes.len() {
+            assert!(tags_of(&bytes[..len]).is_err(), "{0} bytes", len);
+        }
+    }
+
+    #[test]
+    fn lengths_past_the_packet_fail() {
+        let mut block = comment_block("libVorbis", &[]);
+        block.truncate(block.len() - 4);
+        block.extend(le(1));
+        block.extend(le(1000));
+        block.extend_from_slice(b"TITLE=x");
+        assert!(tags_of(&vorbis_file(&block)).is_err());
+
+        let mut huge = le(9);
+        huge.extend_from_slice(b"libVorbis");
+        huge.extend(le(1));
+        huge.extend(le(u32::max_value()));
+        assert!(tags_of(&vorbis_file(&huge)).is_err());
+
+        let mut vendor = le(1000);
+        vendor.extend_from_slice(b"libVorbis");
+        assert!(tags_of(&vorbis_file(&vendor)).is_err());
+    }
+
+    #[test]
+    fn comment_counts_past_the_packet_fail() {
+        let mut block = comment_block("libVorbis", &["TITLE=x"]);
+        block[13] = 200;
+        assert!(read_vorbis_comments(&mut Cursor::new(&block)).is_err());
+    }
+
+    #[test]
+    fn other_streams_fail() {
+        assert!(tags_of(b"RIFF\x00\x00\x00\x00WAVEfmt ").is_err());
+        let mut bytes = ogg_page(&[b"\x80theora"]);
+        bytes.extend(ogg_page(&[b"\x81theora comments"]));
+        assert!(tags_of(&bytes).is_err());
+    }
+}
@@ -1,96 +1,480 @@
 use std::io::Read;
 
-use backend::{BackendSearchResult, Backend};
+use backend::{BackendSearchResult, Backend, BackendType, ResultKind, ResultPage, SearchQuery};
+use error::{Result, SurgeError};
 
 use hyper::Client;
 use serde_json;
+use url::form_urlencoded;
 
 const YT_API_URL: &'static str = "https://www.googleapis.com/youtube/v3";
+/// The most results the api hands out per page
+const YT_MAX_RESULTS: usize = 50;
 
 pub struct YoutubeBackend {
     youtube_api_key: String,
     client: Client,
+    max_results: usize,
 }
 
 impl YoutubeBackend {
-    pub fn new(youtube_api_key: String, client: Client) -> YoutubeBackend {
+    pub fn new(youtube_api_key: String, client: Client, max_results: usize) -> YoutubeBackend {
         YoutubeBackend {
             youtube_api_key: youtube_api_key,
             client: client,
+            max_results: max_results.min(YT_MAX_RESULTS),
         }
     }
 
-    fn hyper_request(&self, url: &str) -> String {
-        let res = self.client
-            .get(format!("{0}&key={1}", url, self.youtube_api_key).as_str())
-            .send();
+    /// GET an api endpoint, e.g. `search`, see `api_url`.
+    fn hyper_request(&self, endpoint: &str, params: &[(&str, &str)]) -> Result<String> {
+        let mut res = self.client
+            .get(api_url(endpoint, params, &self.youtube_api_key).as_str())
+            .send()?;
         let mut ret = String::new();
-        match res {
-            Ok(mut res) => {
-                match res.read_to_string(&mut ret) {
-                    Ok(_) => (),
-                    Err(e) => panic!(e),
-                }
+        res.read_to_string(&mut ret)?;
+        if !res.status.is_success() {
+            // the api explains itself in an error object, e.g. quotaExceeded
+            let reason = serde_json::from_str::<serde_json::Value>(&ret)
+                .ok()
+                .and_then(|x| {
+                    x["error"]["errors"][0]["reason"].as_str().map(String::from)
+                });
+            return Err(SurgeError::Backend(match reason {
+                Some(x) => format!("Youtube api returned {0}: {1}", res.status, x),
+                None => format!("Youtube api returned {0}", res.status),
+            }));
+        }
+        Ok(ret)
+    }
+
+    /// Search results only carry snippets, so durations and view counts
+    /// take another call, made once for the whole page.
+    fn add_details(&self, results: &mut [BackendSearchResult]) -> Result<()> {
+        if results.is_empty() {
+            return Ok(());
+        }
+        let ids = results
+            .iter()
+            .map(|x| x.id.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+        let api_result = self.hyper_request(
+            "videos",
+            &[("part", "contentDetails,statistics"), ("id", &ids)],
+        )?;
+
+        let x = serde_json::from_str::<serde_json::Value>(&api_result)?;
+        let items = match x["items"].as_array() {
+            Some(x) => x,
+            None => {
+                return Err(SurgeError::Backend(
+                    String::from("Didn't get expected response from youtube api"),
+                ))
+            }
+        };
+        for video_obj in items {
+            let id = video_obj["id"].as_str();
+            if let Some(result) = results.iter_mut().find(|x| Some(x.id.as_str()) == id) {
+                result.duration = video_obj["contentDetails"]["duration"]
+                    .as_str()
+                    .and_then(parse_duration);
+                result.views = video_obj["statistics"]["viewCount"]
+                    .as_str()
+                    .and_then(|x| x.parse().ok());
             }
-            Err(e) => panic!(e),
         }
-        ret
+        Ok(())
+    }
+
+    /// A page of results, with the details filled in where possible; they
+    /// are a nicety, so failing to fetch them doesn't fail the search.
+    fn page(&self, api_result: &str, kind: ResultKind) -> Result<ResultPage> {
+        let mut page = yt_json_parser(api_result, kind)?;
+        if kind == ResultKind::Track {
+            let _ = self.add_details(&mut page.results);
+        }
+        Ok(page)
+    }
+
+    /// The playlist a collection's tracks are listed from.
+    fn collection_playlist(&self, id: &str, kind: ResultKind) -> Result<String> {
+        match kind {
+            ResultKind::Channel => self.uploads_playlist(id),
+            _ => Ok(String::from(id)),
+        }
+    }
+
+    fn playlist_items(
+        &self,
+        playlist_id: &str,
+        max_results: usize,
+        page: Option<&str>,
+    ) -> Result<ResultPage> {
+        let max_results = max_results.to_string();
+        let mut params = vec![
+            ("part", "snippet"),
+            ("playlistId", playlist_id),
+            ("maxResults", max_results.as_str()),
+        ];
+        if let Some(x) = page {
+            params.push(("pageToken", x));
+        }
+        let api_result = self.hyper_request("playlistItems", &params)?;
+        self.page(&api_result, ResultKind::Track)
+    }
+
+    /// A channel's uploads are a playlist of their own.
+    fn uploads_playlist(&self, channel_id: &str) -> Result<String> {
+        let api_result = self.hyper_request(
+            "channels",
+            &[("part", "contentDetails"), ("id", channel_id)],
+        )?;
+        let x = serde_json::from_str::<serde_json::Value>(&api_result)?;
+        match x["items"][0]["contentDetails"]["relatedPlaylists"]["uploads"].as_str() {
+            Some(x) => Ok(String::from(x)),
+            None => Err(SurgeError::Backend(
+                format!("Youtube has no uploads for channel {0}", channel_id),
+            )),
+        }
     }
 }
 
 impl Backend for YoutubeBackend {
-    fn find_related_tracks(&self, video_id: &str) -> Vec<BackendSearchResult> {
-        let api_result = self.hyper_request(
-            format!(
-                "{0}/search?part=snippet&relatedToVideoId={1}&type=video",
-                YT_API_URL,
-                video_id
-            ).as_str(),
-        );
+    fn find_related_tracks(&self, video_id: &str, page: Option<&str>) -> Result<ResultPage> {
+        let max_results = self.max_results.to_string();
+        let mut params = vec![
+            ("part", "snippet"),
+            ("relatedToVideoId", video_id),
+            ("type", "video"),
+            ("maxResults", max_results.as_str()),
+        ];
+        if let Some(x) = page {
+            params.push(("pageToken", x));
+        }
+        let api_result = self.hyper_request("search", &params)?;
+
+        self.page(&api_result, ResultKind::Track)
+    }
+
+    fn search(&self, query: &SearchQuery, page: Option<&str>) -> Result<ResultPage> {
+        let max_results = self.max_results.to_string();
+        let mut params = vec![
+            ("part", "snippet"),
+            ("q", query.terms.as_str()),
+            ("type", "video"),
+            ("maxResults", max_results.as_str()),
+        ];
+        if let Some(x) = page {
+            params.push(("pageToken", x));
+        }
+        let filters = [
+            ("videoDuration", &query.duration),
+            ("order", &query.order),
+            ("regionCode", &query.region),
+            ("publishedAfter", &query.published_after),
+            ("safeSearch", &query.safe_search),
+            ("videoCategoryId", &query.category),
+        ];
+        for &(name, value) in &filters {
+            if let Some(ref x) = *value {
+                params.push((name, x.as_str()));
+            }
+        }
+        let api_result = self.hyper_request("search", &params)?;
+        self.page(&api_result, ResultKind::Track)
+    }
 
-        yt_json_parser(&api_result)
+    /// Only some filters apply to playlists and channels; the api rejects
+    /// the video ones.
+    fn search_collections(
+        &self,
+        query: &SearchQuery,
+        kind: ResultKind,
+        page: Option<&str>,
+    ) -> Result<ResultPage> {
+        let max_results = self.max_results.to_string();
+        let mut params = vec![
+            ("part", "snippet"),
+            ("q", query.terms.as_str()),
+            (
+                "type",
+                if kind == ResultKind::Channel {
+                    "channel"
+                } else {
+                    "playlist"
+                },
+            ),
+            ("maxResults", max_results.as_str()),
+        ];
+        if let Some(x) = page {
+            params.push(("pageToken", x));
+        }
+        let filters = [
+            ("order", &query.order),
+            ("regionCode", &query.region),
+            ("publishedAfter", &query.published_after),
+            ("safeSearch", &query.safe_search),
+        ];
+        for &(name, value) in &filters {
+            if let Some(ref x) = *value {
+                params.push((name, x.as_str()));
+            }
+        }
+        let api_result = self.hyper_request("search", &params)?;
+        self.page(&api_result, kind)
     }
 
-    fn search(&self, keywords: &str) -> Vec<BackendSearchResult> {
+    fn lookup(&self, video_id: &str) -> Result<BackendSearchResult> {
         let api_result = self.hyper_request(
+            "videos",
+            &[("part", "snippet,contentDetails,statistics"), ("id", video_id)],
+        )?;
+        match yt_json_parser(&api_result, ResultKind::Track)?.results.pop() {
+            Some(x) => Ok(x),
+            None => Err(SurgeError::Backend(
+                format!("Youtube has no video {0}", video_id),
+            )),
+        }
+    }
+
+    fn list_collection(&self, id: &str, kind: ResultKind, page: Option<&str>) -> Result<ResultPage> {
+        let playlist_id = self.collection_playlist(id, kind)?;
+        self.playlist_items(&playlist_id, self.max_results, page)
+    }
+
+    /// Pages as large as the api hands out, with a channel's uploads
+    /// playlist only looked up once.
+    fn collection_tracks(
+        &self,
+        id: &str,
+        kind: ResultKind,
+        max: usize,
+    ) -> Result<Vec<BackendSearchResult>> {
+        let playlist_id = self.collection_playlist(id, kind)?;
+        let mut tracks = vec![];
+        let mut page = None;
+        loop {
+            let x = self.playlist_items(
+                &playlist_id,
+                YT_MAX_RESULTS,
+                page.as_ref().map(String::as_str),
+            )?;
+            tracks.extend(x.results);
+            page = x.next;
+            if page.is_none() || tracks.len() >= max {
+                break;
+            }
+        }
+        tracks.truncate(max);
+        Ok(tracks)
+    }
+}
+
+/// Parses `search` results of the given kind, `videos`, and
+/// `playlistItems`, whose items point at their video rather than being it.
+/// The url of an api endpoint, with `params` and the api key
+/// percent-encoded into the query string.
+fn api_url(endpoint: &str, params: &[(&str, &str)], key: &str) -> String {
+    let query = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .append_pair("key", key)
+        .finish();
+    format!("{0}/{1}?{2}", YT_API_URL, endpoint, query)
+}
+
+fn yt_json_parser(yt_json: &str, kind: ResultKind) -> Result<ResultPage> {
+    let x = serde_json::from_str::<serde_json::Value>(yt_json)?;
+    if let Some(msg) = x["error"]["message"].as_str() {
+        return Err(SurgeError::Backend(format!("Youtube api: {0}", msg)));
+    }
+    let items = match x["items"].as_array() {
+        Some(x) => x,
+        None => {
+            return Err(SurgeError::Backend(
+                String::from("Didn't get expected response from youtube api"),
+            ))
+        }
+    };
+
+    let mut results = vec![];
+    for video_obj in items {
+        let title = match video_obj["snippet"]["title"].as_str() {
+            Some(x) => String::from(x),
+            None => {
+                return Err(SurgeError::Backend(
+                    String::from("Youtube response didn't contain title"),
+                ))
+            }
+        };
+        let id = match kind {
+            ResultKind::Track => {
+                video_obj["id"]["videoId"]
+                    .as_str()
+                    .or_else(|| video_obj["snippet"]["resourceId"]["videoId"].as_str())
+                    .or_else(|| video_obj["id"].as_str())
+            }
+            ResultKind::Playlist => video_obj["id"]["playlistId"].as_str(),
+            ResultKind::Channel => video_obj["id"]["channelId"].as_str(),
+        };
+        let id = match id {
+            Some(x) => String::from(x),
+            None => {
+                return Err(SurgeError::Backend(
+                    String::from("Youtube response didn't contain id"),
+                ))
+            }
+        };
+
+        let snippet = &video_obj["snippet"];
+        let thumbnail = snippet["thumbnails"]["default"]["url"]
+            .as_str()
+            .map(str::to_string);
+        results.push(BackendSearchResult {
+            thumbnail: thumbnail,
+            kind: kind,
+            // a playlist's own channel is the uploader, not whoever made
+            // the playlist
+            artist: snippet["videoOwnerChannelTitle"]
+                .as_str()
+                .or_else(|| snippet["channelTitle"].as_str())
+                .map(String::from),
+            // only `videos` has these, searches need `add_details`
+            duration: video_obj["contentDetails"]["duration"]
+                .as_str()
+                .and_then(parse_duration),
+            // e.g. 2017-06-01T12:00:00.000Z
+            published: snippet["publishedAt"].as_str().and_then(|x| {
+                x.split('T').next().map(String::from)
+            }),
+            views: video_obj["statistics"]["viewCount"]
+                .as_str()
+                .and_then(|x| x.parse().ok()),
+            description: snippet["description"].as_str().and_then(|x| if x.is_empty() {
+                None
+            } else {
+                Some(String::from(x))
+            }),
+            ..BackendSearchResult::new(id, title, BackendType::Youtube)
+        });
+    }
+    Ok(ResultPage {
+        results: results,
+        next: x["nextPageToken"].as_str().map(String::from),
+        prev: x["prevPageToken"].as_str().map(String::from),
+    })
+}
+
+/// Durations come as ISO 8601 durations, e.g. `PT1H2M3S`, or `P1DT2H` for
+/// the longest videos.
+fn parse_duration(x: &str) -> Option<u64> {
+    if !x.starts_with('P') {
+        return None;
+    }
+    let mut secs: u64 = 0;
+    let mut time = false;
+    // whether a number came since `P`, or since `T` if there's one
+    let mut any = false;
+    let mut digits = String::new();
+    for c in x[1..].chars() {
+        let unit = match (c, time) {
+            ('0'...'9', _) => {
+                digits.push(c);
+                continue;
+            }
+            ('T', false) if digits.is_empty() => {
+                time = true;
+                any = false;
+                continue;
+            }
+            ('W', false) => 7 * 24 * 3600,
+            ('D', false) => 24 * 3600,
+            ('H', true) => 3600,
+            ('M', true) => 60,
+            ('S', true) => 1,
+            _ => return None,
+        };
+        let n: u64 = match digits.parse() {
+            Ok(x) => x,
+            Err(_) => return None,
+        };
+        secs = match n.checked_mul(unit).and_then(|x| secs.checked_add(x)) {
+            Some(x) => x,
+            None => return None,
+        };
+        any = true;
+        digits.clear();
+    }
+    if digits.is_empty() && any {
+        Some(secs)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_urls_percent_encode_their_params() {
+        let url = api_url(
+            "search",
+            &[("part", "snippet"), ("q", "AC/DC & Co. #1 100% café+")],
+            "k=y",
+        );
+        assert_eq!(
+            url,
             format!(
-                "{0}/search?part=snippet&q={1}&type=video",
-                YT_API_URL,
-                keywords.replace(" ", "+")
-            ).as_str(),
+                "{0}/search?part=snippet&q=AC%2FDC+%26+Co.+%231+100%25+caf%C3%A9%2B&key=k%3Dy",
+                YT_API_URL
+            )
         );
-        yt_json_parser(&api_result)
     }
-}
 
-fn yt_json_parser(yt_json: &str) -> Vec<BackendSearchResult> {
-    let results: Vec<BackendSearchResult>;
-    match serde_json::from_str::<serde_json::Value>(yt_json) {
-        Ok(ref x) => {
-            results = x["items"]
-                .as_array()
-                .expect("Didn't get expected response from youtube api")
-                .iter()
-                .map(|video_obj| {
-                    let title = String::from(video_obj["snippet"]["title"].as_str().expect(
-                        "Youtube response didn't contain title",
-                    ));
-                    let id = String::from(video_obj["id"]["videoId"].as_str().expect(
-                        "Youtube response didn't contain id",
-                    ));
-
-                    let thumbnail = video_obj["snippet"]["thumbnails"]["default"]["url"]
-                        .as_str()
-                        .map(str::to_string);
-                    BackendSearchResult {
-                        id: id,
-                        title: title,
-                        thumbnail: thumbnail,
-                    }
-                })
-                .collect::<Vec<_>>();
-        }
-        Err(e) => panic!(e),
-    }
-    results
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("PT1H2M3S"), Some(3723));
+        assert_eq!(parse_duration("PT4M13S"), Some(253));
+        assert_eq!(parse_duration("PT45S"), Some(45));
+        assert_eq!(parse_duration("PT2H"), Some(7200));
+        assert_eq!(parse_duration("P1DT2H3M4S"), Some(93784));
+        assert_eq!(parse_duration("P1W"), Some(604800));
+        assert_eq!(parse_duration("PT0S"), Some(0));
+        // live streams
+        assert_eq!(parse_duration("P0D"), Some(0));
+    }
+
+    #[test]
+    fn durations_that_dont_parse() {
+        let cases = [
+            "",
+            "P",
+            "PT",
+            "1H2M",
+            "pt1h",
+            "PT1H2",
+            "PTH",
+            "P1H",
+            "PT1D",
+            "PT1.5S",
+            "PT-1S",
+            "P1DT",
+            "PT1HT2M",
+        ];
+        for x in &cases {
+            assert_eq!(parse_duration(x), None, "{0}", x);
+        }
+    }
+
+    #[test]
+    fn durations_that_overflow() {
+        assert_eq!(
+            parse_duration("PT18446744073709551615S"),
+            Some(u64::max_value())
+        );
+        assert_eq!(parse_duration("PT18446744073709551616S"), None);
+        assert_eq!(parse_duration("PT1M18446744073709551615S"), None);
+        assert_eq!(parse_duration("P99999999999999W"), None);
+    }
 }
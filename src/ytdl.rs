@@ -0,0 +1,340 @@
+use backend::{self, BackendSearchResult, Backend, BackendType, ResultKind, ResultPage,
+              SearchQuery};
+use error::{Result, SurgeError};
+
+use serde_json;
+use url::form_urlencoded;
+
+use std::process::{Command, Stdio};
+
+/// Tried in order when surge.ini doesn't name a program
+const YTDL_PROGRAMS: &'static [&'static str] = &["yt-dlp", "youtube-dl"];
+/// The `sp` of YouTube's results page that keeps only playlists or channels
+const PLAYLISTS_FILTER: &'static str = "EgIQAw%3D%3D";
+const CHANNELS_FILTER: &'static str = "EgIQAg%3D%3D";
+
+/// Searches YouTube through youtube-dl's own scraper, so no API key or
+/// quota is needed.
+pub struct YtdlBackend {
+    program: String,
+    max_results: usize,
+}
+
+/// The `ytdl_program` of surge.ini, or else whichever of yt-dlp and
+/// youtube-dl is installed. Searches and downloads both run it.
+pub fn program(configured: Option<&str>) -> String {
+    match configured {
+        Some(x) => String::from(x),
+        None => {
+            let found = YTDL_PROGRAMS.iter().find(|x| {
+                Command::new(x)
+                    .arg("--version")
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .map(|status| status.success())
+                    .unwrap_or(false)
+            });
+            // if neither is installed, the error surfaces on first use
+            String::from(*found.unwrap_or(&YTDL_PROGRAMS[1]))
+        }
+    }
+}
+
+impl YtdlBackend {
+    pub fn new(program: &str, max_results: usize) -> YtdlBackend {
+        YtdlBackend {
+            program: String::from(program),
+            max_results: max_results,
+        }
+    }
+
+    /// `end` stops listing a long playlist after that many entries.
+    fn dump_json(&self, target: &str, end: Option<usize>) -> Result<String> {
+        let mut command = Command::new(&self.program);
+        command.args(&["--dump-json", "--flat-playlist", "--no-warnings"]);
+        if let Some(x) = end {
+            command.arg("--playlist-end").arg(x.to_string());
+        }
+        let output = command
+            .arg(target)
+            .output()
+            .map_err(|e| {
+                SurgeError::Backend(format!("Couldn't run {0}: {1}", self.program, e))
+            })?;
+        if !output.status.success() {
+            return Err(SurgeError::Backend(format!(
+                "{0} exited with {1}: {2}",
+                self.program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from(String::from_utf8_lossy(&output.stdout)))
+    }
+}
+
+impl Backend for YtdlBackend {
+    /// YouTube's auto-generated mix for a video is the closest thing to the
+    /// API's related videos that youtube-dl can list. The mix comes whole,
+    /// so pages are cut from it.
+    fn find_related_tracks(&self, video_id: &str, page: Option<&str>) -> Result<ResultPage> {
+        let offset = backend::parse_offset(page)?;
+        let out = self.dump_json(
+            &format!(
+                "https://www.youtube.com/watch?v={0}&list=RD{0}",
+                video_id
+            ),
+            None,
+        )?;
+        let mix = ytdl_json_parser(&out, ResultKind::Track)?
+            .into_iter()
+            .filter(|x| x.id != video_id)
+            .collect();
+        Ok(backend::offset_page(mix, offset, self.max_results))
+    }
+
+    /// youtube-dl can't start a search partway through, so each page asks
+    /// for everything up to it, plus one result to tell if there's more.
+    /// Of the filters, only `order:date` has a youtube-dl equivalent.
+    fn search(&self, query: &SearchQuery, page: Option<&str>) -> Result<ResultPage> {
+        let offset = backend::parse_offset(page)?;
+        let prefix = match query.order.as_ref().map(String::as_str) {
+            Some("date") => "ytsearchdate",
+            _ => "ytsearch",
+        };
+        let out = self.dump_json(
+            &format!(
+                "{0}{1}:{2}",
+                prefix,
+                offset + self.max_results + 1,
+                query.terms
+            ),
+            None,
+        )?;
+        Ok(backend::offset_page(
+            ytdl_json_parser(&out, ResultKind::Track)?,
+            offset,
+            self.max_results,
+        ))
+    }
+
+    /// There's no search prefix for playlists or channels, but youtube-dl
+    /// lists YouTube's own results page with its filter set.
+    fn search_collections(
+        &self,
+        query: &SearchQuery,
+        kind: ResultKind,
+        page: Option<&str>,
+    ) -> Result<ResultPage> {
+        let offset = backend::parse_offset(page)?;
+        let filter = if kind == ResultKind::Channel {
+            CHANNELS_FILTER
+        } else {
+            PLAYLISTS_FILTER
+        };
+        let out = self.dump_json(
+            &format!(
+                "https://www.youtube.com/results?search_query={0}&sp={1}",
+                form_urlencoded::byte_serialize(query.terms.as_bytes()).collect::<String>(),
+                filter
+            ),
+            Some(offset + self.max_results + 1),
+        )?;
+        Ok(backend::offset_page(
+            ytdl_json_parser(&out, kind)?,
+            offset,
+            self.max_results,
+        ))
+    }
+
+    /// For a single video, youtube-dl has the whole of its details.
+    fn lookup(&self, video_id: &str) -> Result<BackendSearchResult> {
+        let out = self.dump_json(
+            &format!("https://www.youtube.com/watch?v={0}", video_id),
+            None,
+        )?;
+        match ytdl_json_parser(&out, ResultKind::Track)?.pop() {
+            Some(x) => Ok(x),
+            None => Err(SurgeError::Backend(
+                format!("youtube-dl found no video {0}", video_id),
+            )),
+        }
+    }
+
+    fn list_collection(&self, id: &str, kind: ResultKind, page: Option<&str>) -> Result<ResultPage> {
+        let offset = backend::parse_offset(page)?;
+        let out = self.dump_json(
+            &collection_url(id, kind),
+            Some(offset + self.max_results + 1),
+        )?;
+        Ok(backend::offset_page(
+            ytdl_json_parser(&out, ResultKind::Track)?,
+            offset,
+            self.max_results,
+        ))
+    }
+
+    /// One run lists the lot, where paging would list the collection from
+    /// its start again for every page.
+    fn collection_tracks(
+        &self,
+        id: &str,
+        kind: ResultKind,
+        max: usize,
+    ) -> Result<Vec<BackendSearchResult>> {
+        let out = self.dump_json(&collection_url(id, kind), Some(max))?;
+        let mut tracks = ytdl_json_parser(&out, ResultKind::Track)?;
+        tracks.truncate(max);
+        Ok(tracks)
+    }
+}
+
+fn collection_url(id: &str, kind: ResultKind) -> String {
+    match kind {
+        ResultKind::Channel => format!("https://www.youtube.com/channel/{0}/videos", id),
+        _ => format!("https://www.youtube.com/playlist?list={0}", id),
+    }
+}
+
+/// `--dump-json` prints one JSON object per entry, one per line.
+fn ytdl_json_parser(out: &str, kind: ResultKind) -> Result<Vec<BackendSearchResult>> {
+    let mut results = vec![];
+    for line in out.lines().filter(|x| !x.trim().is_empty()) {
+        let video_obj = serde_json::from_str::<serde_json::Value>(line)?;
+        let id = match video_obj["id"].as_str() {
+            Some(x) => String::from(x),
+            None => {
+                return Err(SurgeError::Backend(
+                    String::from("youtube-dl output didn't contain id"),
+                ))
+            }
+        };
+        let title = match video_obj["title"].as_str() {
+            Some(x) => String::from(x),
+            None => {
+                return Err(SurgeError::Backend(
+                    String::from("youtube-dl output didn't contain title"),
+                ))
+            }
+        };
+
+        // flat entries don't carry thumbnails, but YouTube serves those of
+        // videos at a fixed location; the small one matches what the API
+        // backend uses
+        let thumbnail = if kind == ResultKind::Track {
+            Some(format!("https://i.ytimg.com/vi/{0}/default.jpg", id))
+        } else {
+            None
+        };
+        // flat entries carry whatever the search page showed, which
+        // varies between extractor versions
+        let artist = video_obj["channel"].as_str().or_else(
+            || video_obj["uploader"].as_str(),
+        );
+        let published = video_obj["upload_date"].as_str().and_then(|x| {
+            // YYYYMMDD
+            if x.len() == 8 && x.chars().all(|c| c.is_digit(10)) {
+                Some(format!("{0}-{1}-{2}", &x[..4], &x[4..6], &x[6..]))
+            } else {
+                None
+            }
+        });
+        results.push(BackendSearchResult {
+            thumbnail: thumbnail,
+            kind: kind,
+            artist: artist.map(String::from),
+            duration: video_obj["duration"].as_f64().map(|x| x as u64),
+            published: published,
+            views: video_obj["view_count"].as_u64(),
+            description: video_obj["description"].as_str().map(String::from),
+            ..BackendSearchResult::new(id, title, BackendType::Ytdl)
+        });
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One line of `youtube-dl --dump-json`, trimmed to the fields surge
+    /// reads and a few it doesn't
+    const VIDEO: &'static str = concat!(
+        r#"{"id": "dQw4w9WgXcQ", "title": "Rick Astley - Never Gonna Give You Up", "#,
+        r#""uploader": "RickAstleyVEVO", "channel": "Rick Astley", "duration": 212.0, "#,
+        r#""upload_date": "20091025", "view_count": 1400000000, "#,
+        r#""description": "The official video", "ext": "webm", "formats": []}"#
+    );
+
+    /// A `--flat-playlist` entry, which knows much less
+    const FLAT: &'static str = concat!(
+        r#"{"_type": "url", "ie_key": "Youtube", "id": "yPYZpwSpKmA", "#,
+        r#""url": "https://www.youtube.com/watch?v=yPYZpwSpKmA", "#,
+        r#""title": "Together Forever", "duration": null, "channel": null}"#
+    );
+
+    #[test]
+    fn parses_a_video() {
+        let results = ytdl_json_parser(VIDEO, ResultKind::Track).unwrap();
+        assert_eq!(results.len(), 1);
+        let x = &results[0];
+        assert_eq!(x.id, "dQw4w9WgXcQ");
+        assert_eq!(x.title, "Rick Astley - Never Gonna Give You Up");
+        assert_eq!(x.source, BackendType::Ytdl);
+        assert_eq!(x.kind, ResultKind::Track);
+        assert_eq!(x.artist, Some(String::from("Rick Astley")));
+        assert_eq!(x.duration, Some(212));
+        assert_eq!(x.published, Some(String::from("2009-10-25")));
+        assert_eq!(x.views, Some(1400000000));
+        assert_eq!(x.description, Some(String::from("The official video")));
+        assert_eq!(
+            x.thumbnail,
+            Some(String::from("https://i.ytimg.com/vi/dQw4w9WgXcQ/default.jpg"))
+        );
+    }
+
+    #[test]
+    fn leaves_out_what_flat_entries_lack() {
+        let results = ytdl_json_parser(FLAT, ResultKind::Track).unwrap();
+        let x = &results[0];
+        assert_eq!(x.id, "yPYZpwSpKmA");
+        assert_eq!(x.artist, None);
+        assert_eq!(x.duration, None);
+        assert_eq!(x.published, None);
+        assert_eq!(x.views, None);
+        assert!(x.thumbnail.is_some());
+    }
+
+    #[test]
+    fn falls_back_to_the_uploader() {
+        let line = r#"{"id": "x", "title": "X", "uploader": "Someone", "upload_date": "2009"}"#;
+        let x = ytdl_json_parser(line, ResultKind::Track).unwrap().remove(0);
+        assert_eq!(x.artist, Some(String::from("Someone")));
+        assert_eq!(x.published, None);
+    }
+
+    #[test]
+    fn collections_have_no_thumbnails() {
+        let line = r#"{"id": "PL123", "title": "Mix", "uploader": "Someone"}"#;
+        let x = ytdl_json_parser(line, ResultKind::Playlist).unwrap().remove(0);
+        assert_eq!(x.kind, ResultKind::Playlist);
+        assert_eq!(x.thumbnail, None);
+    }
+
+    #[test]
+    fn parses_a_line_per_result() {
+        let out = format!("{0}\n\n{1}\n  \n", VIDEO, FLAT);
+        let results = ytdl_json_parser(&out, ResultKind::Track).unwrap();
+        let ids = results.iter().map(|x| x.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["dQw4w9WgXcQ", "yPYZpwSpKmA"]);
+        assert!(ytdl_json_parser("", ResultKind::Track).unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_lines_without_an_id_or_title() {
+        for line in &[r#"{"title": "X"}"#, r#"{"id": "x"}"#, r#"{"id": 5, "title": "X"}"#, "{"] {
+            assert!(ytdl_json_parser(line, ResultKind::Track).is_err(), "{0}", line);
+        }
+    }
+}